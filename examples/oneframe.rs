@@ -11,17 +11,23 @@ use cros_codecs::{
         h264::{EncoderConfig, H264},
         stateless::StatelessEncoder,
     },
-    libva::{Surface, UsageHint, VA_RT_FORMAT_YUV420},
+    libva::{Surface, UsageHint},
     video_frame::{VideoFrame, generic_dma_video_frame::GenericDmaVideoFrame},
 };
 
 use std::{borrow::Borrow, io::Write};
 
+use generator::TestPixelFormat;
+
 fn main() {
     let width = 1280;
     let height = 720;
     let framerate = 60;
 
+    // Switch to `TestPixelFormat::I420`/`TestPixelFormat::P010` to exercise
+    // the encoder against those upload paths instead.
+    let pixel_format = TestPixelFormat::Nv12;
+
     let display = cros_codecs::libva::Display::open().expect("Failed to open VA display");
     let config = EncoderConfig {
         resolution: Resolution { width, height },
@@ -35,23 +41,8 @@ fn main() {
             max_quality: u32::MAX,
         },
     };
-    let fourcc = cros_codecs::Fourcc::from(b"NV12");
-    let frame_layout = FrameLayout {
-        format: (fourcc, 0),
-        size: Resolution { width, height },
-        planes: vec![
-            PlaneLayout {
-                buffer_index: 0,
-                offset: 0,
-                stride: width as usize,
-            },
-            PlaneLayout {
-                buffer_index: 0,
-                offset: width as usize * height as usize,
-                stride: width as usize,
-            },
-        ],
-    };
+    let fourcc = cros_codecs::Fourcc::from(pixel_format.fourcc_tag());
+    let frame_layout = pixel_format.frame_layout(fourcc, width, height);
     let coded_size = cros_codecs::Resolution { width, height };
     let low_power = false;
     let blocking_mode = BlockingMode::Blocking;
@@ -67,7 +58,7 @@ fn main() {
 
     let mut pool = VaSurfacePool::<()>::new(
         display.clone(),
-        VA_RT_FORMAT_YUV420,
+        pixel_format.rt_format(),
         Some(UsageHint::USAGE_HINT_ENCODER),
         Resolution { width, height },
     );
@@ -118,9 +109,77 @@ mod generator {
 
     use cros_codecs::{
         Fourcc,
-        libva::{self, Display, SurfaceMemoryDescriptor, VA_FOURCC_NV12},
+        libva::{
+            self, Display, SurfaceMemoryDescriptor, VA_FOURCC_I420, VA_FOURCC_NV12,
+            VA_FOURCC_P010, VA_RT_FORMAT_YUV420, VA_RT_FORMAT_YUV420_10,
+        },
     };
 
+    /// Which pixel layout to generate test frames in and upload to the VA
+    /// surface pool -- picked once in `main()` so `EncoderConfig`/the pool's
+    /// `rt_format` and `TestFrameGenerator`'s upload path all agree.
+    pub enum TestPixelFormat {
+        /// 8-bit 4:2:0, one luma plane plus one interleaved U/V plane.
+        Nv12,
+        /// 8-bit 4:2:0, one luma plane plus two separate U and V planes.
+        I420,
+        /// 10-bit 4:2:0 packed into 16-bit little-endian samples.
+        P010,
+    }
+
+    impl TestPixelFormat {
+        pub fn fourcc_tag(&self) -> &'static [u8; 4] {
+            match self {
+                TestPixelFormat::Nv12 => b"NV12",
+                TestPixelFormat::I420 => b"I420",
+                TestPixelFormat::P010 => b"P010",
+            }
+        }
+
+        pub fn rt_format(&self) -> u32 {
+            match self {
+                TestPixelFormat::Nv12 | TestPixelFormat::I420 => VA_RT_FORMAT_YUV420,
+                TestPixelFormat::P010 => VA_RT_FORMAT_YUV420_10,
+            }
+        }
+
+        /// Build the `FrameLayout` this format needs: NV12/P010 are
+        /// biplanar (one interleaved chroma plane), I420 is triplanar (one
+        /// plane per U/V component, each half-width); P010 doubles every
+        /// stride since it packs 10-bit samples into 16-bit containers.
+        pub fn frame_layout(&self, fourcc: Fourcc, width: u32, height: u32) -> FrameLayout {
+            let (width, height) = (width as usize, height as usize);
+            let planes = match self {
+                TestPixelFormat::Nv12 => vec![
+                    PlaneLayout { buffer_index: 0, offset: 0, stride: width },
+                    PlaneLayout { buffer_index: 0, offset: width * height, stride: width },
+                ],
+                TestPixelFormat::I420 => {
+                    let chroma_stride = width.div_ceil(2);
+                    let chroma_size = chroma_stride * height.div_ceil(2);
+                    vec![
+                        PlaneLayout { buffer_index: 0, offset: 0, stride: width },
+                        PlaneLayout { buffer_index: 0, offset: width * height, stride: chroma_stride },
+                        PlaneLayout {
+                            buffer_index: 0,
+                            offset: width * height + chroma_size,
+                            stride: chroma_stride,
+                        },
+                    ]
+                }
+                TestPixelFormat::P010 => vec![
+                    PlaneLayout { buffer_index: 0, offset: 0, stride: width * 2 },
+                    PlaneLayout { buffer_index: 0, offset: width * height * 2, stride: width * 2 },
+                ],
+            };
+            FrameLayout {
+                format: (fourcc, 0),
+                size: Resolution { width: width as u32, height: height as u32 },
+                planes,
+            }
+        }
+    }
+
     pub fn get_test_frame_t(ts: u64, max_ts: u64) -> f32 {
         2.0 * std::f32::consts::PI * (ts as f32) / (max_ts as f32)
     }
@@ -211,6 +270,65 @@ mod generator {
         fill_test_frame_nm12(width, height, strides, t, y_plane, uv_plane)
     }
 
+    fn fill_test_frame_i420(
+        width: usize,
+        height: usize,
+        strides: [usize; 3],
+        t: f32,
+        y_plane: &mut [u8],
+        u_plane: &mut [u8],
+        v_plane: &mut [u8],
+    ) {
+        gen_test_frame(width, height, t, |col, row, yuv| {
+            /// Maximum value of color component for I420
+            const MAX_COMP_VAL: f32 = 0xff as f32;
+
+            let (y, u, v) = (
+                (yuv[0] * MAX_COMP_VAL).clamp(0.0, MAX_COMP_VAL) as u8,
+                (yuv[1] * MAX_COMP_VAL).clamp(0.0, MAX_COMP_VAL) as u8,
+                (yuv[2] * MAX_COMP_VAL).clamp(0.0, MAX_COMP_VAL) as u8,
+            );
+
+            y_plane[row * strides[0] + col] = y;
+
+            // Subsample with upper left pixel
+            if col % 2 == 0 && row % 2 == 0 {
+                u_plane[(row / 2) * strides[1] + col / 2] = u;
+                v_plane[(row / 2) * strides[2] + col / 2] = v;
+            }
+        });
+    }
+
+    fn fill_test_frame_p010(
+        width: usize,
+        height: usize,
+        strides: [usize; 2],
+        t: f32,
+        y_plane: &mut [u8],
+        uv_plane: &mut [u8],
+    ) {
+        gen_test_frame(width, height, t, |col, row, yuv| {
+            /// Maximum value of a 10-bit color component, stored in the
+            /// upper 10 bits of each 16-bit little-endian P010 sample.
+            const MAX_COMP_VAL: f32 = 0x3ff as f32;
+
+            let pack = |v: f32| -> u16 { ((v * MAX_COMP_VAL).clamp(0.0, MAX_COMP_VAL) as u16) << 6 };
+            let (y, u, v) = (pack(yuv[0]), pack(yuv[1]), pack(yuv[2]));
+
+            let y_pos = (row * strides[0] + col) * 2;
+            y_plane[y_pos..y_pos + 2].copy_from_slice(&y.to_le_bytes());
+
+            // Subsample with upper left pixel
+            if col % 2 == 0 && row % 2 == 0 {
+                let u_pos = (row / 2) * strides[1] + col * 2;
+                let v_pos = u_pos + 2;
+
+                uv_plane[u_pos..u_pos + 2].copy_from_slice(&u.to_le_bytes());
+                uv_plane[v_pos..v_pos + 2].copy_from_slice(&v.to_le_bytes());
+            }
+        });
+    }
+
     fn map_surface<'a, M: SurfaceMemoryDescriptor>(
         display: &Rc<Display>,
         surface: &'a Surface<M>,
@@ -229,6 +347,24 @@ mod generator {
         map_surface(display, surface, VA_FOURCC_NV12)
     }
 
+    /// Copy one plane from `src` into `dst`, each laid out as `rows` rows of
+    /// `row_bytes` at the given stride. Test frame data is always tightly
+    /// packed (`src_stride == row_bytes`), so when the destination surface
+    /// happens to be unpadded too, the whole plane is one contiguous run
+    /// and copies in a single `copy_from_slice`; otherwise it falls back to
+    /// a strided, row-at-a-time copy.
+    fn copy_plane(dst: &mut [u8], dst_stride: usize, src: &[u8], src_stride: usize, rows: usize, row_bytes: usize) {
+        if dst_stride == row_bytes && src_stride == row_bytes {
+            let len = rows * row_bytes;
+            dst[..len].copy_from_slice(&src[..len]);
+            return;
+        }
+
+        for (dst_row, src_row) in dst.chunks_mut(dst_stride).zip(src.chunks(src_stride)).take(rows) {
+            dst_row[..row_bytes].copy_from_slice(&src_row[..row_bytes]);
+        }
+    }
+
     /// Uploads raw NV12 to Surface
     pub fn upload_nv12_img<M: SurfaceMemoryDescriptor>(
         display: &Rc<Display>,
@@ -244,28 +380,111 @@ mod generator {
         let width = width as usize;
         let height = height as usize;
 
-        let mut src: &[u8] = data;
-        let mut dst = &mut dest[va_image.offsets[0] as usize..];
+        let y_size = width * height;
+        copy_plane(&mut dest[va_image.offsets[0] as usize..], va_image.pitches[0] as usize, &data[..y_size], width, height, width);
+
+        let uv_height = height / 2;
+        copy_plane(
+            &mut dest[va_image.offsets[1] as usize..],
+            va_image.pitches[1] as usize,
+            &data[y_size..y_size + width * uv_height],
+            width,
+            uv_height,
+            width,
+        );
 
-        // Copy luma
-        for _ in 0..height {
-            dst[..width].copy_from_slice(&src[..width]);
-            dst = &mut dst[va_image.pitches[0] as usize..];
-            src = &src[width..];
-        }
+        surface.sync().unwrap();
+        drop(image);
+    }
 
-        // Advance to the offset of the chroma plane
-        let mut src = &data[width * height..];
-        let mut dst = &mut dest[va_image.offsets[1] as usize..];
+    fn map_surface_i420<'a, M: SurfaceMemoryDescriptor>(
+        display: &Rc<Display>,
+        surface: &'a Surface<M>,
+    ) -> libva::Image<'a> {
+        map_surface(display, surface, VA_FOURCC_I420)
+    }
 
-        let height = height / 2;
+    /// Uploads raw I420 (separate U and V planes, each half-width/height)
+    /// to Surface.
+    #[allow(dead_code)]
+    pub fn upload_i420_img<M: SurfaceMemoryDescriptor>(
+        display: &Rc<Display>,
+        surface: &Surface<M>,
+        width: u32,
+        height: u32,
+        data: &[u8],
+    ) {
+        let mut image = map_surface_i420(display, surface);
 
-        // Copy chroma
-        for _ in 0..height {
-            dst[..width].copy_from_slice(&src[..width]);
-            dst = &mut dst[va_image.pitches[1] as usize..];
-            src = &src[width..];
-        }
+        let va_image = *image.image();
+        let dest = image.as_mut();
+        let width = width as usize;
+        let height = height as usize;
+        let chroma_width = width.div_ceil(2);
+        let chroma_height = height.div_ceil(2);
+        let chroma_size = chroma_width * chroma_height;
+
+        let y_size = width * height;
+        copy_plane(&mut dest[va_image.offsets[0] as usize..], va_image.pitches[0] as usize, &data[..y_size], width, height, width);
+        copy_plane(
+            &mut dest[va_image.offsets[1] as usize..],
+            va_image.pitches[1] as usize,
+            &data[y_size..y_size + chroma_size],
+            chroma_width,
+            chroma_height,
+            chroma_width,
+        );
+        copy_plane(
+            &mut dest[va_image.offsets[2] as usize..],
+            va_image.pitches[2] as usize,
+            &data[y_size + chroma_size..y_size + 2 * chroma_size],
+            chroma_width,
+            chroma_height,
+            chroma_width,
+        );
+
+        surface.sync().unwrap();
+        drop(image);
+    }
+
+    fn map_surface_p010<'a, M: SurfaceMemoryDescriptor>(
+        display: &Rc<Display>,
+        surface: &'a Surface<M>,
+    ) -> libva::Image<'a> {
+        map_surface(display, surface, VA_FOURCC_P010)
+    }
+
+    /// Uploads raw P010 (10-bit 4:2:0, samples packed into 16-bit
+    /// little-endian containers, one interleaved U/V plane like NV12) to
+    /// Surface.
+    #[allow(dead_code)]
+    pub fn upload_p010_img<M: SurfaceMemoryDescriptor>(
+        display: &Rc<Display>,
+        surface: &Surface<M>,
+        width: u32,
+        height: u32,
+        data: &[u8],
+    ) {
+        let mut image = map_surface_p010(display, surface);
+
+        let va_image = *image.image();
+        let dest = image.as_mut();
+        let width = width as usize;
+        let height = height as usize;
+        let row_bytes = width * 2; // 2 bytes/sample
+
+        let y_size = row_bytes * height;
+        copy_plane(&mut dest[va_image.offsets[0] as usize..], va_image.pitches[0] as usize, &data[..y_size], row_bytes, height, row_bytes);
+
+        let uv_height = height / 2;
+        copy_plane(
+            &mut dest[va_image.offsets[1] as usize..],
+            va_image.pitches[1] as usize,
+            &data[y_size..y_size + row_bytes * uv_height],
+            row_bytes,
+            uv_height,
+            row_bytes,
+        );
 
         surface.sync().unwrap();
         drop(image);
@@ -389,6 +608,51 @@ mod generator {
         surface.sync().unwrap();
     }
 
+    pub fn upload_test_frame_i420<M: SurfaceMemoryDescriptor>(
+        display: &Rc<Display>,
+        surface: &Surface<M>,
+        t: f32,
+    ) {
+        let mut image = map_surface_i420(display, surface);
+
+        let (width, height) = image.display_resolution();
+        let offsets = image.image().offsets;
+        let pitches = image.image().pitches;
+
+        let strides = [pitches[0] as usize, pitches[1] as usize, pitches[2] as usize];
+        let raw = image.as_mut();
+        let (y_plane, rest) = raw.split_at_mut(offsets[1] as usize);
+        let y_plane = &mut y_plane[offsets[0] as usize..];
+        let (u_plane, v_plane) = rest.split_at_mut((offsets[2] - offsets[1]) as usize);
+
+        fill_test_frame_i420(width as usize, height as usize, strides, t, y_plane, u_plane, v_plane);
+
+        drop(image);
+        surface.sync().unwrap();
+    }
+
+    pub fn upload_test_frame_p010<M: SurfaceMemoryDescriptor>(
+        display: &Rc<Display>,
+        surface: &Surface<M>,
+        t: f32,
+    ) {
+        let mut image = map_surface_p010(display, surface);
+
+        let (width, height) = image.display_resolution();
+        let offsets = image.image().offsets;
+        let pitches = image.image().pitches;
+
+        let strides = [pitches[0] as usize, pitches[1] as usize];
+        let raw = image.as_mut();
+        let (y_plane, uv_plane) = raw.split_at_mut(offsets[1] as usize);
+        let y_plane = &mut y_plane[offsets[0] as usize..];
+
+        fill_test_frame_p010(width as usize, height as usize, strides, t, y_plane, uv_plane);
+
+        drop(image);
+        surface.sync().unwrap();
+    }
+
     /// Helper struct. Procedurally generate NV12 frames for test purposes.
     pub struct TestFrameGenerator {
         counter: u64,
@@ -432,6 +696,8 @@ mod generator {
             let t = get_test_frame_t(meta.timestamp, self.max_count);
             match self.fourcc.0 {
                 VA_FOURCC_NV12 => upload_test_frame_nv12(&self.display, surface, t),
+                VA_FOURCC_I420 => upload_test_frame_i420(&self.display, surface, t),
+                VA_FOURCC_P010 => upload_test_frame_p010(&self.display, surface, t),
                 _ => unreachable!(),
             }
 
@@ -0,0 +1,194 @@
+//! AAC audio encoding via rsmpeg/FFmpeg, fed through a simple sample FIFO so
+//! the encoder -- which only ever accepts exactly `frame_size` samples per
+//! frame -- can be pushed PCM in chunks of any size: keep writing into the
+//! fifo until it holds at least `frame_size` samples, then drain exactly
+//! `frame_size` at a time, stamping each frame's `pts` from a running sample
+//! counter.
+//!
+//! Mirrors `encode::Encoder`'s push/poll shape: `push_samples` queues raw
+//! interleaved S16 PCM, `poll` hands back whatever complete AAC access units
+//! are now ready. [`EncodedAudioPacket`]'s `duration` is already in the
+//! encoder's own sample-rate timebase, ready for
+//! [`Mp4Muxer::push_audio_packet`](crate::mp4::Mp4Muxer::push_audio_packet).
+
+use std::slice;
+
+use anyhow::{Context, Result};
+use rsmpeg::{
+    avcodec::{AVCodec, AVCodecContext},
+    avutil::{ra, AVChannelLayout, AVFrame},
+    error::RsmpegError,
+    ffi,
+};
+
+/// Public encoder configuration accepted by [`AudioEncoder::new`]. The input
+/// PCM is always interleaved signed 16-bit; `sample_rate`/`channels` describe
+/// both the input and the encoded AAC track.
+pub struct AudioEncoderSettings {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bitrate: u64,
+}
+
+impl Default for AudioEncoderSettings {
+    fn default() -> Self {
+        Self {
+            sample_rate: 48_000,
+            channels: 2,
+            bitrate: 128_000,
+        }
+    }
+}
+
+/// One encoded AAC access unit (raw, no ADTS header -- the mp4 muxer wants
+/// the bare payload for `mp4a`/`esds`), with its `duration` in the encoder's
+/// own sample-rate timebase; it carries no `pts` of its own.
+pub struct EncodedAudioPacket {
+    pub data: Vec<u8>,
+    pub duration: i64,
+}
+
+pub struct AudioEncoder {
+    avctx: AVCodecContext,
+    channels: u16,
+    frame_size: usize,
+    // Interleaved S16 samples waiting for a full `frame_size` to accumulate.
+    fifo: Vec<i16>,
+    samples_written: i64,
+}
+
+impl AudioEncoder {
+    pub fn new(settings: AudioEncoderSettings) -> Result<Self> {
+        let codec = AVCodec::find_encoder_by_name(c"aac").context("Could not find AAC encoder")?;
+        let mut avctx = AVCodecContext::new(&codec);
+        avctx.set_bit_rate(settings.bitrate as i64);
+        avctx.set_sample_rate(settings.sample_rate as i32);
+        avctx.set_ch_layout(AVChannelLayout::from_nb_channels(settings.channels as i32));
+        avctx.set_sample_fmt(ffi::AV_SAMPLE_FMT_FLTP);
+        avctx.set_time_base(ra(1, settings.sample_rate as i32));
+
+        avctx.open(None).context("Cannot open AAC encoder codec")?;
+        let frame_size = unsafe { (*avctx.as_ptr()).frame_size } as usize;
+
+        Ok(Self {
+            avctx,
+            channels: settings.channels,
+            frame_size,
+            fifo: Vec::new(),
+            samples_written: 0,
+        })
+    }
+
+    /// `AudioSpecificConfig` bytes for the `esds` audio sample entry, valid
+    /// once the encoder has been opened.
+    pub fn audio_specific_config(&self) -> Vec<u8> {
+        let raw = unsafe { *self.avctx.as_ptr() };
+        if raw.extradata.is_null() || raw.extradata_size <= 0 {
+            return Vec::new();
+        }
+        unsafe { slice::from_raw_parts(raw.extradata, raw.extradata_size as usize) }.to_vec()
+    }
+
+    /// Queue interleaved S16 PCM (`samples.len()` must be a multiple of
+    /// `channels`). Doesn't encode anything itself -- call `poll` afterward
+    /// to drain whatever full `frame_size` chunks are now buffered.
+    pub fn push_samples(&mut self, samples: &[i16]) {
+        self.fifo.extend_from_slice(samples);
+    }
+
+    /// Encode and return the next complete AAC access unit, or `None` if
+    /// fewer than `frame_size` samples are buffered and the encoder has
+    /// nothing queued either.
+    pub fn poll(&mut self) -> Result<Option<EncodedAudioPacket>> {
+        loop {
+            if let Some(packet) = self.receive_packet()? {
+                return Ok(Some(packet));
+            }
+            if self.fifo.len() / self.channels as usize < self.frame_size {
+                return Ok(None);
+            }
+            self.encode_one_frame()?;
+        }
+    }
+
+    /// Same encode loop as [`Self::poll`], but hands back the raw rsmpeg
+    /// `AVPacket` instead of an [`EncodedAudioPacket`] -- for callers (e.g.
+    /// [`crate::mux_ffmpeg::Muxer::write_audio_packet`]) that mux through
+    /// `avformat` rather than `mp4.rs`'s hand-rolled boxes.
+    pub fn poll_packet(&mut self) -> Result<Option<rsmpeg::avcodec::AVPacket>> {
+        loop {
+            match self.avctx.receive_packet() {
+                Ok(packet) => return Ok(Some(packet)),
+                Err(RsmpegError::EncoderDrainError) | Err(RsmpegError::EncoderFlushedError) => {}
+                Err(e) => return Err(e).context("Failed to receive packet from AAC encoder"),
+            }
+            if self.fifo.len() / self.channels as usize < self.frame_size {
+                return Ok(None);
+            }
+            self.encode_one_frame()?;
+        }
+    }
+
+    /// The underlying codec context, so a muxer can copy its codec
+    /// parameters/extradata into an output stream.
+    pub fn avctx(&self) -> &AVCodecContext {
+        &self.avctx
+    }
+
+    /// Flush the fifo's last, possibly short frame (if any) and signal EOF
+    /// to the encoder; call `poll` in a loop afterward to drain it.
+    pub fn drain(&mut self) -> Result<()> {
+        if !self.fifo.is_empty() {
+            self.encode_one_frame()?;
+        }
+        self.avctx
+            .send_frame(None)
+            .context("Failed to send EOF to AAC encoder")?;
+        Ok(())
+    }
+
+    /// Pull exactly `frame_size` samples (or whatever's left, on `drain`'s
+    /// last call) out of the fifo, convert S16 interleaved to FLTP planar --
+    /// the format the native `aac` encoder wants -- and send it off.
+    fn encode_one_frame(&mut self) -> Result<()> {
+        let samples_per_channel = (self.fifo.len() / self.channels as usize).min(self.frame_size);
+
+        let mut frame = AVFrame::new();
+        frame.set_nb_samples(samples_per_channel as i32);
+        frame.set_format(ffi::AV_SAMPLE_FMT_FLTP);
+        frame.set_sample_rate(self.avctx.sample_rate);
+        frame.set_ch_layout(self.avctx.ch_layout);
+        frame.set_pts(self.samples_written);
+        frame
+            .get_buffer(0)
+            .context("Failed to allocate AAC input frame buffer")?;
+
+        for channel in 0..self.channels as usize {
+            let plane = frame.data_mut()[channel] as *mut f32;
+            for i in 0..samples_per_channel {
+                let sample = self.fifo[i * self.channels as usize + channel];
+                unsafe { *plane.add(i) = sample as f32 / i16::MAX as f32 };
+            }
+        }
+
+        self.fifo
+            .drain(0..samples_per_channel * self.channels as usize);
+        self.samples_written += samples_per_channel as i64;
+
+        self.avctx
+            .send_frame(Some(&frame))
+            .context("Failed to send frame to AAC encoder")?;
+        Ok(())
+    }
+
+    fn receive_packet(&mut self) -> Result<Option<EncodedAudioPacket>> {
+        match self.avctx.receive_packet() {
+            Ok(packet) => Ok(Some(EncodedAudioPacket {
+                data: unsafe { slice::from_raw_parts(packet.data, packet.size as usize) }.to_vec(),
+                duration: packet.duration,
+            })),
+            Err(RsmpegError::EncoderDrainError) | Err(RsmpegError::EncoderFlushedError) => Ok(None),
+            Err(e) => Err(e).context("Failed to receive packet from AAC encoder"),
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! PipeWire audio capture, the `MEDIA_TYPE=Audio` counterpart to
+//! [`crate::capture::Capturer`]'s video stream: negotiates interleaved S16LE
+//! PCM at a fixed rate/channel count and hands off each buffer it receives to
+//! a queue [`AudioCapturer::read_samples`] drains.
+//!
+//! Unlike the video capturer's bounded [`crate::frame_buffer::FrameBuffer`]
+//! ring (only recent frames matter, so the oldest is overwritten when it's
+//! full), audio
+//! can't drop samples without an audible gap, so buffers accumulate in a FIFO
+//! queue instead -- the same "keep everything, drain on your own schedule"
+//! shape [`crate::audio::AudioEncoder`] expects from `push_samples`.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use anyhow::Result;
+use pipewire::{self as pw, main_loop, properties::properties};
+
+struct Terminate;
+
+struct UserData {
+    queue: Mutex<VecDeque<i16>>,
+}
+
+#[allow(dead_code)]
+pub struct AudioCapturer {
+    capture_thread: Option<JoinHandle<anyhow::Result<()>>>,
+    user_data: Arc<UserData>,
+    pw_sender: pw::channel::Sender<Terminate>,
+}
+
+impl AudioCapturer {
+    /// Connect a PipeWire audio input stream negotiated at `sample_rate`/
+    /// `channels`, interleaved S16LE -- the format [`crate::audio::AudioEncoder`]
+    /// already accepts from `push_samples`.
+    pub fn new(sample_rate: u32, channels: u16) -> Result<Self> {
+        let user_data = Arc::new(UserData {
+            queue: Mutex::new(VecDeque::new()),
+        });
+        let (pw_sender, pw_receiver) = pw::channel::channel();
+        let capture_thread = thread::spawn::<_, Result<()>>({
+            let user_data = user_data.clone();
+            move || {
+                let main_loop = main_loop::MainLoop::new(None)?;
+                let context = pw::context::Context::new(&main_loop)?;
+                let core = context.connect(None)?;
+
+                let _receiver = pw_receiver.attach(main_loop.loop_(), {
+                    let main_loop = main_loop.clone();
+                    move |_| main_loop.quit()
+                });
+
+                let props = properties! {
+                    *pw::keys::MEDIA_TYPE => "Audio",
+                    *pw::keys::MEDIA_CATEGORY => "Capture",
+                    *pw::keys::MEDIA_ROLE => "Screen",
+                    *pw::keys::TARGET_OBJECT => "gamescope",
+                };
+
+                let stream = pw::stream::Stream::new(&core, "zeroscope-audio", props)?;
+
+                let _listener = stream
+                    .add_local_listener_with_user_data(user_data.clone())
+                    .state_changed(|_, _, old_state, new_state| {
+                        println!("Audio state changed: {:?} -> {:?}", old_state, new_state);
+                    })
+                    .process(|stream, user_data| match stream.dequeue_buffer() {
+                        None => println!("out of audio buffers"),
+                        Some(mut buffer) => {
+                            let datas = buffer.datas_mut();
+                            if datas.is_empty() {
+                                eprintln!("No data in pipewire audio buffer");
+                                return;
+                            }
+                            let data = &mut datas[0];
+                            let Some(chunk) = data.data() else {
+                                return;
+                            };
+                            let samples = chunk
+                                .chunks_exact(2)
+                                .map(|b| i16::from_le_bytes([b[0], b[1]]));
+                            user_data.queue.lock().unwrap().extend(samples);
+                        }
+                    })
+                    .register()?;
+
+                let obj = pw::spa::pod::object!(
+                    pw::spa::utils::SpaTypes::ObjectParamFormat,
+                    pw::spa::param::ParamType::EnumFormat,
+                    pw::spa::pod::property!(
+                        pw::spa::param::format::FormatProperties::MediaType,
+                        Id,
+                        pw::spa::param::format::MediaType::Audio
+                    ),
+                    pw::spa::pod::property!(
+                        pw::spa::param::format::FormatProperties::MediaSubtype,
+                        Id,
+                        pw::spa::param::format::MediaSubtype::Raw
+                    ),
+                    pw::spa::pod::property!(
+                        pw::spa::param::format::FormatProperties::AudioFormat,
+                        Id,
+                        pw::spa::param::audio::AudioFormat::S16LE
+                    ),
+                    pw::spa::pod::property!(
+                        pw::spa::param::format::FormatProperties::AudioRate,
+                        Int,
+                        sample_rate as i32
+                    ),
+                    pw::spa::pod::property!(
+                        pw::spa::param::format::FormatProperties::AudioChannels,
+                        Int,
+                        channels as i32
+                    ),
+                );
+
+                let values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
+                    std::io::Cursor::new(Vec::new()),
+                    &pw::spa::pod::Value::Object(obj),
+                )
+                .expect("Failed to serialize pod")
+                .0
+                .into_inner();
+
+                let mut params = [pw::spa::pod::Pod::from_bytes(&values).unwrap()];
+
+                stream.connect(
+                    pw::spa::utils::Direction::Input,
+                    None,
+                    pw::stream::StreamFlags::AUTOCONNECT,
+                    &mut params,
+                )?;
+
+                main_loop.run();
+
+                Ok(())
+            }
+        });
+
+        if capture_thread.is_finished() {
+            return Err(anyhow::anyhow!("Audio capture thread finished prematurely"));
+        }
+
+        Ok(Self {
+            capture_thread: Some(capture_thread),
+            user_data,
+            pw_sender,
+        })
+    }
+
+    /// Drain every sample captured since the last call, interleaved S16, fed
+    /// straight into [`crate::audio::AudioEncoder::push_samples`]. Empty if
+    /// nothing new arrived.
+    pub fn read_samples(&self) -> Vec<i16> {
+        let mut queue = self.user_data.queue.lock().unwrap();
+        queue.drain(..).collect()
+    }
+}
+
+impl Drop for AudioCapturer {
+    fn drop(&mut self) {
+        self.pw_sender.send(Terminate).ok();
+        self.capture_thread.take().unwrap().join().ok();
+    }
+}
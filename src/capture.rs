@@ -1,5 +1,7 @@
 use std::{
+    borrow::Borrow,
     fs::File,
+    rc::Rc,
     sync::{Arc, Mutex},
     thread::{self, JoinHandle},
 };
@@ -8,7 +10,7 @@ use anyhow::Result;
 use cros_codecs::{
     backend::vaapi::surface_pool::{PooledVaSurface, VaSurfacePool},
     decoder::FramePool,
-    libva::{Display, UsageHint, VA_RT_FORMAT_YUV420},
+    libva::{Display, Image, Surface, UsageHint, VA_FOURCC_NV12, VA_RT_FORMAT_YUV420},
     video_frame::generic_dma_video_frame::GenericDmaVideoFrame,
     Fourcc, FrameLayout, PlaneLayout, Resolution,
 };
@@ -21,13 +23,62 @@ use pipewire::{self as pw, main_loop, properties::properties};
 
 use crate::frame_buffer::FrameBuffer;
 
+/// Either surface representation a captured frame can arrive as: a dma-buf
+/// imported directly from PipeWire's buffer fd (no copy), or a driver-
+/// allocated surface CPU-uploaded from a mapped SHM buffer, for compositors
+/// or driver configurations where dma-buf export isn't negotiated.
+pub enum CapturedSurface {
+    DmaBuf(PooledVaSurface<GenericDmaVideoFrame>),
+    Shm(PooledVaSurface<()>),
+}
+
+impl CapturedSurface {
+    pub fn size(&self) -> (u32, u32) {
+        match self {
+            CapturedSurface::DmaBuf(s) => {
+                Borrow::<Surface<GenericDmaVideoFrame>>::borrow(s).size()
+            }
+            CapturedSurface::Shm(s) => Borrow::<Surface<()>>::borrow(s).size(),
+        }
+    }
+
+    pub fn display(&self) -> Rc<Display> {
+        match self {
+            CapturedSurface::DmaBuf(s) => {
+                Borrow::<Surface<GenericDmaVideoFrame>>::borrow(s).display().clone()
+            }
+            CapturedSurface::Shm(s) => Borrow::<Surface<()>>::borrow(s).display().clone(),
+        }
+    }
+
+    pub fn id(&self) -> cros_codecs::libva::VASurfaceID {
+        match self {
+            CapturedSurface::DmaBuf(s) => Borrow::<Surface<GenericDmaVideoFrame>>::borrow(s).id(),
+            CapturedSurface::Shm(s) => Borrow::<Surface<()>>::borrow(s).id(),
+        }
+    }
+}
+
 #[allow(dead_code)]
 struct UserData {
     format: Mutex<spa::param::video::VideoInfoRaw>,
-    pool: Mutex<Option<VaSurfacePool<()>>>,
-    frame_buffer: FrameBuffer<PooledVaSurface<()>>,
+    // Keyed by `GenericDmaVideoFrame` so `get_surface` imports each incoming
+    // dma-buf directly as a VA surface (`VASurfaceAttribExternalBuffers`)
+    // instead of copying it into a driver-allocated one.
+    pool: Mutex<Option<VaSurfacePool<GenericDmaVideoFrame>>>,
+    // Small pool of driver-allocated surfaces the SHM fallback path uploads
+    // mapped buffer bytes into via `vaMapBuffer`, recycled across frames the
+    // same way the encoder's own native pool is in `encode::build_encoder`.
+    shm_pool: Mutex<Option<VaSurfacePool<()>>>,
+    display: Mutex<Option<Rc<Display>>>,
+    frame_buffer: FrameBuffer<CapturedSurface>,
 }
 
+/// Queued-but-unencoded frames `main.rs`'s reader is allowed to fall behind
+/// by before the ring starts evicting the oldest one -- matches the SHM
+/// fallback pool's surface count above it.
+const FRAME_BUFFER_CAPACITY: usize = 4;
+
 struct Terminate;
 
 #[allow(dead_code)]
@@ -42,7 +93,9 @@ impl Capturer {
         let user_data = Arc::new(UserData {
             format: Mutex::new(Default::default()),
             pool: Mutex::new(None),
-            frame_buffer: FrameBuffer::new(),
+            shm_pool: Mutex::new(None),
+            display: Mutex::new(None),
+            frame_buffer: FrameBuffer::new(FRAME_BUFFER_CAPACITY),
         });
         let (pw_sender, pw_receiver) = pw::channel::channel();
         let capture_thread = thread::spawn::<_, Result<()>>({
@@ -111,7 +164,9 @@ impl Capturer {
                         println!("  color_matrix: {:?}", format.color_matrix());
 
                         let display = Display::open().unwrap();
-                        let mut pool = VaSurfacePool::new(
+                        // Frames are imported one at a time as they arrive in
+                        // `process` below, so the pool starts out empty.
+                        let pool = VaSurfacePool::new(
                             display.clone(),
                             VA_RT_FORMAT_YUV420,
                             Some(UsageHint::USAGE_HINT_VPP_WRITE | UsageHint::USAGE_HINT_VPP_READ),
@@ -120,9 +175,28 @@ impl Capturer {
                                 height: format.size().height,
                             },
                         );
-                        pool.add_frames(vec![(); 16])
-                            .expect("Failed to add frames to pool");
                         user_data.pool.lock().unwrap().replace(pool);
+
+                        // Unlike the dma-buf pool, this one's surfaces are
+                        // driver-allocated up front (same as
+                        // `encode::build_encoder`'s native pool) since there's
+                        // no fd to import -- `process` uploads into one of
+                        // these via `vaMapBuffer` whenever a buffer turns out
+                        // to be SHM rather than dma-buf.
+                        let mut shm_pool = VaSurfacePool::<()>::new(
+                            display.clone(),
+                            VA_RT_FORMAT_YUV420,
+                            Some(UsageHint::USAGE_HINT_VPP_WRITE | UsageHint::USAGE_HINT_VPP_READ),
+                            Resolution {
+                                width: format.size().width,
+                                height: format.size().height,
+                            },
+                        );
+                        shm_pool
+                            .add_frames(vec![(); 4])
+                            .expect("Failed to allocate SHM upload surfaces");
+                        user_data.shm_pool.lock().unwrap().replace(shm_pool);
+                        user_data.display.lock().unwrap().replace(display);
                     })
                     .process(|stream, user_data| match stream.dequeue_buffer() {
                         None => println!("out of buffers"),
@@ -133,54 +207,87 @@ impl Capturer {
                                 return;
                             }
                             let data = &mut datas[0];
-                            let fd: std::os::unix::prelude::BorrowedFd<'_> =
-                                data.fd().expect("Failed to get fd from buffer data");
-                            let file = File::from(fd.try_clone_to_owned().unwrap());
-
-                            let fourcc = Fourcc::from(b"NV12");
                             let (width, height) = {
                                 let format = user_data.format.lock().unwrap().size();
                                 (format.width, format.height)
                             };
-                            let frame_layout = FrameLayout {
-                                format: (fourcc, 0),
-                                size: Resolution { width, height },
-                                planes: vec![
-                                    PlaneLayout {
-                                        buffer_index: 0,
-                                        offset: 0,
-                                        stride: width as usize,
-                                    },
-                                    PlaneLayout {
-                                        buffer_index: 0,
-                                        offset: width as usize * height as usize,
-                                        stride: width as usize,
-                                    },
-                                ],
-                            };
 
-                            let dma_frame = GenericDmaVideoFrame::new(vec![file], frame_layout)
-                                .expect("Failed to create GenericDmaVideoFrame");
-
-                            let pooled_surface = user_data
-                                .pool
-                                .lock()
-                                .unwrap()
-                                .as_mut()
-                                .unwrap()
-                                .get_surface()
-                                .expect("Failed to get surface from pool");
-
-                            dma_frame
-                                .copy_to_surface(std::borrow::Borrow::borrow(&pooled_surface))
-                                .unwrap();
-                            user_data.frame_buffer.write(Arc::new(pooled_surface));
+                            if data.type_().contains(pw::spa::buffer::DataType::DmaBuf) {
+                                let fd: std::os::unix::prelude::BorrowedFd<'_> =
+                                    data.fd().expect("Failed to get fd from buffer data");
+                                let file = File::from(fd.try_clone_to_owned().unwrap());
+
+                                let fourcc = Fourcc::from(b"NV12");
+                                let frame_layout = FrameLayout {
+                                    format: (fourcc, 0),
+                                    size: Resolution { width, height },
+                                    planes: vec![
+                                        PlaneLayout {
+                                            buffer_index: 0,
+                                            offset: 0,
+                                            stride: width as usize,
+                                        },
+                                        PlaneLayout {
+                                            buffer_index: 0,
+                                            offset: width as usize * height as usize,
+                                            stride: width as usize,
+                                        },
+                                    ],
+                                };
+
+                                let dma_frame = GenericDmaVideoFrame::new(vec![file], frame_layout)
+                                    .expect("Failed to create GenericDmaVideoFrame");
+
+                                // Import the dma-buf directly as a VA surface
+                                // (VASurfaceAttribExternalBuffers under the hood)
+                                // instead of copying its contents into a
+                                // driver-allocated one: `add_frames` hands this
+                                // exact frame's descriptor to the pool, and
+                                // `get_surface` immediately pops it back out as
+                                // the imported surface.
+                                let mut pool = user_data.pool.lock().unwrap();
+                                let pool = pool.as_mut().unwrap();
+                                pool.add_frames(vec![dma_frame])
+                                    .expect("Failed to import dma-buf as VA surface");
+                                let pooled_surface = pool
+                                    .get_surface()
+                                    .expect("Failed to get imported surface from pool");
+
+                                user_data
+                                    .frame_buffer
+                                    .write(Arc::new(CapturedSurface::DmaBuf(pooled_surface)));
+                            } else {
+                                // No dma-buf fd to import: this buffer is
+                                // plain SHM (MemFd/MemPtr), so read the
+                                // mapped plane bytes straight out of it and
+                                // upload them into a pooled VA surface via
+                                // `vaMapBuffer`/`Image`, same approach as
+                                // `vaapi_scaler::upload_nv12`.
+                                let Some(frame_data) = data.data() else {
+                                    eprintln!("SHM buffer has no mapped data");
+                                    return;
+                                };
+
+                                let display = user_data.display.lock().unwrap();
+                                let display = display.as_ref().expect("Display not initialized");
+                                let mut shm_pool = user_data.shm_pool.lock().unwrap();
+                                let shm_pool = shm_pool.as_mut().unwrap();
+                                let pooled_surface = shm_pool
+                                    .get_surface()
+                                    .expect("Failed to get SHM upload surface from pool");
+                                let surface: &Surface<()> = pooled_surface.borrow();
+                                upload_nv12(display, surface, frame_data, width, height)
+                                    .expect("Failed to upload SHM frame to VA surface");
+
+                                user_data
+                                    .frame_buffer
+                                    .write(Arc::new(CapturedSurface::Shm(pooled_surface)));
+                            }
                             // println!("Captured frame: {}x{}", width, height);
                         }
                     })
                     .register()?;
 
-                // FIXME: use 2 params, with second as shm fallback
                 let obj = pw::spa::pod::object!(
                     pw::spa::utils::SpaTypes::ObjectParamFormat,
                     pw::spa::param::ParamType::EnumFormat,
@@ -270,7 +377,74 @@ impl Capturer {
                 .0
                 .into_inner();
 
-                let mut params = [Pod::from_bytes(&values).unwrap()];
+                // Plain-memory fallback offered alongside the dma-buf format
+                // above: it has no `VideoModifier`, so PipeWire doesn't try
+                // to negotiate dma-buf export and falls back to MemFd/MemPtr
+                // buffers when that's all the compositor/driver combination
+                // can give us. It also leaves out the color range/matrix
+                // properties.
+                let shm_obj = pw::spa::pod::object!(
+                    pw::spa::utils::SpaTypes::ObjectParamFormat,
+                    pw::spa::param::ParamType::EnumFormat,
+                    pw::spa::pod::property!(
+                        pw::spa::param::format::FormatProperties::MediaType,
+                        Id,
+                        pw::spa::param::format::MediaType::Video
+                    ),
+                    pw::spa::pod::property!(
+                        pw::spa::param::format::FormatProperties::MediaSubtype,
+                        Id,
+                        pw::spa::param::format::MediaSubtype::Raw
+                    ),
+                    pw::spa::pod::property!(
+                        pw::spa::param::format::FormatProperties::VideoFormat,
+                        Id,
+                        pw::spa::param::video::VideoFormat::NV12
+                    ),
+                    pw::spa::pod::property!(
+                        pw::spa::param::format::FormatProperties::VideoSize,
+                        Choice,
+                        Range,
+                        Rectangle,
+                        spa::utils::Rectangle {
+                            width: 320,
+                            height: 240
+                        },
+                        spa::utils::Rectangle {
+                            width: 1,
+                            height: 1
+                        },
+                        spa::utils::Rectangle {
+                            width: 4096,
+                            height: 4096
+                        }
+                    ),
+                    pw::spa::pod::property!(
+                        pw::spa::param::format::FormatProperties::VideoFramerate,
+                        Choice,
+                        Range,
+                        Fraction,
+                        spa::utils::Fraction { num: 25, denom: 1 },
+                        spa::utils::Fraction { num: 0, denom: 1 },
+                        spa::utils::Fraction {
+                            num: 1000,
+                            denom: 1
+                        }
+                    ),
+                );
+
+                let shm_values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
+                    std::io::Cursor::new(Vec::new()),
+                    &pw::spa::pod::Value::Object(shm_obj),
+                )
+                .expect("Failed to serialize SHM fallback pod")
+                .0
+                .into_inner();
+
+                let mut params = [
+                    Pod::from_bytes(&values).unwrap(),
+                    Pod::from_bytes(&shm_values).unwrap(),
+                ];
 
                 stream.connect(
                     spa::utils::Direction::Input,
@@ -296,7 +470,7 @@ impl Capturer {
         })
     }
 
-    pub fn read_frame(&self) -> Option<Arc<PooledVaSurface<()>>> {
+    pub fn read_frame(&self) -> Option<Arc<CapturedSurface>> {
         self.user_data.frame_buffer.read()
     }
 }
@@ -307,3 +481,52 @@ impl Drop for Capturer {
         self.capture_thread.take().unwrap().join().ok();
     }
 }
+
+fn map_surface_nv12<'a>(display: &Display, surface: &'a Surface<()>) -> Image<'a> {
+    let image_fmts = display.query_image_formats().unwrap();
+    let image_fmt = image_fmts
+        .into_iter()
+        .find(|f| f.fourcc == VA_FOURCC_NV12)
+        .unwrap();
+    Image::create_from(surface, image_fmt, surface.size(), surface.size())
+        .unwrap()
+}
+
+/// CPU-upload an NV12 frame (as read straight out of a mapped SHM buffer)
+/// into a driver-allocated VA surface via `vaMapBuffer`. Same plane-by-plane
+/// copy as `vaapi_scaler::upload_nv12`, duplicated here rather than shared
+/// since that one is private to the scaler and this path has no VPP
+/// involved -- it's purely how the SHM fallback gets pixels onto a surface
+/// at all.
+fn upload_nv12(
+    display: &Display,
+    surface: &Surface<()>,
+    frame_data: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<()> {
+    let mut image = map_surface_nv12(display, surface);
+    let va_image = *image.image();
+    let dest = image.as_mut();
+    let (width, height) = (width as usize, height as usize);
+
+    let y_plane_size = width * height;
+    let y_src = &frame_data[0..y_plane_size];
+    let y_dst = &mut dest[va_image.offsets[0] as usize..va_image.offsets[0] as usize + y_plane_size];
+    for row in 0..height {
+        let src = &y_src[row * width..row * width + width];
+        let dst = &mut y_dst[row * va_image.pitches[0] as usize..row * va_image.pitches[0] as usize + width];
+        dst.copy_from_slice(src);
+    }
+
+    let uv_plane_size = width * height / 2;
+    let uv_src = &frame_data[y_plane_size..y_plane_size + uv_plane_size];
+    let uv_dst = &mut dest[va_image.offsets[1] as usize..va_image.offsets[1] as usize + uv_plane_size];
+    for row in 0..height / 2 {
+        let src = &uv_src[row * width..row * width + width];
+        let dst = &mut uv_dst[row * va_image.pitches[1] as usize..row * va_image.pitches[1] as usize + width];
+        dst.copy_from_slice(src);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+//! Portable software fallback for [`ScalerBackend`](crate::vaapi_scaler::ScalerBackend),
+//! for headless/CI machines or software-rendered setups where
+//! [`VaapiScalerBackend`](crate::vaapi_scaler::VaapiScalerBackend) can't open a display or
+//! the driver lacks a VPP entrypoint. Mirrors the role `sws_scale` plays in
+//! the ffmpeg examples (and nihav's `scale` module): a bilinear resize that
+//! needs nothing but the CPU.
+
+use anyhow::Result;
+
+use crate::vaapi_scaler::ScalerBackend;
+
+/// Bilinear NV12 scaler. The Y plane is scaled at full resolution; the
+/// interleaved UV plane is de-interleaved into independent Cb/Cr channels,
+/// each scaled at half resolution, then re-interleaved, so chroma doesn't
+/// bleed across channels the way scaling the interleaved bytes directly
+/// would.
+#[derive(Default)]
+pub struct CpuScalerBackend;
+
+impl ScalerBackend for CpuScalerBackend {
+    fn scale_nv12(
+        &mut self,
+        src: &[u8],
+        src_width: u32,
+        src_height: u32,
+        dst: &mut [u8],
+        dst_width: u32,
+        dst_height: u32,
+    ) -> Result<()> {
+        let (src_width, src_height) = (src_width as usize, src_height as usize);
+        let (dst_width, dst_height) = (dst_width as usize, dst_height as usize);
+
+        let y_src_size = src_width * src_height;
+        let y_dst_size = dst_width * dst_height;
+        scale_plane(
+            &src[..y_src_size],
+            src_width,
+            src_height,
+            &mut dst[..y_dst_size],
+            dst_width,
+            dst_height,
+        );
+
+        let (src_chroma_width, src_chroma_height) = (src_width / 2, src_height / 2);
+        let (dst_chroma_width, dst_chroma_height) = (dst_width / 2, dst_height / 2);
+        let uv_src = &src[y_src_size..y_src_size + src_chroma_width * src_chroma_height * 2];
+        let uv_dst = &mut dst[y_dst_size..y_dst_size + dst_chroma_width * dst_chroma_height * 2];
+
+        let (cb_src, cr_src) = deinterleave(uv_src, src_chroma_width * src_chroma_height);
+        let mut cb_dst = vec![0u8; dst_chroma_width * dst_chroma_height];
+        let mut cr_dst = vec![0u8; dst_chroma_width * dst_chroma_height];
+        scale_plane(
+            &cb_src,
+            src_chroma_width,
+            src_chroma_height,
+            &mut cb_dst,
+            dst_chroma_width,
+            dst_chroma_height,
+        );
+        scale_plane(
+            &cr_src,
+            src_chroma_width,
+            src_chroma_height,
+            &mut cr_dst,
+            dst_chroma_width,
+            dst_chroma_height,
+        );
+        interleave(&cb_dst, &cr_dst, uv_dst);
+
+        Ok(())
+    }
+}
+
+/// Splits an interleaved `CbCrCbCr...` plane of `pixel_count` chroma samples
+/// into two independent `Cb`/`Cr` planes.
+fn deinterleave(uv: &[u8], pixel_count: usize) -> (Vec<u8>, Vec<u8>) {
+    let mut cb = Vec::with_capacity(pixel_count);
+    let mut cr = Vec::with_capacity(pixel_count);
+    for chunk in uv.chunks_exact(2) {
+        cb.push(chunk[0]);
+        cr.push(chunk[1]);
+    }
+    (cb, cr)
+}
+
+/// Re-interleaves independently-scaled `Cb`/`Cr` planes back into `CbCrCbCr...`.
+fn interleave(cb: &[u8], cr: &[u8], out: &mut [u8]) {
+    for (i, chunk) in out.chunks_exact_mut(2).enumerate() {
+        chunk[0] = cb[i];
+        chunk[1] = cr[i];
+    }
+}
+
+/// Bilinear-resamples a single 8-bit plane from `src_width`x`src_height` to
+/// `dst_width`x`dst_height`.
+fn scale_plane(src: &[u8], src_width: usize, src_height: usize, dst: &mut [u8], dst_width: usize, dst_height: usize) {
+    if src_width == 0 || src_height == 0 || dst_width == 0 || dst_height == 0 {
+        return;
+    }
+    for y in 0..dst_height {
+        let sy = (y as f64 + 0.5) * src_height as f64 / dst_height as f64 - 0.5;
+        let y0 = sy.floor().clamp(0.0, (src_height - 1) as f64) as usize;
+        let y1 = (y0 + 1).min(src_height - 1);
+        let fy = (sy - y0 as f64).clamp(0.0, 1.0);
+
+        for x in 0..dst_width {
+            let sx = (x as f64 + 0.5) * src_width as f64 / dst_width as f64 - 0.5;
+            let x0 = sx.floor().clamp(0.0, (src_width - 1) as f64) as usize;
+            let x1 = (x0 + 1).min(src_width - 1);
+            let fx = (sx - x0 as f64).clamp(0.0, 1.0);
+
+            let p00 = src[y0 * src_width + x0] as f64;
+            let p01 = src[y0 * src_width + x1] as f64;
+            let p10 = src[y1 * src_width + x0] as f64;
+            let p11 = src[y1 * src_width + x1] as f64;
+
+            let value = p00 * (1.0 - fx) * (1.0 - fy)
+                + p01 * fx * (1.0 - fy)
+                + p10 * (1.0 - fx) * fy
+                + p11 * fx * fy;
+            dst[y * dst_width + x] = value.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Exp-Golomb SPS parsing plus PSNR comparison, used to close the loop on
+//! `encode_sample`'s encoder output: recover the resolution/profile straight
+//! out of the SPS it just produced, then (via [`crate::h264_decode`] and
+//! [`psnr`]) confirm an encode/decode round trip still looks like the frame
+//! that went in. Full slice decoding is left to [`crate::h264_decode`]'s
+//! `StatelessDecoder`-backed `H264Decoder` rather than hand-rolled here --
+//! only the SPS header is simple enough to be worth parsing by hand.
+
+use anyhow::{bail, Result};
+
+use crate::h264_vui::remove_emulation_prevention;
+
+const NAL_TYPE_SPS: u8 = 7;
+
+/// Exp-Golomb bit reader over an RBSP (emulation-prevention bytes already
+/// removed), named to read like nihav hwdec-vaapi's `ReadUE` trait.
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    bit_pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, bit_pos: 0 }
+    }
+
+    pub fn read_bit(&mut self) -> Result<u32> {
+        let byte = self.bit_pos / 8;
+        if byte >= self.data.len() {
+            bail!("ran out of bits while parsing SPS");
+        }
+        let bit = 7 - (self.bit_pos % 8);
+        let b = (self.data[byte] >> bit) & 1;
+        self.bit_pos += 1;
+        Ok(b as u32)
+    }
+
+    pub fn read_bits(&mut self, n: u32) -> Result<u64> {
+        let mut value = 0u64;
+        for _ in 0..n {
+            value = (value << 1) | self.read_bit()? as u64;
+        }
+        Ok(value)
+    }
+
+    /// `ue(v)`: a gamma code minus one -- count the leading zero bits, then
+    /// read that many more bits as the suffix.
+    pub fn read_ue(&mut self) -> Result<u64> {
+        let mut leading_zeros = 0;
+        while self.read_bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 32 {
+                bail!("malformed Exp-Golomb code in SPS");
+            }
+        }
+        if leading_zeros == 0 {
+            return Ok(0);
+        }
+        let suffix = self.read_bits(leading_zeros)?;
+        Ok((1u64 << leading_zeros) - 1 + suffix)
+    }
+
+    /// `se(v)`: zig-zag of [`Self::read_ue`] -- odd codes map to positive
+    /// values, even codes to negative ones.
+    pub fn read_se(&mut self) -> Result<i64> {
+        let code = self.read_ue()?;
+        let magnitude = ((code + 1) / 2) as i64;
+        if code % 2 == 1 {
+            Ok(magnitude)
+        } else {
+            Ok(-magnitude)
+        }
+    }
+
+    /// `te(v)`: truncated Exp-Golomb -- a single inverted bit when the range
+    /// is exactly `[0, 1]`, otherwise an ordinary `ue(v)`.
+    pub fn read_te(&mut self, range_max: u64) -> Result<u64> {
+        if range_max == 1 {
+            Ok(1 - self.read_bit()? as u64)
+        } else {
+            self.read_ue()
+        }
+    }
+}
+
+/// The fields of an H.264 SPS this crate cares about for round-trip
+/// verification: enough to recover the coded picture size without pulling
+/// in a full SPS model.
+#[derive(Debug, Clone, Copy)]
+pub struct Sps {
+    pub profile_idc: u8,
+    pub level_idc: u8,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Parse an SPS NAL unit (NAL header byte included, as stored by e.g.
+/// [`crate::mp4::Mp4Muxer`]'s `self.sps`) with an Exp-Golomb [`BitReader`],
+/// recovering resolution and profile straight from the bitstream instead of
+/// trusting whatever `width`/`height` the caller thinks it encoded with.
+pub fn parse_sps(nal: &[u8]) -> Result<Sps> {
+    if nal.is_empty() || nal[0] & 0x1f != NAL_TYPE_SPS {
+        bail!("not an SPS NAL unit");
+    }
+    let rbsp = remove_emulation_prevention(&nal[1..]);
+    let mut r = BitReader::new(&rbsp);
+
+    let profile_idc = r.read_bits(8)? as u8;
+    let _constraint_flags_and_reserved = r.read_bits(8)?;
+    let level_idc = r.read_bits(8)? as u8;
+    let _seq_parameter_set_id = r.read_ue()?;
+
+    let mut chroma_format_idc = 1u64;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format_idc = r.read_ue()?;
+        if chroma_format_idc == 3 {
+            let _separate_colour_plane_flag = r.read_bit()?;
+        }
+        let _bit_depth_luma_minus8 = r.read_ue()?;
+        let _bit_depth_chroma_minus8 = r.read_ue()?;
+        let _qpprime_y_zero_transform_bypass_flag = r.read_bit()?;
+        let seq_scaling_matrix_present_flag = r.read_bit()?;
+        if seq_scaling_matrix_present_flag != 0 {
+            bail!("SPS with an explicit scaling matrix is not supported");
+        }
+    }
+
+    let _log2_max_frame_num_minus4 = r.read_ue()?;
+    let pic_order_cnt_type = r.read_ue()?;
+    if pic_order_cnt_type == 0 {
+        let _log2_max_pic_order_cnt_lsb_minus4 = r.read_ue()?;
+    } else if pic_order_cnt_type == 1 {
+        let _delta_pic_order_always_zero_flag = r.read_bit()?;
+        let _offset_for_non_ref_pic = r.read_se()?;
+        let _offset_for_top_to_bottom_field = r.read_se()?;
+        let num_ref_frames_in_pic_order_cnt_cycle = r.read_ue()?;
+        for _ in 0..num_ref_frames_in_pic_order_cnt_cycle {
+            let _offset_for_ref_frame = r.read_se()?;
+        }
+    }
+
+    let _max_num_ref_frames = r.read_ue()?;
+    let _gaps_in_frame_num_value_allowed_flag = r.read_bit()?;
+    let pic_width_in_mbs_minus1 = r.read_ue()?;
+    let pic_height_in_map_units_minus1 = r.read_ue()?;
+    let frame_mbs_only_flag = r.read_bit()?;
+    if frame_mbs_only_flag == 0 {
+        let _mb_adaptive_frame_field_flag = r.read_bit()?;
+    }
+    let _direct_8x8_inference_flag = r.read_bit()?;
+
+    let frame_cropping_flag = r.read_bit()?;
+    let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0u64, 0u64, 0u64, 0u64);
+    if frame_cropping_flag != 0 {
+        crop_left = r.read_ue()?;
+        crop_right = r.read_ue()?;
+        crop_top = r.read_ue()?;
+        crop_bottom = r.read_ue()?;
+    }
+
+    let width = (pic_width_in_mbs_minus1 + 1) * 16;
+    let frame_height_in_mbs = (2 - frame_mbs_only_flag as u64) * (pic_height_in_map_units_minus1 + 1);
+    let height = frame_height_in_mbs * 16;
+
+    // Chroma subsampling factors for the crop units, per Table 6-1 -- this
+    // crate only ever encodes 4:2:0, so `chroma_format_idc` is always 1 in
+    // practice, but the general form costs nothing extra here.
+    let (sub_width_c, sub_height_c) = match chroma_format_idc {
+        1 => (2u64, 2u64),
+        2 => (2, 1),
+        _ => (1, 1),
+    };
+    let crop_unit_x = sub_width_c;
+    let crop_unit_y = sub_height_c * (2 - frame_mbs_only_flag as u64);
+
+    let width = width - crop_unit_x * (crop_left + crop_right);
+    let height = height - crop_unit_y * (crop_top + crop_bottom);
+
+    Ok(Sps {
+        profile_idc,
+        level_idc,
+        width: width as u32,
+        height: height as u32,
+    })
+}
+
+/// Mean-squared-error-derived PSNR (dB) between two equal-length byte
+/// buffers -- used to compare an original uploaded NV12 frame against the
+/// same frame after an encode/decode round trip. `f64::INFINITY` means the
+/// buffers are bit-identical.
+pub fn psnr(a: &[u8], b: &[u8]) -> f64 {
+    assert_eq!(a.len(), b.len(), "psnr: buffer length mismatch");
+    let mse: f64 = a.iter().zip(b).map(|(&x, &y)| {
+        let d = x as f64 - y as f64;
+        d * d
+    }).sum::<f64>() / a.len() as f64;
+    if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0f64 * 255.0 / mse).log10()
+    }
+}
+
+/// Download a decoded NV12 surface back into a tightly-packed NV12 buffer
+/// (stride == width) -- the same layout `encode_sample`'s main loop keeps
+/// its uploaded `frame_buffer`s in -- so the two can be compared directly
+/// with [`psnr`].
+pub fn download_nv12_surface(
+    display: &cros_codecs::libva::Display,
+    surface: &cros_codecs::libva::Surface<()>,
+    width: u32,
+    height: u32,
+) -> Vec<u8> {
+    let image = crate::map_surface_nv12(display, surface);
+    let va_image = *image.image();
+    let data = image.as_ref();
+    let (width, height) = (width as usize, height as usize);
+
+    let mut out = vec![0u8; width * height * 3 / 2];
+    for row in 0..height {
+        let src_off = va_image.offsets[0] as usize + row * va_image.pitches[0] as usize;
+        out[row * width..(row + 1) * width].copy_from_slice(&data[src_off..src_off + width]);
+    }
+    let y_size = width * height;
+    for row in 0..height.div_ceil(2) {
+        let src_off = va_image.offsets[1] as usize + row * va_image.pitches[1] as usize;
+        out[y_size + row * width..y_size + (row + 1) * width].copy_from_slice(&data[src_off..src_off + width]);
+    }
+    out
+}
@@ -1,4 +1,4 @@
-use std::{borrow::Borrow, sync::Arc};
+use std::{borrow::Borrow, collections::VecDeque, rc::Rc, sync::Arc};
 
 use anyhow::{anyhow, Result};
 
@@ -12,100 +12,199 @@ use cros_codecs::{
     encoder::{
         h264::{EncoderConfig, H264},
         stateless::StatelessEncoder,
-        FrameMetadata, PredictionStructure, Tunings, VideoEncoder,
+        CodedBitstreamBuffer, FrameMetadata, PredictionStructure, RateControl, Tunings,
+        VideoEncoder,
     },
-    libva::{Surface, UsageHint, VA_RT_FORMAT_YUV420},
+    libva::{Display, Surface, SurfaceMemoryDescriptor, UsageHint, VA_RT_FORMAT_YUV420},
+    video_frame::generic_dma_video_frame::GenericDmaVideoFrame,
     BlockingMode, FrameLayout, PlaneLayout, Resolution,
 };
 
+use crate::capture::CapturedSurface;
+use crate::h264_vui::{self, ColorConfig};
+
+type H264Encoder = StatelessEncoder<H264, PooledVaSurface<()>, VaapiBackend<(), PooledVaSurface<()>>>;
+
+/// Rate-control mode selectable at construction time and adjustable at
+/// runtime via [`Encoder::set_rate_control`].
+#[derive(Debug, Clone, Copy)]
+pub enum RateControlMode {
+    /// Constant bitrate, good for a steady streaming pipe.
+    ConstantBitrate { bitrate: u64 },
+    /// Target/peak bitrate, spending more bits on complex content.
+    VariableBitrate { target_bitrate: u64, max_bitrate: u64 },
+    /// Fixed QP, for quality-stable archival captures of desktop content.
+    ConstantQuality { qp: u32 },
+}
+
+impl RateControlMode {
+    fn into_rate_control(self) -> RateControl {
+        match self {
+            RateControlMode::ConstantBitrate { bitrate } => RateControl::ConstantBitrate(bitrate),
+            RateControlMode::VariableBitrate {
+                target_bitrate,
+                max_bitrate,
+            } => RateControl::VariableBitrate {
+                target_bitrate,
+                max_bitrate,
+            },
+            RateControlMode::ConstantQuality { qp } => RateControl::ConstantQuality(qp),
+        }
+    }
+}
+
+/// GOP structure selectable at construction time via [`EncoderSettings::gop`].
+///
+/// Only `LowDelay` (I/P-only, encoded and output in display order) is
+/// actually implemented: `cros-codecs`' stateless H264 backend doesn't
+/// expose a hierarchical-B prediction structure, so there is currently no
+/// way to produce real B-frames here.
+#[derive(Debug, Clone, Copy)]
+pub enum GopStructure {
+    /// I and P frames only, encoded and output in display order.
+    LowDelay { limit: u32 },
+}
+
+impl GopStructure {
+    fn into_prediction_structure(self) -> PredictionStructure {
+        match self {
+            GopStructure::LowDelay { limit } => PredictionStructure::LowDelay { limit },
+        }
+    }
+}
+
+/// Public encoder configuration accepted by [`Encoder::new`].
+#[derive(Debug, Clone)]
+pub struct EncoderSettings {
+    pub rate_control: RateControlMode,
+    pub min_quality: u32,
+    pub max_quality: u32,
+    /// VUI color-signaling metadata written into the SPS of the produced
+    /// bitstream, since `cros-codecs` doesn't surface it directly.
+    pub color: ColorConfig,
+    pub gop: GopStructure,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        Self {
+            rate_control: RateControlMode::ConstantBitrate { bitrate: 9_000_000 },
+            min_quality: 0,
+            max_quality: u32::MAX,
+            color: ColorConfig::SRGB,
+            gop: GopStructure::LowDelay { limit: 240 }, // Every 4s for 60fps
+        }
+    }
+}
+
+/// A single encoded access unit, handed out by [`Encoder::poll`].
+pub struct EncodedFrame {
+    pub bitstream: Vec<u8>,
+    /// Presentation timestamp: the `pts` this frame was passed to `encode` with.
+    pub pts: u64,
+}
+
 pub struct Encoder {
-    encoder: StatelessEncoder<H264, PooledVaSurface<()>, VaapiBackend<(), PooledVaSurface<()>>>,
+    encoder: H264Encoder,
     pub frame_layout: FrameLayout,
     pool: VaSurfacePool<()>,
     counter: u64,
+    display: Rc<Display>,
+    tunings: Tunings,
+    color: ColorConfig,
+    gop: GopStructure,
+    // Buffers drained from the previous encoder instance at a reconfiguration
+    // boundary, returned by `poll` before anything from the current one.
+    pending: VecDeque<EncodedFrame>,
+    // Bumped on every `reconfigure`, so callers/logs can tell generations apart.
+    generation: u64,
+    // Probed once at construction: whether the driver's `vaCopy` works for a
+    // surface-to-surface copy. Intel drivers do; AMD's doesn't, so
+    // `copy_surfaces` falls back to the VPP pipeline there.
+    use_va_copy: bool,
 }
 
 impl Encoder {
-    // FIXME: size changes will break this encoder
-    pub fn new(framerate: u32, first_frame: &Arc<PooledVaSurface<()>>) -> Result<Self> {
-        let surface: &Surface<()> = std::borrow::Borrow::borrow(first_frame.as_ref());
-        let width = surface.size().0;
-        let height = surface.size().1;
-        let display = surface.display().clone();
-        let config = EncoderConfig {
-            resolution: Resolution { width, height },
-            profile: Profile::Main,
-            level: Level::L4_1,
-            pred_structure: PredictionStructure::LowDelay { limit: 240 }, // Every 4s for 60fps
-            initial_tunings: Tunings {
-                rate_control: cros_codecs::encoder::RateControl::ConstantBitrate(9_000_000),
-                framerate,
-                min_quality: 0,
-                max_quality: u32::MAX,
-            },
+    pub fn new(
+        framerate: u32,
+        first_frame: &Arc<CapturedSurface>,
+        settings: EncoderSettings,
+    ) -> Result<Self> {
+        let (width, height) = first_frame.size();
+        let display = first_frame.display();
+        let tunings = Tunings {
+            rate_control: settings.rate_control.into_rate_control(),
+            framerate,
+            min_quality: settings.min_quality,
+            max_quality: settings.max_quality,
         };
-        let fourcc = cros_codecs::Fourcc::from(b"NV12");
-        let frame_layout = FrameLayout {
-            format: (fourcc, 0),
-            size: Resolution { width, height },
-            planes: vec![
-                PlaneLayout {
-                    buffer_index: 0,
-                    offset: 0,
-                    stride: width as usize,
-                },
-                PlaneLayout {
-                    buffer_index: 0,
-                    offset: width as usize * height as usize,
-                    stride: width as usize,
-                },
-            ],
-        };
-        let coded_size = cros_codecs::Resolution { width, height };
-        let low_power = false;
-        let blocking_mode = BlockingMode::NonBlocking;
-        let encoder = StatelessEncoder::<H264, _, _>::new_native_vaapi(
-            display.clone(),
-            config,
-            fourcc,
-            coded_size,
-            low_power,
-            blocking_mode,
-        )
-        .expect("Failed to create H264 encoder");
 
-        let mut pool = VaSurfacePool::<()>::new(
-            display.clone(),
-            VA_RT_FORMAT_YUV420,
-            Some(UsageHint::USAGE_HINT_ENCODER),
-            Resolution { width, height },
-        );
-        pool.add_frames(vec![(); 16])
-            .expect("Failed to add frames to pool");
+        let (encoder, pool, frame_layout) = build_encoder(
+            &display,
+            width,
+            height,
+            tunings.clone(),
+            settings.gop.into_prediction_structure(),
+        )?;
+        let use_va_copy = probe_va_copy_support(&display);
 
         Ok(Encoder {
             encoder,
-            frame_layout: frame_layout.clone(),
+            frame_layout,
             pool,
             counter: 0,
+            display,
+            tunings,
+            color: settings.color,
+            gop: settings.gop,
+            pending: VecDeque::new(),
+            generation: 0,
+            use_va_copy,
         })
     }
 
-    pub fn encode(&mut self, input_surface: Arc<PooledVaSurface<()>>) -> Result<()> {
+    /// Encode `input_surface`, timestamped `pts` -- the caller's choice of
+    /// units, carried straight through to the matching
+    /// [`EncodedFrame::pts`](crate::encode::EncodedFrame::pts) so a capture
+    /// loop threading through real capture timestamps (for variable frame
+    /// rate) doesn't have its timing flattened to a fixed per-frame tick.
+    pub fn encode(&mut self, input_surface: Arc<CapturedSurface>, pts: u64) -> Result<()> {
+        let (width, height) = input_surface.size();
+        if Resolution { width, height } != self.frame_layout.size {
+            self.reconfigure(width, height)?;
+        }
+
         let meta = FrameMetadata {
-            timestamp: self.counter,
+            timestamp: pts,
             layout: self.frame_layout.clone(),
             force_keyframe: false,
         };
 
+        // The imported dma-buf surface (or, via the SHM fallback, a surface
+        // CPU-uploaded from a mapped buffer) is still shared with the
+        // capturer's frame buffer (see `FrameBuffer`), so it can't be handed
+        // to the encoder directly: it still needs a VPP copy into a surface
+        // we exclusively own. This is the one case `copy_surfaces` is still
+        // required for now, even though the dma-buf capture path no longer
+        // does a copy of its own to get here.
         let pooled_surface = self
             .pool
             .get_surface()
             .expect("Failed to get surface from pool");
-        copy_surfaces(input_surface.as_ref().borrow(), pooled_surface.borrow())
-            .map_err(|e| anyhow!("{}", e))?;
+        match input_surface.as_ref() {
+            CapturedSurface::DmaBuf(s) => copy_surfaces(
+                std::borrow::Borrow::<Surface<GenericDmaVideoFrame>>::borrow(s),
+                pooled_surface.borrow(),
+                self.use_va_copy,
+            ),
+            CapturedSurface::Shm(s) => copy_surfaces(
+                std::borrow::Borrow::<Surface<()>>::borrow(s),
+                pooled_surface.borrow(),
+                self.use_va_copy,
+            ),
+        }
+        .map_err(|e| anyhow!("{}", e))?;
 
-        self.counter += 1;
         // FIXME: implement Error for EncodeError
         self.encoder
             .encode(meta, pooled_surface)
@@ -113,22 +212,234 @@ impl Encoder {
         Ok(())
     }
 
+    /// Next sequential timestamp for a fixed-framerate capture, where
+    /// `encode`'s `pts` is simply "the next tick": one integer per frame, at
+    /// a timescale of the configured framerate.
+    pub fn next_cfr_pts(&mut self) -> u64 {
+        let pts = self.counter;
+        self.counter += 1;
+        pts
+    }
+
+    /// Rebuild the encoder and surface pool for a new input resolution,
+    /// keeping the `counter` timestamp continuous across the boundary.
+    ///
+    /// Mirrors the "mid stream configuration change" handling in the Fuchsia
+    /// VAAPI adapter: drain and fully flush the old encoder's in-flight
+    /// surfaces before standing up the new pool, so nothing from the old
+    /// generation leaks into the new one.
+    pub fn reconfigure(&mut self, width: u32, height: u32) -> Result<()> {
+        // Drain the old encoder and stash any buffers it still owes us; they
+        // get handed back to the caller on the next `poll` calls.
+        self.encoder.drain().expect("Failed to drain encoder");
+        while let Some(mut buffer) = self.encoder.poll().expect("Failed to poll encoder") {
+            h264_vui::patch_sps_vui(&mut buffer.bitstream, self.color)?;
+            self.pending.push_back(self.to_encoded_frame(buffer));
+        }
+
+        let (encoder, pool, frame_layout) = build_encoder(
+            &self.display,
+            width,
+            height,
+            self.tunings.clone(),
+            self.gop.into_prediction_structure(),
+        )?;
+        self.encoder = encoder;
+        self.pool = pool;
+        self.frame_layout = frame_layout;
+        self.generation += 1;
+
+        Ok(())
+    }
+
+    /// Update the rate-control mode on the running encoder without a full
+    /// `reconfigure`, feeding the new `Tunings` into the stateless encoder's
+    /// tuning-change mechanism.
+    pub fn set_rate_control(&mut self, rate_control: RateControlMode) -> Result<()> {
+        self.tunings.rate_control = rate_control.into_rate_control();
+        self.encoder
+            .tune(self.tunings.clone())
+            .map_err(|e| anyhow!("Failed to update encoder tunings: {:?}", e))?;
+        Ok(())
+    }
+
     pub fn drain(&mut self) -> Result<()> {
         // FIXME: implement Error for EncodeError
         self.encoder.drain().expect("Failed to drain encoder");
         Ok(())
     }
 
-    pub fn poll(&mut self) -> Result<Option<cros_codecs::encoder::CodedBitstreamBuffer>> {
+    pub fn poll(&mut self) -> Result<Option<EncodedFrame>> {
+        if let Some(frame) = self.pending.pop_front() {
+            return Ok(Some(frame));
+        }
         // FIXME: implement Error for EncodeError
-        let bitstream_buffer = self.encoder.poll().expect("Failed to poll encoder");
-        Ok(bitstream_buffer)
+        let mut bitstream_buffer = self.encoder.poll().expect("Failed to poll encoder");
+        if let Some(buffer) = &mut bitstream_buffer {
+            h264_vui::patch_sps_vui(&mut buffer.bitstream, self.color)?;
+        }
+        Ok(bitstream_buffer.map(|buffer| self.to_encoded_frame(buffer)))
+    }
+
+    /// Pair a `CodedBitstreamBuffer` coming out of the encoder with its
+    /// `pts` (carried back via the `FrameMetadata` it was encoded with).
+    fn to_encoded_frame(&self, buffer: CodedBitstreamBuffer) -> EncodedFrame {
+        EncodedFrame {
+            bitstream: buffer.bitstream,
+            pts: buffer.metadata.timestamp,
+        }
     }
 }
 
-pub fn copy_surfaces(src_surface: &Surface<()>, dst_surface: &Surface<()>) -> Result<(), String> {
+/// Build the `(encoder, pool, frame_layout)` triple for a given resolution.
+/// Shared by `Encoder::new` and `Encoder::reconfigure` so both paths always
+/// agree on how a generation is constructed.
+fn build_encoder(
+    display: &Rc<Display>,
+    width: u32,
+    height: u32,
+    tunings: Tunings,
+    pred_structure: PredictionStructure,
+) -> Result<(H264Encoder, VaSurfacePool<()>, FrameLayout)> {
+    let config = EncoderConfig {
+        resolution: Resolution { width, height },
+        profile: Profile::Main,
+        level: Level::L4_1,
+        pred_structure,
+        initial_tunings: tunings,
+    };
+    let fourcc = cros_codecs::Fourcc::from(b"NV12");
+    let frame_layout = FrameLayout {
+        format: (fourcc, 0),
+        size: Resolution { width, height },
+        planes: vec![
+            PlaneLayout {
+                buffer_index: 0,
+                offset: 0,
+                stride: width as usize,
+            },
+            PlaneLayout {
+                buffer_index: 0,
+                offset: width as usize * height as usize,
+                stride: width as usize,
+            },
+        ],
+    };
+    let coded_size = cros_codecs::Resolution { width, height };
+    let low_power = false;
+    let blocking_mode = BlockingMode::NonBlocking;
+    let encoder = StatelessEncoder::<H264, _, _>::new_native_vaapi(
+        display.clone(),
+        config,
+        fourcc,
+        coded_size,
+        low_power,
+        blocking_mode,
+    )
+    .expect("Failed to create H264 encoder");
+
+    let mut pool = VaSurfacePool::<()>::new(
+        display.clone(),
+        VA_RT_FORMAT_YUV420,
+        Some(UsageHint::USAGE_HINT_ENCODER),
+        Resolution { width, height },
+    );
+    pool.add_frames(vec![(); 16])
+        .expect("Failed to add frames to pool");
+
+    Ok((encoder, pool, frame_layout))
+}
+
+/// One-time capability probe for whether `vaCopy` works as a surface-to-surface
+/// copy on this driver. Creates two throwaway driver-allocated surfaces and
+/// attempts a single `vaCopy` between them; Intel's iHD driver supports this,
+/// AMD's radeonsi/Mesa driver doesn't (it returns `VA_STATUS_ERROR_UNIMPLEMENTED`).
+fn probe_va_copy_support(display: &Rc<Display>) -> bool {
+    use cros_codecs::libva::*;
+
+    let mut pool = VaSurfacePool::<()>::new(
+        display.clone(),
+        VA_RT_FORMAT_YUV420,
+        Some(UsageHint::USAGE_HINT_VPP_READ | UsageHint::USAGE_HINT_VPP_WRITE),
+        Resolution {
+            width: 16,
+            height: 16,
+        },
+    );
+    if pool.add_frames(vec![(), ()]).is_err() {
+        return false;
+    }
+    let (Ok(src), Ok(dst)) = (pool.get_surface(), pool.get_surface()) else {
+        return false;
+    };
+    let src_surface: &Surface<()> = src.borrow();
+    let dst_surface: &Surface<()> = dst.borrow();
+
+    let mut dst_object = _VACopyObject {
+        obj_type: VACopyObjectType::VACopyObjectSurface,
+        object: _VACopyObject__bindgen_ty_1 {
+            surface_id: dst_surface.id(),
+        },
+        ..Default::default()
+    };
+    let mut src_object = _VACopyObject {
+        obj_type: VACopyObjectType::VACopyObjectSurface,
+        object: _VACopyObject__bindgen_ty_1 {
+            surface_id: src_surface.id(),
+        },
+        ..Default::default()
+    };
+
+    let ret = unsafe {
+        vaCopy(
+            display.handle(),
+            &mut dst_object,
+            &mut src_object,
+            Default::default(),
+        )
+    };
+
+    ret == VA_STATUS_SUCCESS as i32
+}
+
+pub fn copy_surfaces<M: SurfaceMemoryDescriptor>(
+    src_surface: &Surface<M>,
+    dst_surface: &Surface<()>,
+    use_va_copy: bool,
+) -> Result<(), String> {
     use cros_codecs::libva::{VAProfile::VAProfileNone, *};
 
+    if use_va_copy {
+        let mut dst_object = _VACopyObject {
+            obj_type: VACopyObjectType::VACopyObjectSurface,
+            object: _VACopyObject__bindgen_ty_1 {
+                surface_id: dst_surface.id(),
+            },
+            ..Default::default()
+        };
+        let mut src_object = _VACopyObject {
+            obj_type: VACopyObjectType::VACopyObjectSurface,
+            object: _VACopyObject__bindgen_ty_1 {
+                surface_id: src_surface.id(),
+            },
+            ..Default::default()
+        };
+
+        let ret = unsafe {
+            vaCopy(
+                src_surface.display().handle(),
+                &mut dst_object,
+                &mut src_object,
+                Default::default(),
+            )
+        };
+        if ret != VA_STATUS_SUCCESS as i32 {
+            return Err(format!("Error copying surface via vaCopy: {ret:?}"));
+        }
+        unsafe { vaSyncSurface(src_surface.display().handle(), dst_surface.id()) };
+        return Ok(());
+    }
+
     // TODO: implement proper bindings in cros-libva
     let mut vpp_config = Default::default();
     let mut vpp_context = Default::default();
@@ -204,29 +515,5 @@ pub fn copy_surfaces(src_surface: &Surface<()>, dst_surface: &Surface<()>) -> Re
         vaDestroyConfig(raw_display, vpp_config);
     };
 
-    // TODO: detect and use vaCopy when possible instead as below, since it's faster.
-    // It doesn't work on AMD though.
-
-    // let mut dst_object = _VACopyObject {
-    //     obj_type: VACopyObjectType::VACopyObjectSurface,
-    //     object: _VACopyObject__bindgen_ty_1 { surface_id: dst_surface.id() },
-    //     ..Default::default()
-    // };
-    // let mut src_object = _VACopyObject {
-    //     obj_type: VACopyObjectType::VACopyObjectSurface,
-    //     object: _VACopyObject__bindgen_ty_1 { surface_id: src_surface.id() },
-    //     ..Default::default()
-    // };
-
-    // let ret = unsafe {
-    //     vaCopy(display.handle(), &mut dst_object, &mut src_object, Default::default())
-    // };
-
-    // if ret != VA_STATUS_SUCCESS as i32 {
-    //     return Err(format!("Error copying GenericDmaVideoFrame to VA-API surface: {ret:?}"));
-    // }
-
-    // unsafe { vaSyncSurface(display.handle(), dst_surface.id()) };
-
     Ok(())
 }
@@ -5,43 +5,218 @@ use std::{
     slice,
     str::FromStr,
     sync::Arc,
+    time::Instant,
 };
 
 use anyhow::{bail, Context, Result};
-use cros_codecs::{
-    backend::vaapi::surface_pool::PooledVaSurface,
-    libva::{Surface, VADisplay, VASurfaceID},
-};
+use cros_codecs::libva::{VADisplay, VASurfaceID};
 use rsmpeg::{
     avcodec::{AVCodec, AVCodecContext},
     avutil::{ra, AVDictionary, AVFrame, AVHWDeviceContext},
     error::RsmpegError,
     ffi::{
-        self, AV_HWDEVICE_TYPE_VAAPI, AV_PIX_FMT_NV12, AV_PIX_FMT_VAAPI, FF_PROFILE_H264_BASELINE,
-        FF_PROFILE_H264_CONSTRAINED_BASELINE,
+        self, AV_HWDEVICE_TYPE_VAAPI, AV_PIX_FMT_NV12, AV_PIX_FMT_VAAPI, FF_PROFILE_AV1_MAIN,
+        FF_PROFILE_H264_BASELINE, FF_PROFILE_H264_CONSTRAINED_BASELINE, FF_PROFILE_H264_HIGH,
+        FF_PROFILE_H264_MAIN, FF_PROFILE_HEVC_MAIN, FF_PROFILE_UNKNOWN,
     },
 };
 
+use crate::capture::CapturedSurface;
+use crate::h264_vui::ColorConfig;
+
 #[repr(C)]
 pub struct AVVAAPIDeviceContext {
     pub display: *mut c_void, // VADisplay is typically a void pointer
     pub driver_quirks: c_uint,
 }
 
+/// Codec family selectable via [`EncoderConfig::codec`]. All three name a
+/// VAAPI encoder FFmpeg exposes; which ones actually open depends on what
+/// the driver's VAAPI profiles report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    H264,
+    Hevc,
+    Av1,
+}
+
+impl Codec {
+    fn encoder_name(self) -> &'static std::ffi::CStr {
+        match self {
+            Codec::H264 => c"h264_vaapi",
+            Codec::Hevc => c"hevc_vaapi",
+            Codec::Av1 => c"av1_vaapi",
+        }
+    }
+}
+
+/// Rate-control mode selectable via [`EncoderConfig::rate_control`], mirroring
+/// the bitrate/bitrate-mode split in crosvm's video encoder. Each variant
+/// maps onto the VAAPI `rc_mode` private option FFmpeg's VAAPI encoders
+/// expect (1 = CQP, 2 = CBR, 3 = VBR) plus the matching bitrate/QP fields.
+#[derive(Debug, Clone, Copy)]
+pub enum RateControlMode {
+    /// Fixed QP: spend however many bits a frame needs to hit that quality.
+    ConstantQuality { qp: i32 },
+    /// Constant bitrate: equal target/peak/buffer, good for a steady streaming pipe.
+    ConstantBitrate { bitrate: i64 },
+    /// Target/peak bitrate: spend more bits on complex content, up to `max_bitrate`.
+    VariableBitrate { bitrate: i64, max_bitrate: i64 },
+}
+
+impl RateControlMode {
+    fn rc_mode(self) -> i64 {
+        match self {
+            RateControlMode::ConstantQuality { .. } => 1,
+            RateControlMode::ConstantBitrate { .. } => 2,
+            RateControlMode::VariableBitrate { .. } => 3,
+        }
+    }
+
+    fn apply(self, avctx: &mut AVCodecContext) {
+        match self {
+            RateControlMode::ConstantQuality { qp } => {
+                avctx.set_bit_rate(0);
+                avctx.set_qmin(qp);
+                avctx.set_qmax(qp);
+            }
+            RateControlMode::ConstantBitrate { bitrate } => {
+                avctx.set_bit_rate(bitrate);
+                avctx.set_rc_max_rate(bitrate);
+                avctx.set_rc_buffer_size(bitrate);
+                avctx.set_qmin(20);
+                avctx.set_qmax(32);
+            }
+            RateControlMode::VariableBitrate { bitrate, max_bitrate } => {
+                avctx.set_bit_rate(bitrate);
+                avctx.set_rc_max_rate(max_bitrate);
+                avctx.set_rc_buffer_size(max_bitrate * 2);
+                avctx.set_qmin(20);
+                avctx.set_qmax(32);
+            }
+        }
+    }
+}
+
+/// H264/HEVC/AV1 profile requested via [`EncoderConfig::profile`]. Checked
+/// against what the selected codec actually reports at open time (see
+/// [`resolve_profile`]), falling back to the codec's default rather than
+/// failing to open on a mismatch.
+#[derive(Debug, Clone, Copy)]
+pub enum Profile {
+    H264Baseline,
+    H264ConstrainedBaseline,
+    H264Main,
+    H264High,
+    HevcMain,
+    Av1Main,
+}
+
+impl Profile {
+    fn ff_profile(self) -> i32 {
+        match self {
+            Profile::H264Baseline => FF_PROFILE_H264_BASELINE as i32,
+            Profile::H264ConstrainedBaseline => FF_PROFILE_H264_CONSTRAINED_BASELINE as i32,
+            Profile::H264Main => FF_PROFILE_H264_MAIN as i32,
+            Profile::H264High => FF_PROFILE_H264_HIGH as i32,
+            Profile::HevcMain => FF_PROFILE_HEVC_MAIN as i32,
+            Profile::Av1Main => FF_PROFILE_AV1_MAIN as i32,
+        }
+    }
+}
+
+/// Walk `codec`'s advertised `AVProfile` list (terminated by a
+/// `FF_PROFILE_UNKNOWN` sentinel entry) and return `requested` if the codec
+/// lists it, or the codec's first (default) profile otherwise -- so asking
+/// e.g. an HEVC build for `Profile::H264High` degrades to whatever that
+/// codec actually supports instead of failing `avctx.open`.
+fn resolve_profile(codec: &AVCodec, requested: i32) -> i32 {
+    let profiles = unsafe { (*codec.as_ptr()).profiles };
+    if profiles.is_null() {
+        return FF_PROFILE_UNKNOWN as i32;
+    }
+    let mut fallback = None;
+    let mut i = 0isize;
+    loop {
+        let entry = unsafe { *profiles.offset(i) };
+        if entry.profile == FF_PROFILE_UNKNOWN as i32 {
+            break;
+        }
+        if fallback.is_none() {
+            fallback = Some(entry.profile);
+        }
+        if entry.profile == requested {
+            return requested;
+        }
+        i += 1;
+    }
+    fallback.unwrap_or(FF_PROFILE_UNKNOWN as i32)
+}
+
+/// Public encoder configuration accepted by [`Encoder::new`].
+#[derive(Debug, Clone)]
+pub struct EncoderConfig {
+    pub codec: Codec,
+    pub rate_control: RateControlMode,
+    /// Frames between keyframes. `0` means "one GOP per second of `framerate`".
+    pub gop_size: i32,
+    pub max_b_frames: i32,
+    pub profile: Profile,
+    /// Color standard/range the VPP copy in [`Encoder::encode`] tags both its
+    /// input and output with, matching the format PipeWire negotiated for
+    /// capture; no conversion between standards is done.
+    pub color: ColorConfig,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            codec: Codec::H264,
+            rate_control: RateControlMode::VariableBitrate {
+                bitrate: 9_000_000,
+                max_bitrate: 11_000_000,
+            },
+            gop_size: 0,
+            max_b_frames: 0,
+            profile: Profile::H264ConstrainedBaseline,
+            color: ColorConfig::REC709,
+        }
+    }
+}
+
+/// How [`Encoder::encode`] derives each frame's PTS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtsMode {
+    /// One tick per frame at the configured framerate -- correct only if
+    /// capture genuinely delivers frames at that exact cadence.
+    Cfr,
+    /// Wall-clock time elapsed since the encoder was created, converted to
+    /// the encoder's `1/framerate` time base. Tracks real capture jitter
+    /// and stalls instead of flattening every frame to a fixed tick.
+    Real,
+}
+
 pub struct Encoder {
-    _counter: u64,
+    counter: i64,
+    start: Instant,
+    framerate: i32,
     avctx: AVCodecContext,
+    surface_copier: SurfaceCopier,
+    color: ColorConfig,
 }
 
 impl Encoder {
     // FIXME: size changes will break this encoder
-    pub fn new(framerate: i32, first_frame: &Arc<PooledVaSurface<()>>) -> Result<Self> {
+    pub fn new(
+        framerate: i32,
+        first_frame: &Arc<CapturedSurface>,
+        config: EncoderConfig,
+    ) -> Result<Self> {
         println!("Encoder::new - Starting encoder initialization");
-        let surface: &Surface<()> = std::borrow::Borrow::borrow(first_frame.as_ref());
-        let width = surface.size().0 as i32;
-        let height = surface.size().1 as i32;
+        let (width, height) = first_frame.size();
+        let (width, height) = (width as i32, height as i32);
         println!("Encoder::new - Surface size: {}x{}", width, height);
-        let display = surface.display().clone();
+        let display = first_frame.display();
         let mut hw_device_ctx = AVHWDeviceContext::alloc(AV_HWDEVICE_TYPE_VAAPI);
         let device_ctx = unsafe { *hw_device_ctx.as_mut_ptr() }.data as *mut ffi::AVHWDeviceContext;
         let vaapi_ctx = unsafe { *device_ctx }.hwctx as *mut AVVAAPIDeviceContext;
@@ -53,8 +228,8 @@ impl Encoder {
             .init()
             .context("Failed to initialize VAAPI device context")?;
 
-        let codec =
-            AVCodec::find_encoder_by_name(c"h264_vaapi").context("Could not find encoder.")?;
+        let codec = AVCodec::find_encoder_by_name(config.codec.encoder_name())
+            .context("Could not find encoder.")?;
         let mut avctx = AVCodecContext::new(&codec);
 
         avctx.set_width(width);
@@ -64,20 +239,27 @@ impl Encoder {
         avctx.set_sample_aspect_ratio(ra(1, 1));
         avctx.set_pix_fmt(AV_PIX_FMT_VAAPI);
 
-        // WebRTC settings
-        avctx.set_bit_rate(9_000_000);
-        avctx.set_rc_max_rate(11_000_000);
-        avctx.set_rc_buffer_size(9_000_000 * 2);
-        avctx.set_max_b_frames(0);
-        avctx.set_gop_size(framerate);
-        avctx.set_keyint_min(framerate);
-        avctx.set_refs(1);
-        avctx.set_qmin(20);
-        avctx.set_qmax(32);
-        avctx.set_profile(FF_PROFILE_H264_CONSTRAINED_BASELINE as i32);
-
-        let opts = AVDictionary::new_int(CString::from_str("rc_mode").unwrap().as_c_str(), 3, 0)
-            .set_int(CString::from_str("quality").unwrap().as_c_str(), 4, 0);
+        let gop_size = if config.gop_size == 0 {
+            framerate
+        } else {
+            config.gop_size
+        };
+        config.rate_control.apply(&mut avctx);
+        avctx.set_max_b_frames(config.max_b_frames);
+        avctx.set_gop_size(gop_size);
+        avctx.set_keyint_min(gop_size);
+        avctx.set_refs(if config.max_b_frames > 0 { 2 } else { 1 });
+        avctx.set_profile(resolve_profile(&codec, config.profile.ff_profile()));
+
+        let mut opts = AVDictionary::new_int(
+            CString::from_str("rc_mode").unwrap().as_c_str(),
+            config.rate_control.rc_mode(),
+            0,
+        )
+        .set_int(CString::from_str("quality").unwrap().as_c_str(), 4, 0);
+        if let RateControlMode::ConstantQuality { qp } = config.rate_control {
+            opts = opts.set_int(CString::from_str("qp").unwrap().as_c_str(), qp as i64, 0);
+        }
 
         let mut hw_frames_ref = hw_device_ctx.hwframe_ctx_alloc();
         hw_frames_ref.data().format = AV_PIX_FMT_VAAPI;
@@ -95,14 +277,24 @@ impl Encoder {
             .open(Some(opts))
             .context("Cannot open video encoder codec")?;
 
+        let surface_copier = SurfaceCopier::new(display.handle())?;
+
         println!("Encoder::new - Encoder created successfully");
-        Ok(Encoder { _counter: 0, avctx })
+        Ok(Encoder {
+            counter: 0,
+            start: Instant::now(),
+            framerate,
+            avctx,
+            surface_copier,
+            color: config.color,
+        })
     }
 
-    pub fn encode(&mut self, input_surface: Arc<PooledVaSurface<()>>) -> Result<()> {
-        let surface: &Surface<()> = std::borrow::Borrow::borrow(input_surface.as_ref());
-        let width = surface.size().0 as i32;
-        let height = surface.size().1 as i32;
+    /// Encode `input_surface`, timestamped per `pts_mode` in the encoder's
+    /// `1/framerate` time base.
+    pub fn encode(&mut self, input_surface: Arc<CapturedSurface>, pts_mode: PtsMode) -> Result<()> {
+        let (width, height) = input_surface.size();
+        let (width, height) = (width as i32, height as i32);
 
         let mut pooled_frame = AVFrame::new();
         self.avctx
@@ -111,12 +303,30 @@ impl Encoder {
             .get_buffer(&mut pooled_frame)
             .context("Get buffer failed")?;
 
-        let dpy = surface.display().handle();
-        let src_surface = surface.id();
+        let src_surface = input_surface.id();
         let dst_surface = pooled_frame.data_mut()[3] as u32;
-        copy_surfaces(dpy, src_surface, dst_surface, width, height)
+        let (dst_width, dst_height) =
+            unsafe { ((*self.avctx.as_ptr()).width, (*self.avctx.as_ptr()).height) };
+        self.surface_copier
+            .copy(
+                src_surface,
+                (width, height),
+                dst_surface,
+                (dst_width, dst_height),
+                self.color,
+            )
             .context("Failed to copy surfaces")?;
 
+        let pts = match pts_mode {
+            PtsMode::Cfr => {
+                let pts = self.counter;
+                self.counter += 1;
+                pts
+            }
+            PtsMode::Real => (self.start.elapsed().as_secs_f64() * self.framerate as f64).round() as i64,
+        };
+        pooled_frame.set_pts(pts);
+
         self.avctx
             .send_frame(Some(&pooled_frame))
             .context("Send frame failed")?;
@@ -169,89 +379,201 @@ impl Encoder {
             .context("Failed to write packet data to file")?;
         Ok(num_packets)
     }
+
+    /// Signal end of stream, for callers (e.g. [`crate::pipeline_ffmpeg`])
+    /// that drain via [`Encoder::poll_packet`] instead of `drain_write`.
+    pub fn signal_eof(&mut self) -> Result<()> {
+        self.avctx.send_frame(None).context("Send frame failed")
+    }
+
+    /// Pull the next coded packet, for callers that mux it themselves
+    /// instead of writing raw Annex-B to a file. Returns `None` once the
+    /// encoder (or, after [`Encoder::signal_eof`], the drain) has nothing
+    /// left to give.
+    pub fn poll_packet(&mut self) -> Result<Option<rsmpeg::avcodec::AVPacket>> {
+        match self.avctx.receive_packet() {
+            Ok(packet) => Ok(Some(packet)),
+            Err(RsmpegError::EncoderDrainError) | Err(RsmpegError::EncoderFlushedError) => Ok(None),
+            Err(e) => Err(e).context("Receive packet failed"),
+        }
+    }
+
+    /// The underlying codec context, so a muxer can copy its codec
+    /// parameters/extradata into an output stream.
+    pub fn avctx(&self) -> &AVCodecContext {
+        &self.avctx
+    }
 }
 
-pub fn copy_surfaces(
-    raw_display: VADisplay,
-    src_surface: VASurfaceID,
-    mut dst_surface: VASurfaceID,
-    width: i32,
-    height: i32,
-) -> Result<()> {
-    use cros_codecs::libva::{VAProfile::VAProfileNone, *};
-
-    // TODO: implement proper bindings in cros-libva
-    let mut vpp_config = Default::default();
-    let mut vpp_context = Default::default();
-
-    let ret = unsafe {
-        vaCreateConfig(
-            raw_display,
-            VAProfileNone,
-            VAEntrypoint::VAEntrypointVideoProc,
-            std::ptr::null_mut(),
-            0,
-            &mut vpp_config,
-        )
-    };
-    if ret != VA_STATUS_SUCCESS as i32 {
-        bail!("Error creating VPP config: {ret:?}");
+/// VPP scale + color-convert, caching the `VAConfigID`/`VAContextID` across
+/// calls instead of creating and destroying them per frame -- a measurable
+/// cost in a realtime capture loop. The context is only rebuilt when the
+/// destination size actually changes.
+pub struct SurfaceCopier {
+    display: VADisplay,
+    config: cros_codecs::libva::VAConfigID,
+    // Cached alongside the size it was built for, so a resize is detected
+    // and the context rebuilt rather than silently scaling into stale
+    // dimensions.
+    context: Option<(cros_codecs::libva::VAContextID, i32, i32)>,
+}
+
+impl SurfaceCopier {
+    pub fn new(display: VADisplay) -> Result<Self> {
+        use cros_codecs::libva::{VAProfile::VAProfileNone, *};
+
+        let mut config = Default::default();
+        let ret = unsafe {
+            vaCreateConfig(
+                display,
+                VAProfileNone,
+                VAEntrypoint::VAEntrypointVideoProc,
+                std::ptr::null_mut(),
+                0,
+                &mut config,
+            )
+        };
+        if ret != VA_STATUS_SUCCESS as i32 {
+            bail!("Error creating VPP config: {ret:?}");
+        }
+        Ok(Self {
+            display,
+            config,
+            context: None,
+        })
     }
 
-    let ret = unsafe {
-        vaCreateContext(
-            raw_display,
-            vpp_config,
-            width,
-            height,
-            VA_PROGRESSIVE as i32,
-            &mut dst_surface,
-            1,
-            &mut vpp_context,
-        )
-    };
-    if ret != VA_STATUS_SUCCESS as i32 {
-        unsafe { vaDestroyConfig(raw_display, vpp_config) };
-        bail!("Error creating VPP context: {ret:?}");
+    fn context_for(
+        &mut self,
+        dst_surface: VASurfaceID,
+        width: i32,
+        height: i32,
+    ) -> Result<cros_codecs::libva::VAContextID> {
+        use cros_codecs::libva::*;
+
+        if let Some((context, cached_width, cached_height)) = self.context {
+            if cached_width == width && cached_height == height {
+                return Ok(context);
+            }
+            unsafe { vaDestroyContext(self.display, context) };
+            self.context = None;
+        }
+
+        let mut context = Default::default();
+        let mut render_targets = [dst_surface];
+        let ret = unsafe {
+            vaCreateContext(
+                self.display,
+                self.config,
+                width,
+                height,
+                VA_PROGRESSIVE as i32,
+                render_targets.as_mut_ptr(),
+                render_targets.len() as i32,
+                &mut context,
+            )
+        };
+        if ret != VA_STATUS_SUCCESS as i32 {
+            bail!("Error creating VPP context: {ret:?}");
+        }
+        self.context = Some((context, width, height));
+        Ok(context)
     }
 
-    let pipeline_param = VAProcPipelineParameterBuffer {
-        surface: src_surface,
-        ..Default::default()
-    };
-    let mut params = [pipeline_param];
-
-    let mut pipeline_buf = Default::default();
-    let ret = unsafe {
-        vaCreateBuffer(
-            raw_display,
-            vpp_context,
-            VABufferType::VAProcPipelineParameterBufferType,
-            std::mem::size_of::<VAProcPipelineParameterBuffer>() as u32,
-            1,
-            params.as_mut_ptr() as *mut _,
-            &mut pipeline_buf,
-        )
-    };
+    /// Scale `src_surface` (`src_size`) into `dst_surface` (`dst_size`) --
+    /// e.g. downscaling a 4K gamescope surface to the encoder's resolution.
+    /// `color`'s standard is set as both the input and the output color
+    /// standard and its range flag as the source range, so the pixels are
+    /// scaled but not converted between color standards.
+    pub fn copy(
+        &mut self,
+        src_surface: VASurfaceID,
+        src_size: (i32, i32),
+        dst_surface: VASurfaceID,
+        dst_size: (i32, i32),
+        color: ColorConfig,
+    ) -> Result<()> {
+        use cros_codecs::libva::*;
+
+        let context = self.context_for(dst_surface, dst_size.0, dst_size.1)?;
+
+        let src_rect = VARectangle {
+            x: 0,
+            y: 0,
+            width: src_size.0 as u16,
+            height: src_size.1 as u16,
+        };
+        let dst_rect = VARectangle {
+            x: 0,
+            y: 0,
+            width: dst_size.0 as u16,
+            height: dst_size.1 as u16,
+        };
+        let color_standard = va_color_standard(color);
+        let range_flags = if color.video_full_range_flag {
+            VA_SOURCE_RANGE_FULL
+        } else {
+            VA_SOURCE_RANGE_REDUCED
+        };
+
+        let pipeline_param = VAProcPipelineParameterBuffer {
+            surface: src_surface,
+            surface_region: &src_rect,
+            output_region: &dst_rect,
+            filter_flags: (VA_FILTER_SCALING_DEFAULT | range_flags) as i32,
+            input_color_standard: color_standard,
+            output_color_standard: color_standard,
+            ..Default::default()
+        };
+        let mut params = [pipeline_param];
+
+        let mut pipeline_buf = Default::default();
+        let ret = unsafe {
+            vaCreateBuffer(
+                self.display,
+                context,
+                VABufferType::VAProcPipelineParameterBufferType,
+                std::mem::size_of::<VAProcPipelineParameterBuffer>() as u32,
+                1,
+                params.as_mut_ptr() as *mut _,
+                &mut pipeline_buf,
+            )
+        };
+        if ret != VA_STATUS_SUCCESS as i32 {
+            bail!("Error creating VPP pipeline buffer: {ret:?}");
+        }
 
-    if ret != VA_STATUS_SUCCESS as i32 {
         unsafe {
-            vaDestroyContext(raw_display, vpp_context);
-            vaDestroyConfig(raw_display, vpp_config);
+            vaBeginPicture(self.display, context, dst_surface);
+            vaRenderPicture(self.display, context, &mut pipeline_buf, 1);
+            vaEndPicture(self.display, context);
+            vaSyncSurface(self.display, dst_surface);
+            vaDestroyBuffer(self.display, pipeline_buf);
         }
-        bail!("Error creating VPP pipeline buffer: {ret:?}");
-    }
 
-    unsafe {
-        vaBeginPicture(raw_display, vpp_context, dst_surface);
-        vaRenderPicture(raw_display, vpp_context, &mut pipeline_buf, 1);
-        vaEndPicture(raw_display, vpp_context);
-        vaSyncSurface(raw_display, dst_surface);
+        Ok(())
+    }
+}
 
-        vaDestroyBuffer(raw_display, pipeline_buf);
-        vaDestroyContext(raw_display, vpp_context);
-        vaDestroyConfig(raw_display, vpp_config);
-    };
+impl Drop for SurfaceCopier {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some((context, ..)) = self.context {
+                cros_codecs::libva::vaDestroyContext(self.display, context);
+            }
+            cros_codecs::libva::vaDestroyConfig(self.display, self.config);
+        }
+    }
+}
 
-    Ok(())
+/// Map [`ColorConfig`]'s matrix coefficients onto the VAAPI color standard
+/// the VPP pipeline expects for `input_color_standard`/`output_color_standard`.
+fn va_color_standard(color: ColorConfig) -> cros_codecs::libva::VAProcColorStandardType::Type {
+    use cros_codecs::libva::VAProcColorStandardType;
+    match color.matrix_coefficients {
+        1 => VAProcColorStandardType::VAProcColorStandardBT709,
+        5 | 6 => VAProcColorStandardType::VAProcColorStandardBT601,
+        9 => VAProcColorStandardType::VAProcColorStandardBT2020,
+        _ => VAProcColorStandardType::VAProcColorStandardNone,
+    }
 }
@@ -1,9 +1,17 @@
-use std::{borrow::Borrow, fs::File, io::Read, rc::Rc};
+use std::{
+    borrow::Borrow,
+    fs::File,
+    io::{BufReader, Read},
+    rc::Rc,
+};
 
 use anyhow::{bail, Result};
 use clap::Parser;
 use cros_codecs::{
-    backend::vaapi::surface_pool::VaSurfacePool,
+    backend::vaapi::{
+        encoder::VaapiBackend,
+        surface_pool::{PooledVaSurface, VaSurfacePool},
+    },
     codec::h264::parser::{Level, Profile},
     decoder::FramePool,
     encoder::{
@@ -15,10 +23,27 @@ use cros_codecs::{
     BlockingMode, FrameLayout, PlaneLayout, Resolution,
 };
 
-const WIDTH: u32 = 1280;
-const HEIGHT: u32 = 720;
-const FRAMERATE: u32 = 60;
-const FRAME_SIZE: usize = (WIDTH * HEIGHT * 3 / 2) as usize; // NV12 format
+mod decode;
+mod frame_hasher;
+mod h264_decode;
+mod h264_vui;
+mod mp4;
+mod quality;
+mod vaapi_scaler;
+mod y4m;
+
+use frame_hasher::FrameHasher;
+
+use mp4::{Mp4Muxer, OutputFormat};
+
+/// `.mp4` gets a single-moov MP4 container; anything else (including the
+/// previous default, `.h264`) keeps writing a raw Annex-B stream.
+fn output_format_for_path(path: &str) -> OutputFormat {
+    match std::path::Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("mp4") => OutputFormat::Mp4,
+        _ => OutputFormat::AnnexB,
+    }
+}
 
 fn parse_bitrate(s: &str) -> Result<u64, String> {
     let s = s.trim();
@@ -44,91 +69,331 @@ fn parse_bitrate(s: &str) -> Result<u64, String> {
 #[command(name = "encode-sample")]
 #[command(about = "Encode raw NV12 frames to H.264 using VAAPI")]
 struct Args {
-    /// Input raw NV12 file
+    /// Input file: headerless raw NV12, or Y4M if `--input-format y4m` (or
+    /// `auto` detects a `.y4m` extension). Ignored when `--transcode` is set.
     #[arg(long)]
-    input: String,
+    input: Option<String>,
 
-    /// Output H.264 file
+    /// Decode this existing Annex-B H.264 file with VAAPI and feed the
+    /// decoded pictures straight back into the encoder instead of reading
+    /// raw NV12/Y4M frames from `--input` -- e.g. to change bitrate or
+    /// rate-control mode on an existing clip. Resolution and frame count
+    /// come from the decoded stream, so `--input-width`/`--input-height`
+    /// and Y4M handling don't apply.
+    #[arg(long)]
+    transcode: Option<String>,
+
+    /// Output file. A `.mp4` extension writes a single-moov MP4 container
+    /// (SPS/PPS folded into an `avcC` sample entry, Annex-B start codes
+    /// converted to length-prefixed NAL units); anything else writes a raw
+    /// Annex-B elementary stream, as before.
     #[arg(long)]
     output: String,
 
-    /// Bitrate (e.g., 6M, 500K, 6000000)
+    /// Input width, required unless reading a Y4M stream (its `W` tag is
+    /// used instead)
+    #[arg(long)]
+    input_width: Option<u32>,
+
+    /// Input height, required unless reading a Y4M stream (its `H` tag is
+    /// used instead)
+    #[arg(long)]
+    input_height: Option<u32>,
+
+    /// Input container format
+    #[arg(long, value_enum, default_value = "auto")]
+    input_format: InputFormat,
+
+    /// Encoder framerate, overridden by a Y4M input stream's `F` tag
+    #[arg(long, default_value_t = 60)]
+    framerate: u32,
+
+    /// Bitrate (e.g., 6M, 500K, 6000000). Required unless `--quality` is set.
     #[arg(long, value_parser = parse_bitrate)]
-    bitrate: u64,
+    bitrate: Option<u64>,
 
-    /// Maximum bitrate (e.g., 8M, 1000K, 8000000)
+    /// Maximum bitrate (e.g., 8M, 1000K, 8000000). Defaults to `--bitrate`;
+    /// unused when `--quality` is set.
     #[arg(long, value_parser = parse_bitrate)]
-    maxrate: u64,
-    
-    /// Rate control mode: cbr, vbr, or cqp
+    maxrate: Option<u64>,
+
+    /// Rate control mode: cbr, vbr, or cqp. Unused when `--quality` is set.
     #[arg(long, default_value = "cbr")]
     rc_mode: String,
 
+    /// Single-knob quality, 0-100: maps onto a constant-bitrate `Tunings`
+    /// (QP band and bitrate both derived from this), overriding
+    /// `--bitrate`/`--maxrate`/`--rc-mode`. `0` falls back to the encoder's
+    /// own defaults. See [`quality::from_quality`].
+    #[arg(long)]
+    quality: Option<u8>,
+
     /// Maximum number of frames to process (optional, processes all frames if not specified)
     #[arg(long)]
     frames: Option<usize>,
+
+    /// Normalized L1 distance between consecutive frames' luma histograms
+    /// above which a scene cut forces a keyframe (see [`SceneChangeDetector`])
+    #[arg(long, default_value_t = 0.4)]
+    scene_threshold: f64,
+
+    /// Upload each frame to a VA surface, SHA-256 it with [`frame_hasher`],
+    /// and print the hex digest instead of encoding -- a way to check the
+    /// upload path is pixel-correct without an external tool. Implies
+    /// `--input` (`--transcode` isn't supported here).
+    #[arg(long)]
+    hash_frames: bool,
+
+    /// One hex digest per line, checked against `--hash-frames`' output in
+    /// order; any mismatch fails loudly. Ignored without `--hash-frames`.
+    #[arg(long)]
+    expected_digests: Option<String>,
+
+    /// After encoding, decode every produced frame back with VAAPI (see
+    /// [`h264_decode::H264Decoder`]) and compare it against the original
+    /// upload with [`decode::psnr`], so a round-trip quality regression
+    /// fails loudly instead of silently shipping. Not supported with
+    /// `--transcode` or `--hash-frames`.
+    #[arg(long)]
+    verify_roundtrip: bool,
+
+    /// PSNR (dB) below which `--verify-roundtrip` fails; ignored otherwise.
+    #[arg(long, default_value_t = 30.0)]
+    psnr_threshold: f64,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq)]
+enum InputFormat {
+    Auto,
+    Nv12,
+    Y4m,
+}
+
+/// Forces a keyframe on a hard scene cut instead of relying solely on the
+/// fixed-length `PredictionStructure::LowDelay` GOP, which otherwise lands
+/// cuts mid-GOP and wastes bits re-predicting from an unrelated reference.
+///
+/// Tracks a 256-bin histogram of the previously encoded frame's luma plane,
+/// averaged over 8x8 blocks for speed, and compares it against each new
+/// frame's histogram with the normalized L1 distance `d = Σ|h_cur - h_prev| /
+/// (2 * num_blocks)`. A keyframe is forced when `d` crosses `threshold` (and
+/// at least `MIN_KEYFRAME_INTERVAL` frames have passed since the last one),
+/// or unconditionally every `MAX_KEYFRAME_INTERVAL` frames to bound GOP
+/// length.
+struct SceneChangeDetector {
+    prev_histogram: Option<[u32; 256]>,
+    frames_since_keyframe: usize,
+}
+
+impl SceneChangeDetector {
+    /// Frames between forced keyframes, even under the scene-cut threshold,
+    /// so a noisy signal can't chain cuts back-to-back.
+    const MIN_KEYFRAME_INTERVAL: usize = 10;
+    /// Frames between forced keyframes regardless of scene cuts, bounding
+    /// worst-case GOP length the same way the fixed GOP did before.
+    const MAX_KEYFRAME_INTERVAL: usize = 30;
+    const BLOCK_SIZE: usize = 8;
+
+    fn new() -> Self {
+        Self {
+            prev_histogram: None,
+            frames_since_keyframe: 0,
+        }
+    }
+
+    /// `y_plane` is the tightly packed `width * height` luma plane of an
+    /// NV12 frame.
+    fn should_force_keyframe(&mut self, y_plane: &[u8], width: usize, height: usize, threshold: f64) -> bool {
+        let histogram = Self::block_averaged_histogram(y_plane, width, height);
+
+        let force = match self.prev_histogram {
+            None => true, // no prior frame to compare against
+            Some(prev) => {
+                if self.frames_since_keyframe >= Self::MAX_KEYFRAME_INTERVAL {
+                    true
+                } else if self.frames_since_keyframe >= Self::MIN_KEYFRAME_INTERVAL {
+                    Self::l1_distance(&histogram, &prev) > threshold
+                } else {
+                    false
+                }
+            }
+        };
+
+        self.prev_histogram = Some(histogram);
+        self.frames_since_keyframe = if force { 1 } else { self.frames_since_keyframe + 1 };
+        force
+    }
+
+    /// Histogram of `y_plane`, downscaled by averaging non-overlapping
+    /// `BLOCK_SIZE x BLOCK_SIZE` blocks first so the per-frame cost stays
+    /// proportional to block count rather than pixel count.
+    fn block_averaged_histogram(y_plane: &[u8], width: usize, height: usize) -> [u32; 256] {
+        let mut histogram = [0u32; 256];
+        let mut by = 0;
+        while by < height {
+            let block_height = Self::BLOCK_SIZE.min(height - by);
+            let mut bx = 0;
+            while bx < width {
+                let block_width = Self::BLOCK_SIZE.min(width - bx);
+                let mut sum = 0u32;
+                for row in 0..block_height {
+                    let row_start = (by + row) * width + bx;
+                    for col in 0..block_width {
+                        sum += y_plane[row_start + col] as u32;
+                    }
+                }
+                let average = sum / (block_width * block_height) as u32;
+                histogram[average as usize] += 1;
+                bx += Self::BLOCK_SIZE;
+            }
+            by += Self::BLOCK_SIZE;
+        }
+        histogram
+    }
+
+    /// `Σ|h_cur[i] - h_prev[i]| / (2 * num_blocks)`, normalized to `[0, 1]`
+    /// regardless of resolution.
+    fn l1_distance(current: &[u32; 256], previous: &[u32; 256]) -> f64 {
+        let num_blocks: u32 = current.iter().sum();
+        let l1_sum: i64 = current
+            .iter()
+            .zip(previous.iter())
+            .map(|(&a, &b)| (a as i64 - b as i64).abs())
+            .sum();
+        l1_sum as f64 / (2.0 * num_blocks as f64)
+    }
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
 
+    if let Some(transcode_input) = &args.transcode {
+        return run_transcode(&args, transcode_input);
+    }
+
+    let input_path = args
+        .input
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("--input is required unless --transcode is set"))?;
+
     println!("Starting H.264 encoding using VAAPI...");
-    println!("Input: {}", args.input);
+    println!("Input: {}", input_path);
     println!("Output: {}", args.output);
-    println!("Bitrate: {} bps ({:.1} Mbps)", args.bitrate, args.bitrate as f64 / 1_000_000.0);
+    if let Some(quality) = args.quality {
+        println!("Quality: {}", quality);
+    } else {
+        let bitrate = args.bitrate.ok_or_else(|| anyhow::anyhow!("--bitrate is required unless --quality is set"))?;
+        println!("Bitrate: {} bps ({:.1} Mbps)", bitrate, bitrate as f64 / 1_000_000.0);
+    }
 
-    // Open the raw NV12 file
-    let mut input_file = File::open(&args.input)?;
+    let is_y4m_input = match args.input_format {
+        InputFormat::Y4m => true,
+        InputFormat::Nv12 => false,
+        InputFormat::Auto => input_path.ends_with(".y4m"),
+    };
+
+    // Open the input, parsing the Y4M header up front when present so
+    // resolution and framerate travel with the pixels instead of having to
+    // be passed in on the command line.
+    let mut input_file = BufReader::new(File::open(input_path)?);
+    let (width, height, framerate) = if is_y4m_input {
+        let header = y4m::read_header(&mut input_file)?;
+        println!(
+            "Detected Y4M input: {}x{} @ {}:{} fps",
+            header.width, header.height, header.fps_num, header.fps_den
+        );
+        (header.width, header.height, header.framerate())
+    } else {
+        let width = args
+            .input_width
+            .ok_or_else(|| anyhow::anyhow!("--input-width is required for raw NV12 input"))?;
+        let height = args
+            .input_height
+            .ok_or_else(|| anyhow::anyhow!("--input-height is required for raw NV12 input"))?;
+        (width, height, args.framerate)
+    };
+    println!("Input resolution: {}x{}", width, height);
 
-    // Get file size and calculate total frames
-    let file_size = input_file.metadata()?.len() as usize;
-    let available_frames = file_size / FRAME_SIZE;
-    let total_frames = args.frames.unwrap_or(available_frames).min(available_frames);
-    println!("Input file size: {} bytes, estimated frames: {}, processing: {}", file_size, available_frames, total_frames);
+    let frame_size = (width * height * 3 / 2) as usize; // I420 and NV12 are both 12 bits/pixel
+
+    // Get file size and calculate total frames. A Y4M stream's file size
+    // includes header/FRAME-marker bytes on top of the pixel data, so there's
+    // no point estimating a frame count from it -- just run until EOF (or
+    // `--frames`, whichever comes first).
+    let total_frames = if is_y4m_input {
+        args.frames.unwrap_or(usize::MAX)
+    } else {
+        let file_size = input_file.get_ref().metadata()?.len() as usize;
+        let available_frames = file_size / frame_size;
+        let total_frames = args.frames.unwrap_or(available_frames).min(available_frames);
+        println!(
+            "Input file size: {} bytes, estimated frames: {}, processing: {}",
+            file_size, available_frames, total_frames
+        );
+        total_frames
+    };
+
+    if args.hash_frames {
+        return run_hash_frames(&args, &mut input_file, is_y4m_input, width, height, total_frames);
+    }
 
     // Initialize VAAPI display
     let Some(display) = cros_codecs::libva::Display::open() else {
         bail!("Failed to open VAAPI display");
     };
 
-    // Configure encoder
-    let config = EncoderConfig {
-        resolution: Resolution { width: WIDTH, height: HEIGHT },
-        profile: Profile::High,
-        level: Level::L4_1,
-        pred_structure: PredictionStructure::LowDelay { limit: 30 }, // Match FFmpeg keyframe interval
-        initial_tunings: Tunings {
-            rate_control: match args.rc_mode.as_str() {
-                "cbr" => cros_codecs::encoder::RateControl::ConstantBitrate(args.bitrate),
-                "vbr" => cros_codecs::encoder::RateControl::VariableBitrate {
-                    target_bitrate: args.bitrate,
-                    max_bitrate: args.maxrate,
+    // Configure encoder: either the single `--quality` knob, or the
+    // hand-picked bitrate/rate-control-mode combination.
+    let config = if let Some(quality) = args.quality {
+        quality::from_quality(
+            Resolution { width, height },
+            Profile::High,
+            Level::L4_1,
+            PredictionStructure::LowDelay { limit: 30 }, // Match FFmpeg keyframe interval
+            framerate,
+            quality::Quality(quality),
+        )
+    } else {
+        let bitrate = args.bitrate.ok_or_else(|| anyhow::anyhow!("--bitrate is required unless --quality is set"))?;
+        let maxrate = args.maxrate.unwrap_or(bitrate);
+        EncoderConfig {
+            resolution: Resolution { width, height },
+            profile: Profile::High,
+            level: Level::L4_1,
+            pred_structure: PredictionStructure::LowDelay { limit: 30 }, // Match FFmpeg keyframe interval
+            initial_tunings: Tunings {
+                rate_control: match args.rc_mode.as_str() {
+                    "cbr" => cros_codecs::encoder::RateControl::ConstantBitrate(bitrate),
+                    "vbr" => cros_codecs::encoder::RateControl::VariableBitrate {
+                        target_bitrate: bitrate,
+                        max_bitrate: maxrate,
+                    },
+                    "cqp" => cros_codecs::encoder::RateControl::ConstantQuality(23), // Default CQP value
+                    _ => {
+                        bail!("Invalid rate control mode: {}. Use cbr, vbr, or cqp", args.rc_mode);
+                    }
                 },
-                "cqp" => cros_codecs::encoder::RateControl::ConstantQuality(23), // Default CQP value
-                _ => {
-                    bail!("Invalid rate control mode: {}. Use cbr, vbr, or cqp", args.rc_mode);
-                }
+                framerate,
+                min_quality: 0,
+                max_quality: u32::MAX,
             },
-            framerate: FRAMERATE,
-            min_quality: 0,
-            max_quality: u32::MAX,
-        },
+        }
     };
 
     let fourcc = cros_codecs::Fourcc::from(b"NV12");
     let frame_layout = FrameLayout {
         format: (fourcc, 0),
-        size: Resolution { width: WIDTH, height: HEIGHT },
+        size: Resolution { width, height },
         planes: vec![
             PlaneLayout {
                 buffer_index: 0,
                 offset: 0,
-                stride: WIDTH as usize,
+                stride: width as usize,
             },
             PlaneLayout {
                 buffer_index: 0,
-                offset: (WIDTH * HEIGHT) as usize,
-                stride: WIDTH as usize,
+                offset: (width * height) as usize,
+                stride: width as usize,
             },
         ],
     };
@@ -138,7 +403,7 @@ fn main() -> Result<()> {
         display.clone(),
         config,
         fourcc,
-        Resolution { width: WIDTH, height: HEIGHT },
+        Resolution { width, height },
         false, // low_power
         BlockingMode::NonBlocking,
     ).map_err(|e| anyhow::anyhow!("Failed to create encoder: {:?}", e))?;
@@ -148,23 +413,58 @@ fn main() -> Result<()> {
         display.clone(),
         VA_RT_FORMAT_YUV420,
         Some(UsageHint::USAGE_HINT_ENCODER),
-        Resolution { width: WIDTH, height: HEIGHT },
+        Resolution { width, height },
     );
     pool.add_frames(vec![(); 16])?;
 
-    // Create output file
-    let mut output_file = File::create(&args.output)?;
+    // Create output file, either a raw Annex-B stream or an MP4 container,
+    // depending on `args.output`'s extension.
+    let output_format = output_format_for_path(&args.output);
+    let mut output_file = match output_format {
+        OutputFormat::AnnexB => Some(File::create(&args.output)?),
+        OutputFormat::Mp4 | OutputFormat::Fmp4 => None,
+    };
+    let mut muxer = match output_format {
+        OutputFormat::AnnexB => None,
+        OutputFormat::Mp4 | OutputFormat::Fmp4 => Some(Mp4Muxer::new(
+            &args.output,
+            output_format,
+            framerate,
+            width,
+            height,
+            None,
+        )?),
+    };
     let mut bitstream_data = Vec::new();
 
-    println!("Encoding {} frames...", total_frames);
+    if total_frames == usize::MAX {
+        println!("Encoding frames...");
+    } else {
+        println!("Encoding {} frames...", total_frames);
+    }
+
+    // `input_buffer` holds whatever the file's native layout is (NV12 as-is,
+    // or Y4M's planar I420); `frame_buffer` always ends up NV12, which is
+    // what `upload_nv12_frame` expects.
+    let mut input_buffer = vec![0u8; frame_size];
+    let mut frame_buffer = vec![0u8; frame_size];
+    let mut frames_done = 0;
+    let mut scene_detector = SceneChangeDetector::new();
 
-    // Frame buffer for reading one frame at a time
-    let mut frame_buffer = vec![0u8; FRAME_SIZE];
+    // Only kept around for `--verify-roundtrip`; empty (no extra memory)
+    // otherwise.
+    let mut uploaded_frames: Vec<Vec<u8>> = Vec::new();
+    let mut verify_bitstream: Option<Vec<u8>> = args.verify_roundtrip.then(Vec::new);
 
     // Process each frame
     for frame_idx in 0..total_frames {
+        if is_y4m_input && !y4m::read_frame_marker(&mut input_file)? {
+            println!("Reached end of Y4M stream at frame {}", frame_idx);
+            break;
+        }
+
         // Read one frame from file
-        match input_file.read_exact(&mut frame_buffer) {
+        match input_file.read_exact(&mut input_buffer) {
             Ok(_) => {},
             Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
                 println!("Reached end of file at frame {}", frame_idx);
@@ -172,6 +472,11 @@ fn main() -> Result<()> {
             },
             Err(e) => return Err(e.into()),
         }
+        if is_y4m_input {
+            y4m::i420_to_nv12(&input_buffer, width as usize, height as usize, &mut frame_buffer);
+        } else {
+            frame_buffer.copy_from_slice(&input_buffer);
+        }
 
         // Get surface from pool
         let pooled_surface = pool.get_surface()
@@ -179,13 +484,26 @@ fn main() -> Result<()> {
 
         // Upload frame data to surface
         let surface: &Surface<()> = pooled_surface.borrow();
-        upload_nv12_frame(&display, surface, &frame_buffer)?;
+        upload_nv12_frame(&display, surface, &frame_buffer, width, height)?;
 
-        // Create frame metadata
+        if verify_bitstream.is_some() {
+            uploaded_frames.push(frame_buffer.clone());
+        }
+
+        // Create frame metadata. The first frame always forces a keyframe
+        // (no prior histogram to compare against); after that, a scene cut
+        // or the max-GOP-length backstop can force one too.
+        let y_plane = &frame_buffer[..(width * height) as usize];
+        let force_keyframe = scene_detector.should_force_keyframe(
+            y_plane,
+            width as usize,
+            height as usize,
+            args.scene_threshold,
+        );
         let meta = FrameMetadata {
             timestamp: frame_idx as u64,
             layout: frame_layout.clone(),
-            force_keyframe: frame_idx == 0, // Force keyframe for first frame
+            force_keyframe,
         };
 
         // Encode frame
@@ -195,11 +513,23 @@ fn main() -> Result<()> {
         // Poll for encoded data
         while let Some(coded_buffer) = encoder.poll()
             .map_err(|e| anyhow::anyhow!("Failed to poll encoder: {:?}", e))? {
-            bitstream_data.extend_from_slice(&coded_buffer.bitstream);
+            if let Some(verify_bitstream) = &mut verify_bitstream {
+                verify_bitstream.extend_from_slice(&coded_buffer.bitstream);
+            }
+            if let Some(muxer) = &mut muxer {
+                muxer.push_frame(&coded_buffer.bitstream, coded_buffer.metadata.timestamp)?;
+            } else {
+                bitstream_data.extend_from_slice(&coded_buffer.bitstream);
+            }
         }
 
+        frames_done = frame_idx + 1;
         if frame_idx % 30 == 0 {
-            println!("Encoded frame {}/{}", frame_idx + 1, total_frames);
+            if total_frames == usize::MAX {
+                println!("Encoded frame {}", frame_idx + 1);
+            } else {
+                println!("Encoded frame {}/{}", frame_idx + 1, total_frames);
+            }
         }
     }
 
@@ -210,21 +540,446 @@ fn main() -> Result<()> {
     // Get remaining encoded data
     while let Some(coded_buffer) = encoder.poll()
         .map_err(|e| anyhow::anyhow!("Failed to poll encoder: {:?}", e))? {
-        bitstream_data.extend_from_slice(&coded_buffer.bitstream);
+        if let Some(verify_bitstream) = &mut verify_bitstream {
+            verify_bitstream.extend_from_slice(&coded_buffer.bitstream);
+        }
+        if let Some(muxer) = &mut muxer {
+            muxer.push_frame(&coded_buffer.bitstream, coded_buffer.metadata.timestamp)?;
+        } else {
+            bitstream_data.extend_from_slice(&coded_buffer.bitstream);
+        }
     }
 
-    // Write to output file
+    // Finish writing the output: flush the trailer for an MP4 container, or
+    // the buffered bitstream for a raw Annex-B stream.
     use std::io::Write;
-    output_file.write_all(&bitstream_data)?;
-    output_file.flush()?;
+    let output_size = if let Some(muxer) = muxer {
+        muxer.finish()?;
+        std::fs::metadata(&args.output)?.len() as usize
+    } else {
+        let output_file = output_file.as_mut().expect("AnnexB output always has a file");
+        output_file.write_all(&bitstream_data)?;
+        output_file.flush()?;
+        bitstream_data.len()
+    };
 
     println!("Encoding complete! Output written to {}", args.output);
-    println!("Encoded {} frames, output size: {} bytes", total_frames, bitstream_data.len());
+
+    if let Some(verify_bitstream) = verify_bitstream {
+        verify_roundtrip(&display, &verify_bitstream, &uploaded_frames, width, height, args.psnr_threshold)?;
+    }
+    println!("Encoded {} frames, output size: {} bytes", frames_done, output_size);
 
     Ok(())
 }
 
-fn map_surface_nv12<'a>(
+/// Upload each input frame to a VA surface and SHA-256 it with
+/// [`FrameHasher`] instead of encoding, printing one hex digest per frame --
+/// a way to check the upload path (stride handling, I420->NV12 conversion)
+/// is pixel-correct without an external tool or a reference decoder.
+/// If `args.expected_digests` names a file, each digest is checked in order
+/// against that file's lines and the first mismatch fails loudly.
+fn run_hash_frames(
+    args: &Args,
+    input_file: &mut BufReader<File>,
+    is_y4m_input: bool,
+    width: u32,
+    height: u32,
+    total_frames: usize,
+) -> Result<()> {
+    println!("Hashing frames (no encoding)...");
+
+    let expected_digests = args
+        .expected_digests
+        .as_ref()
+        .map(std::fs::read_to_string)
+        .transpose()?
+        .map(|contents| contents.lines().map(String::from).collect::<Vec<_>>());
+
+    let Some(display) = cros_codecs::libva::Display::open() else {
+        bail!("Failed to open VAAPI display");
+    };
+
+    let mut pool = VaSurfacePool::<()>::new(
+        display.clone(),
+        VA_RT_FORMAT_YUV420,
+        Some(UsageHint::USAGE_HINT_VPP_WRITE | UsageHint::USAGE_HINT_VPP_READ),
+        Resolution { width, height },
+    );
+    pool.add_frames(vec![(); 1])?;
+
+    let frame_size = (width * height * 3 / 2) as usize;
+    let mut input_buffer = vec![0u8; frame_size];
+    let mut frame_buffer = vec![0u8; frame_size];
+    let mut frames_done = 0;
+
+    for frame_idx in 0..total_frames {
+        if is_y4m_input && !y4m::read_frame_marker(input_file)? {
+            println!("Reached end of Y4M stream at frame {}", frame_idx);
+            break;
+        }
+
+        match input_file.read_exact(&mut input_buffer) {
+            Ok(_) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                println!("Reached end of file at frame {}", frame_idx);
+                break;
+            },
+            Err(e) => return Err(e.into()),
+        }
+        if is_y4m_input {
+            y4m::i420_to_nv12(&input_buffer, width as usize, height as usize, &mut frame_buffer);
+        } else {
+            frame_buffer.copy_from_slice(&input_buffer);
+        }
+
+        let pooled_surface = pool.get_surface()
+            .ok_or_else(|| anyhow::anyhow!("Failed to get surface from pool"))?;
+        let surface: &Surface<()> = pooled_surface.borrow();
+        upload_nv12_frame(&display, surface, &frame_buffer, width, height)?;
+
+        let image = map_surface_nv12(&display, surface);
+        let digest = FrameHasher::hash_nv12_image(&image, width, height);
+        println!("frame {}: {}", frame_idx, digest);
+
+        if let Some(expected_digests) = &expected_digests {
+            match expected_digests.get(frame_idx) {
+                Some(expected) if expected == &digest => {},
+                Some(expected) => bail!(
+                    "frame {} digest mismatch: got {}, expected {}",
+                    frame_idx,
+                    digest,
+                    expected
+                ),
+                None => bail!("no expected digest for frame {} in {:?}", frame_idx, args.expected_digests),
+            }
+        }
+
+        frames_done = frame_idx + 1;
+    }
+
+    println!("Hashed {} frames.", frames_done);
+
+    Ok(())
+}
+
+/// Decode `bitstream` (the Annex-B elementary stream just produced by
+/// `main()`'s encode loop) with [`h264_decode::H264Decoder`] and compare
+/// each decoded picture against the frame that was uploaded at the matching
+/// index in `uploaded_frames`, via [`decode::psnr`]. Bails on the first
+/// frame whose PSNR falls below `psnr_threshold`.
+fn verify_roundtrip(
+    display: &Rc<cros_codecs::libva::Display>,
+    bitstream: &[u8],
+    uploaded_frames: &[Vec<u8>],
+    width: u32,
+    height: u32,
+    psnr_threshold: f64,
+) -> Result<()> {
+    println!("Verifying round trip (decode + PSNR, threshold {} dB)...", psnr_threshold);
+
+    let nalus = h264_decode::split_annex_b_nalus(bitstream);
+    if let Some(sps_nal) = nalus.iter().find(|nal| !nal.is_empty() && nal[0] & 0x1f == 7) {
+        let sps = decode::parse_sps(sps_nal)?;
+        if sps.width != width || sps.height != height {
+            bail!(
+                "SPS resolution {}x{} doesn't match the encoded {}x{}",
+                sps.width, sps.height, width, height
+            );
+        }
+        println!("SPS: profile_idc={} level_idc={} {}x{}", sps.profile_idc, sps.level_idc, sps.width, sps.height);
+    }
+
+    let mut decoder = h264_decode::H264Decoder::new(display.clone())?;
+    let mut decoded = Vec::new();
+    for (idx, nalu) in nalus.iter().enumerate() {
+        decoder.decode_nalu(idx as u64, nalu)?;
+        decoded.extend(decoder.poll()?);
+    }
+    decoded.extend(decoder.drain()?);
+
+    if decoded.len() != uploaded_frames.len() {
+        bail!(
+            "decoded {} pictures but uploaded {} frames -- round-trip verification needs a 1:1 match",
+            decoded.len(), uploaded_frames.len()
+        );
+    }
+
+    let mut min_psnr = f64::INFINITY;
+    for (idx, (decoded_surface, original)) in decoded.iter().zip(uploaded_frames).enumerate() {
+        let surface = h264_decode::borrow_surface(decoded_surface);
+        let roundtripped = decode::download_nv12_surface(display, surface, width, height);
+        let psnr = decode::psnr(original, &roundtripped);
+        println!("frame {}: PSNR = {:.2} dB", idx, psnr);
+        min_psnr = min_psnr.min(psnr);
+        if psnr < psnr_threshold {
+            bail!("frame {} PSNR {:.2} dB is below the {} dB threshold", idx, psnr, psnr_threshold);
+        }
+    }
+
+    println!("Round trip verified: {} frames, min PSNR = {:.2} dB", decoded.len(), min_psnr);
+    Ok(())
+}
+
+/// Decode `input_path` (an existing Annex-B H.264 elementary stream) with
+/// VAAPI and feed the decoded pictures straight into a fresh encoder, so the
+/// repackaged output gets `args`' bitrate/rate-control instead of whatever
+/// the input was encoded with. Decoded surfaces are copied into the
+/// encoder's own pool with [`vaapi_scaler::VaapiScaler`] -- a VPP copy
+/// rather than a CPU download/re-upload -- the same surface-to-surface path
+/// `scale_sample`'s H264 transcode mode uses when it also needs to resize.
+fn run_transcode(args: &Args, input_path: &str) -> Result<()> {
+    println!("Starting H.264 transcode (decode + re-encode) using VAAPI...");
+    println!("Input: {}", input_path);
+    println!("Output: {}", args.output);
+    if let Some(quality) = args.quality {
+        println!("Quality: {}", quality);
+    } else {
+        let bitrate = args.bitrate.ok_or_else(|| anyhow::anyhow!("--bitrate is required unless --quality is set"))?;
+        println!("Bitrate: {} bps ({:.1} Mbps)", bitrate, bitrate as f64 / 1_000_000.0);
+    }
+
+    let Some(display) = cros_codecs::libva::Display::open() else {
+        bail!("Failed to open VAAPI display");
+    };
+
+    let mut decoder = h264_decode::H264Decoder::new(display.clone())?;
+    let scaler = vaapi_scaler::VaapiScaler::new(display.clone())?;
+
+    let input_data = std::fs::read(input_path)?;
+    let nalus = h264_decode::split_annex_b_nalus(&input_data);
+
+    // The encoder, its surface pool, and the frame layout all need the
+    // decoded resolution, which we only learn once the first picture comes
+    // out of the decoder -- so they're built lazily on the first decoded
+    // surface, the same pattern the recorder's `main.rs` uses for its
+    // `Encoder`.
+    let mut encoder: Option<
+        StatelessEncoder<H264, PooledVaSurface<()>, VaapiBackend<(), PooledVaSurface<()>>>,
+    > = None;
+    let mut dst_pool: Option<VaSurfacePool<()>> = None;
+    let mut frame_layout: Option<FrameLayout> = None;
+
+    let output_format = output_format_for_path(&args.output);
+    let mut output_file = match output_format {
+        OutputFormat::AnnexB => Some(File::create(&args.output)?),
+        OutputFormat::Mp4 | OutputFormat::Fmp4 => None,
+    };
+    let mut muxer: Option<Mp4Muxer> = None;
+    let mut bitstream_data = Vec::new();
+
+    // Built once and moved into `EncoderConfig` on the first decoded
+    // surface; an `Option` (rather than relying on `RateControl: Clone`)
+    // keeps that one-time move out of the loop's borrow checking. Only
+    // populated in the hand-picked-bitrate path -- the `--quality` path
+    // derives its `RateControl` from the decoded resolution instead, which
+    // isn't known until the first surface comes out of the decoder.
+    let mut rate_control = match args.quality {
+        Some(_) => None,
+        None => {
+            let bitrate = args.bitrate.ok_or_else(|| anyhow::anyhow!("--bitrate is required unless --quality is set"))?;
+            let maxrate = args.maxrate.unwrap_or(bitrate);
+            Some(match args.rc_mode.as_str() {
+                "cbr" => cros_codecs::encoder::RateControl::ConstantBitrate(bitrate),
+                "vbr" => cros_codecs::encoder::RateControl::VariableBitrate {
+                    target_bitrate: bitrate,
+                    max_bitrate: maxrate,
+                },
+                "cqp" => cros_codecs::encoder::RateControl::ConstantQuality(23),
+                _ => bail!("Invalid rate control mode: {}. Use cbr, vbr, or cqp", args.rc_mode),
+            })
+        }
+    };
+
+    let mut frame_idx = 0usize;
+    // True once `--frames` has been hit; in that case we deliberately don't
+    // drain the decoder afterward, since the user asked for an exact frame
+    // count, not "however many more the decoder happened to be holding back".
+    let mut truncated = false;
+
+    // Shared by the main decode loop below and the decoder-drain pass after
+    // it, since a picture coming out of `decoder.drain()` at end-of-stream
+    // (reorder/reference delay) needs the exact same scale+encode+mux
+    // handling as one coming out of `decoder.poll()` mid-stream. Returns
+    // whether `--frames` has now been reached.
+    let mut process_decoded_surface = |decoded_surface: PooledVaSurface<()>| -> Result<bool> {
+        let src_surface: &Surface<()> = h264_decode::borrow_surface(&decoded_surface);
+        let resolution = src_surface.size();
+
+        if encoder.is_none() {
+            println!("Detected resolution: {}x{}", resolution.width, resolution.height);
+            let fourcc = cros_codecs::Fourcc::from(b"NV12");
+            let config = if let Some(quality) = args.quality {
+                quality::from_quality(
+                    resolution,
+                    Profile::High,
+                    Level::L4_1,
+                    PredictionStructure::LowDelay { limit: 30 },
+                    args.framerate,
+                    quality::Quality(quality),
+                )
+            } else {
+                EncoderConfig {
+                    resolution,
+                    profile: Profile::High,
+                    level: Level::L4_1,
+                    pred_structure: PredictionStructure::LowDelay { limit: 30 },
+                    initial_tunings: Tunings {
+                        rate_control: rate_control.take().unwrap(),
+                        framerate: args.framerate,
+                        min_quality: 0,
+                        max_quality: u32::MAX,
+                    },
+                }
+            };
+            encoder = Some(
+                StatelessEncoder::<H264, _, _>::new_native_vaapi(
+                    display.clone(),
+                    config,
+                    fourcc,
+                    resolution,
+                    false, // low_power
+                    BlockingMode::NonBlocking,
+                )
+                .map_err(|e| anyhow::anyhow!("Failed to create encoder: {:?}", e))?,
+            );
+
+            let mut pool = VaSurfacePool::<()>::new(
+                display.clone(),
+                VA_RT_FORMAT_YUV420,
+                Some(UsageHint::USAGE_HINT_ENCODER | UsageHint::USAGE_HINT_VPP_WRITE),
+                resolution,
+            );
+            pool.add_frames(vec![(); 16])?;
+            dst_pool = Some(pool);
+
+            frame_layout = Some(FrameLayout {
+                format: (fourcc, 0),
+                size: resolution,
+                planes: vec![
+                    PlaneLayout {
+                        buffer_index: 0,
+                        offset: 0,
+                        stride: resolution.width as usize,
+                    },
+                    PlaneLayout {
+                        buffer_index: 0,
+                        offset: (resolution.width * resolution.height) as usize,
+                        stride: resolution.width as usize,
+                    },
+                ],
+            });
+
+            muxer = match output_format {
+                OutputFormat::AnnexB => None,
+                OutputFormat::Mp4 | OutputFormat::Fmp4 => Some(Mp4Muxer::new(
+                    &args.output,
+                    output_format,
+                    args.framerate,
+                    resolution.width,
+                    resolution.height,
+                    None,
+                )?),
+            };
+        }
+
+        let dst_pooled_surface = dst_pool
+            .as_mut()
+            .unwrap()
+            .get_surface()
+            .ok_or_else(|| anyhow::anyhow!("Failed to get destination surface from pool"))?;
+        let dst_surface: &Surface<()> = dst_pooled_surface.borrow();
+        scaler.scale(src_surface, dst_surface)?;
+
+        let meta = FrameMetadata {
+            timestamp: frame_idx as u64,
+            layout: frame_layout.clone().unwrap(),
+            force_keyframe: frame_idx == 0,
+        };
+        encoder
+            .as_mut()
+            .unwrap()
+            .encode(meta, dst_pooled_surface)
+            .map_err(|e| anyhow::anyhow!("Failed to encode frame: {:?}", e))?;
+
+        while let Some(coded_buffer) = encoder
+            .as_mut()
+            .unwrap()
+            .poll()
+            .map_err(|e| anyhow::anyhow!("Failed to poll encoder: {:?}", e))?
+        {
+            if let Some(muxer) = &mut muxer {
+                muxer.push_frame(&coded_buffer.bitstream, coded_buffer.metadata.timestamp)?;
+            } else {
+                bitstream_data.extend_from_slice(&coded_buffer.bitstream);
+            }
+        }
+
+        frame_idx += 1;
+        if frame_idx % 30 == 0 {
+            println!("Transcoded frame {}", frame_idx);
+        }
+        Ok(args.frames.is_some_and(|max| frame_idx >= max))
+    };
+
+    'decode: for (nalu_idx, nalu) in nalus.iter().enumerate() {
+        decoder.decode_nalu(nalu_idx as u64, nalu)?;
+        for decoded_surface in decoder.poll()? {
+            if process_decoded_surface(decoded_surface)? {
+                truncated = true;
+                break 'decode;
+            }
+        }
+    }
+
+    // Flush whatever the decoder was still holding back (reorder/reference
+    // delay) at end-of-stream, so `--transcode` doesn't silently drop the
+    // last few frames -- unless `--frames` already cut the output short on
+    // purpose, in which case there's nothing more to add.
+    if !truncated {
+        for decoded_surface in decoder.drain()? {
+            if process_decoded_surface(decoded_surface)? {
+                break;
+            }
+        }
+    }
+    drop(process_decoded_surface);
+
+    let Some(mut encoder) = encoder else {
+        bail!("No decodable H.264 pictures found in {}", input_path);
+    };
+    encoder
+        .drain()
+        .map_err(|e| anyhow::anyhow!("Failed to drain encoder: {:?}", e))?;
+    while let Some(coded_buffer) = encoder
+        .poll()
+        .map_err(|e| anyhow::anyhow!("Failed to poll encoder: {:?}", e))?
+    {
+        if let Some(muxer) = &mut muxer {
+            muxer.push_frame(&coded_buffer.bitstream, coded_buffer.metadata.timestamp)?;
+        } else {
+            bitstream_data.extend_from_slice(&coded_buffer.bitstream);
+        }
+    }
+
+    use std::io::Write;
+    let output_size = if let Some(muxer) = muxer {
+        muxer.finish()?;
+        std::fs::metadata(&args.output)?.len() as usize
+    } else {
+        let output_file = output_file.as_mut().expect("AnnexB output always has a file");
+        output_file.write_all(&bitstream_data)?;
+        output_file.flush()?;
+        bitstream_data.len()
+    };
+
+    println!("Transcode complete! Output written to {}", args.output);
+    println!("Transcoded {} frames, output size: {} bytes", frame_idx, output_size);
+
+    Ok(())
+}
+
+pub(crate) fn map_surface_nv12<'a>(
     display: &cros_codecs::libva::Display,
     surface: &'a Surface<()>,
 ) -> cros_codecs::libva::Image<'a> {
@@ -233,12 +988,18 @@ fn map_surface_nv12<'a>(
     cros_codecs::libva::Image::create_from(surface, image_fmt, surface.size(), surface.size()).unwrap()
 }
 
-fn upload_nv12_frame(display: &cros_codecs::libva::Display, surface: &Surface<()>, frame_data: &[u8]) -> Result<()> {
+fn upload_nv12_frame(
+    display: &cros_codecs::libva::Display,
+    surface: &Surface<()>,
+    frame_data: &[u8],
+    width: u32,
+    height: u32,
+) -> Result<()> {
     let mut image = map_surface_nv12(display, surface);
     let va_image = *image.image();
     let dest = image.as_mut();
-    let width = WIDTH as usize;
-    let height = HEIGHT as usize;
+    let width = width as usize;
+    let height = height as usize;
 
     // Copy Y plane
     let y_plane_size = width * height;
@@ -1,105 +1,170 @@
 use std::ptr;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
-/// A lock-free frame buffer that allows one writer and one reader
-/// to operate concurrently without blocking each other.
+/// What [`FrameBuffer::write`] does when the ring is already full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Evict the oldest queued frame to make room for the new one. Matches
+    /// the old single-slot `FrameBuffer`'s behavior: the writer never blocks
+    /// or fails, at the cost of silently dropping frames the reader hasn't
+    /// gotten to yet.
+    OverwriteOldest,
+    /// Reject the new frame and hand it back to the caller, so a capture
+    /// pool can recycle its surface instead of it being dropped.
+    ReturnToCaller,
+}
+
+/// A bounded lock-free single-producer/single-consumer ring buffer of
+/// `Arc<T>` frames, generalizing the old single-slot double-buffer so a
+/// reader that falls behind doesn't silently lose every frame but the most
+/// recent one.
 ///
-/// Uses double-buffering with atomic state management.
+/// `head`/`tail` are monotonically increasing counters (never wrapped
+/// mod `capacity` themselves, only their slot index is) so `len()` is a
+/// plain subtraction. `write` publishes into the `tail` slot and advances
+/// `tail`; when full, [`OverflowPolicy`] decides whether to evict the
+/// `head` slot or hand the frame back. `read` pops the `head` slot.
 pub struct FrameBuffer<T> {
-    // Two frame slots
-    frames: [AtomicPtr<T>; 2],
-
-    // Which buffer is currently being read from (0 or 1)
-    // The writer always writes to the opposite buffer
-    reading_buffer: AtomicBool,
+    slots: Box<[Slot<T>]>,
+    capacity: usize,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    policy: OverflowPolicy,
 }
 
-// Wrapper to make Arc<T> work with AtomicPtr
-struct AtomicPtr<T> {
+/// One ring slot: an `Arc<T>` smuggled through a raw pointer so it can live
+/// behind an atomic, the same trick the old double-buffer's `AtomicPtr`
+/// wrapper used.
+struct Slot<T> {
     ptr: std::sync::atomic::AtomicPtr<T>,
 }
 
-impl<T> AtomicPtr<T> {
+impl<T> Slot<T> {
     fn new() -> Self {
         Self {
             ptr: std::sync::atomic::AtomicPtr::new(ptr::null_mut()),
         }
     }
 
-    fn swap(&self, frame: Option<Arc<T>>, ordering: Ordering) -> Option<Arc<T>> {
-        let new_ptr = match frame {
-            Some(arc) => Arc::into_raw(arc) as *mut T,
-            None => ptr::null_mut(),
-        };
-
+    fn store(&self, frame: Arc<T>, ordering: Ordering) {
+        let new_ptr = Arc::into_raw(frame) as *mut T;
         let old_ptr = self.ptr.swap(new_ptr, ordering);
-
-        if old_ptr.is_null() {
-            None
-        } else {
-            // SAFETY: We own this pointer from a previous Arc::into_raw
-            Some(unsafe { Arc::from_raw(old_ptr) })
-        }
+        debug_assert!(old_ptr.is_null(), "overwrote a slot that wasn't empty");
     }
 
-    fn load(&self, ordering: Ordering) -> Option<Arc<T>> {
-        let ptr = self.ptr.load(ordering);
+    fn take(&self, ordering: Ordering) -> Option<Arc<T>> {
+        let ptr = self.ptr.swap(ptr::null_mut(), ordering);
         if ptr.is_null() {
             None
         } else {
-            // SAFETY: We're incrementing the Arc's reference count and we know this pointer is valid
-            unsafe {
-                Arc::increment_strong_count(ptr);
-                Some(Arc::from_raw(ptr))
-            }
+            // SAFETY: we own this pointer from a previous `Arc::into_raw`.
+            Some(unsafe { Arc::from_raw(ptr) })
         }
     }
 }
 
-impl<T> Drop for AtomicPtr<T> {
+impl<T> Drop for Slot<T> {
     fn drop(&mut self) {
-        let ptr = self.ptr.load(Ordering::Relaxed);
-        if !ptr.is_null() {
-            // SAFETY: We own this pointer
-            unsafe {
-                Arc::from_raw(ptr);
-            }
-        }
+        self.take(Ordering::Relaxed);
     }
 }
 
 impl<T> FrameBuffer<T> {
-    pub fn new() -> Self {
+    /// A ring of `capacity` slots with the old double-buffer's overwrite
+    /// semantics: a full ring evicts its oldest frame rather than rejecting
+    /// the new one. `capacity` must be at least 1.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_policy(capacity, OverflowPolicy::OverwriteOldest)
+    }
+
+    /// Like [`FrameBuffer::new`], with an explicit [`OverflowPolicy`].
+    pub fn with_policy(capacity: usize, policy: OverflowPolicy) -> Self {
+        assert!(capacity > 0, "FrameBuffer capacity must be at least 1");
         Self {
-            frames: [AtomicPtr::new(), AtomicPtr::new()],
-            reading_buffer: AtomicBool::new(false), // Start with buffer 0 for reading
+            slots: (0..capacity).map(|_| Slot::new()).collect(),
+            capacity,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            policy,
         }
     }
 
-    /// Write a new frame. Non-blocking operation.
-    pub fn write(&self, frame: Arc<T>) {
-        // Determine which buffer to write to (opposite of reading buffer)
-        let reading = self.reading_buffer.load(Ordering::Acquire);
-        let writing = !reading;
-        let write_idx = if writing { 1 } else { 0 };
+    /// Publish a new frame. Non-blocking. Returns `Some(frame)` (the same
+    /// frame handed in) if [`OverflowPolicy::ReturnToCaller`] rejected it
+    /// because the ring was full; `None` otherwise.
+    pub fn write(&self, frame: Arc<T>) -> Option<Arc<T>> {
+        loop {
+            let tail = self.tail.load(Ordering::Relaxed);
+            let head = self.head.load(Ordering::Acquire);
 
-        // Store the new frame in the write buffer
-        self.frames[write_idx].swap(Some(frame), Ordering::Release);
+            if tail.wrapping_sub(head) >= self.capacity {
+                match self.policy {
+                    OverflowPolicy::ReturnToCaller => return Some(frame),
+                    OverflowPolicy::OverwriteOldest => {
+                        // Make room by dropping the oldest queued frame and
+                        // advancing `head` past it. If the reader beat us to
+                        // it, `head` already moved on; re-check from the top.
+                        self.slots[head % self.capacity].take(Ordering::Acquire);
+                        if self
+                            .head
+                            .compare_exchange(head, head + 1, Ordering::AcqRel, Ordering::Relaxed)
+                            .is_err()
+                        {
+                            continue;
+                        }
+                    }
+                }
+            }
 
-        // Swap buffers by flipping the reading buffer flag
-        self.reading_buffer.store(writing, Ordering::Release);
+            self.slots[tail % self.capacity].store(frame, Ordering::Release);
+            self.tail.store(tail + 1, Ordering::Release);
+            return None;
+        }
     }
 
-    /// Read the latest complete frame. Non-blocking operation.
-    /// Returns None if no frame has been written yet.
-    /// Returns the same frame multiple times if no new frame is available.
+    /// Pop the oldest queued frame. Non-blocking. Returns `None` if the ring
+    /// is empty.
     pub fn read(&self) -> Option<Arc<T>> {
-        // Read from the current reading buffer
-        let reading = self.reading_buffer.load(Ordering::Acquire);
-        let read_idx = if reading { 1 } else { 0 };
+        loop {
+            let head = self.head.load(Ordering::Relaxed);
+            let tail = self.tail.load(Ordering::Acquire);
+            if head == tail {
+                return None;
+            }
 
-        self.frames[read_idx].load(Ordering::Acquire)
+            let Some(frame) = self.slots[head % self.capacity].take(Ordering::Acquire) else {
+                // A concurrent `OverwriteOldest` write already evicted this
+                // slot; it owns advancing `head` past it, so just retry.
+                continue;
+            };
+
+            if self
+                .head
+                .compare_exchange(head, head + 1, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+            {
+                return Some(frame);
+            }
+            // Lost the race to a concurrent eviction of this same slot;
+            // the frame we just took is still ours to return.
+            return Some(frame);
+        }
+    }
+
+    /// Number of frames currently queued.
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head).min(self.capacity)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
     }
 }
 
@@ -120,9 +185,8 @@ mod tests {
 
     #[test]
     fn test_basic_write_read() {
-        let buffer = Arc::new(FrameBuffer::new());
+        let buffer = Arc::new(FrameBuffer::new(4));
 
-        // Write a frame
         let frame1 = Arc::new(GpuFrame {
             data: vec![1, 2, 3],
             width: 1920,
@@ -130,20 +194,81 @@ mod tests {
             timestamp: 1,
         });
 
-        buffer.write(frame1.clone());
+        assert!(buffer.write(frame1.clone()).is_none());
 
-        // Read should return the frame
         let read_frame = buffer.read().unwrap();
-        // assert_eq!(read_frame.timestamp, 1);
+        assert_eq!(read_frame.timestamp, 1);
+        assert!(buffer.read().is_none(), "ring should be empty after the one frame is popped");
+    }
+
+    #[test]
+    fn test_fifo_order_within_capacity() {
+        let buffer = FrameBuffer::new(4);
+        for i in 0..4 {
+            let frame = Arc::new(GpuFrame {
+                data: vec![],
+                width: 0,
+                height: 0,
+                timestamp: i,
+            });
+            assert!(buffer.write(frame).is_none());
+        }
+        assert!(buffer.is_full());
+
+        for i in 0..4 {
+            assert_eq!(buffer.read().unwrap().timestamp, i);
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_overwrite_oldest_drops_oldest_frame() {
+        let buffer = FrameBuffer::new(2);
+        for i in 0..3 {
+            let frame = Arc::new(GpuFrame {
+                data: vec![],
+                width: 0,
+                height: 0,
+                timestamp: i,
+            });
+            buffer.write(frame);
+        }
+
+        // Frame 0 was evicted to make room for frame 2.
+        assert_eq!(buffer.read().unwrap().timestamp, 1);
+        assert_eq!(buffer.read().unwrap().timestamp, 2);
+        assert!(buffer.read().is_none());
+    }
+
+    #[test]
+    fn test_return_to_caller_rejects_when_full() {
+        let buffer = FrameBuffer::with_policy(1, OverflowPolicy::ReturnToCaller);
+        let frame0 = Arc::new(GpuFrame {
+            data: vec![],
+            width: 0,
+            height: 0,
+            timestamp: 0,
+        });
+        assert!(buffer.write(frame0).is_none());
+
+        let frame1 = Arc::new(GpuFrame {
+            data: vec![],
+            width: 0,
+            height: 0,
+            timestamp: 1,
+        });
+        let rejected = buffer.write(frame1.clone()).expect("full ring should reject");
+        assert_eq!(rejected.timestamp, frame1.timestamp);
+
+        assert_eq!(buffer.read().unwrap().timestamp, 0);
     }
 
     #[test]
     fn test_concurrent_access() {
-        let buffer = Arc::new(FrameBuffer::new());
+        let buffer = Arc::new(FrameBuffer::new(8));
         let buffer_writer = buffer.clone();
         let buffer_reader = buffer.clone();
 
-        // Writer thread
         let writer = thread::spawn(move || {
             for i in 0..100 {
                 let frame = Arc::new(GpuFrame {
@@ -157,26 +282,22 @@ mod tests {
             }
         });
 
-        // Reader thread
         let reader = thread::spawn(move || {
-            let mut last_timestamp = 0;
-            let mut duplicates = 0;
             let mut frames_read = 0;
+            let mut last_timestamp = None;
 
-            for _ in 0..200 {
+            for _ in 0..300 {
                 if let Some(frame) = buffer_reader.read() {
                     frames_read += 1;
-                    if frame.timestamp == last_timestamp {
-                        duplicates += 1;
-                    } else {
-                        assert!(frame.timestamp >= last_timestamp);
-                        last_timestamp = frame.timestamp;
+                    if let Some(last) = last_timestamp {
+                        assert!(frame.timestamp > last, "frames must come out in order");
                     }
+                    last_timestamp = Some(frame.timestamp);
                 }
                 thread::sleep(Duration::from_micros(50));
             }
 
-            println!("Frames read: {}, Duplicates: {}", frames_read, duplicates);
+            println!("Frames read: {}", frames_read);
             assert!(frames_read > 0);
         });
 
@@ -0,0 +1,76 @@
+use sha2::{Digest, Sha256};
+
+use cros_codecs::libva::Image;
+use cros_codecs::FrameLayout;
+
+/// Row-by-row SHA-256 over an NV12 frame, inspired by Fuchsia's
+/// `video_frame_hasher`: each plane is fed in one row at a time, truncated
+/// to the frame's real display width, so stride padding never affects the
+/// digest -- two uploads of the same pixels into surfaces with different
+/// pitches hash identically.
+pub struct FrameHasher;
+
+impl FrameHasher {
+    /// Hash an already-uploaded NV12 `libva::Image`. `display_width`/
+    /// `display_height` are the frame's real resolution (as passed to
+    /// whatever `upload_nv12_frame`-style call produced this `Image`), used
+    /// for the per-row byte count instead of `va_image.pitches`, which can
+    /// be wider than the frame due to surface alignment.
+    pub fn hash_nv12_image(image: &Image, display_width: u32, display_height: u32) -> String {
+        let va_image = *image.image();
+        let display_width = display_width as usize;
+        let display_height = display_height as usize;
+        let data = image.as_ref();
+
+        let mut hasher = Sha256::new();
+        Self::hash_plane(
+            &mut hasher,
+            data,
+            va_image.offsets[0] as usize,
+            va_image.pitches[0] as usize,
+            display_width,
+            display_height,
+        );
+        // NV12's interleaved U/V plane has the same per-row byte count as
+        // luma (one U and one V byte per luma column pair) and half the rows.
+        Self::hash_plane(
+            &mut hasher,
+            data,
+            va_image.offsets[1] as usize,
+            va_image.pitches[1] as usize,
+            display_width,
+            display_height.div_ceil(2),
+        );
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Hash a raw NV12 buffer plus the `FrameLayout` describing its strides,
+    /// without going through a VA `Image` -- e.g. to hash frames straight out
+    /// of a file reader before they're ever uploaded to a surface.
+    pub fn hash_nv12_buffer(buffer: &[u8], layout: &FrameLayout, display_width: u32, display_height: u32) -> String {
+        let mut hasher = Sha256::new();
+        for (plane_idx, plane) in layout.planes.iter().enumerate() {
+            let plane_height = if plane_idx == 0 {
+                display_height
+            } else {
+                display_height.div_ceil(2)
+            };
+            Self::hash_plane(
+                &mut hasher,
+                buffer,
+                plane.offset,
+                plane.stride,
+                display_width as usize,
+                plane_height as usize,
+            );
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn hash_plane(hasher: &mut Sha256, data: &[u8], offset: usize, stride: usize, row_bytes: usize, rows: usize) {
+        for row in 0..rows {
+            let start = offset + row * stride;
+            hasher.update(&data[start..start + row_bytes]);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use std::{borrow::Borrow, rc::Rc};
+
+use anyhow::{anyhow, Result};
+use cros_codecs::{
+    backend::vaapi::{decoder::VaapiBackend, surface_pool::PooledVaSurface},
+    decoder::stateless::{h264::H264, StatelessDecoder},
+    libva::{Display, Surface},
+    BlockingMode,
+};
+
+/// Splits an Annex-B byte stream (0x000001/0x00000001 start codes) into its
+/// NAL units, the framing `--input-format h264` reads -- AVCC-style
+/// length-prefixed NALs aren't supported here since the sample has no box
+/// parser to pull the `avcC` length size from.
+pub fn split_annex_b_nalus(data: &[u8]) -> Vec<&[u8]> {
+    let starts: Vec<usize> = (0..data.len())
+        .filter(|&i| data[i..].starts_with(&[0, 0, 1]))
+        .collect();
+    starts
+        .iter()
+        .enumerate()
+        .map(|(idx, &start)| {
+            let nalu_start = start + 3;
+            let nalu_end = starts.get(idx + 1).map_or(data.len(), |&next| {
+                // Trim the trailing zero byte of a 4-byte start code
+                // (0x00000001) off the end of this NALU.
+                if next > 0 && data[next - 1] == 0 {
+                    next - 1
+                } else {
+                    next
+                }
+            });
+            &data[nalu_start..nalu_end]
+        })
+        .collect()
+}
+
+/// Hardware H264 decode front-end: parses Annex-B NAL units and feeds them
+/// to a [`StatelessDecoder`] backed by VAAPI (same "decode straight to VA
+/// surfaces" approach as nihav's `hwdec-vaapi` module), so decoded pictures
+/// can be handed to [`crate::vaapi_scaler::VaapiScaler`] as `src_surface`
+/// without ever downloading them to a CPU buffer first.
+pub struct H264Decoder {
+    decoder: StatelessDecoder<H264, VaapiBackend<PooledVaSurface<()>>>,
+}
+
+impl H264Decoder {
+    pub fn new(display: Rc<Display>) -> Result<Self> {
+        let decoder = StatelessDecoder::<H264, _>::new_vaapi(display, BlockingMode::NonBlocking)
+            .map_err(|e| anyhow!("Failed to create H264 decoder: {:?}", e))?;
+        Ok(Self { decoder })
+    }
+
+    /// Feed one NAL unit (no start code) into the decoder. A single input
+    /// access unit is usually several NAL units (SPS/PPS/slice), so this is
+    /// called once per element of [`split_annex_b_nalus`]'s output.
+    pub fn decode_nalu(&mut self, timestamp: u64, nalu: &[u8]) -> Result<()> {
+        self.decoder
+            .decode(timestamp, nalu)
+            .map_err(|e| anyhow!("Failed to decode H264 NAL unit: {:?}", e))?;
+        Ok(())
+    }
+
+    /// Drain every picture the decoder now has ready, in display order.
+    /// Returns an empty `Vec` if nothing has finished decoding yet -- e.g.
+    /// right after feeding only a parameter-set NAL.
+    pub fn poll(&mut self) -> Result<Vec<PooledVaSurface<()>>> {
+        let mut surfaces = Vec::new();
+        while let Some(surface) = self
+            .decoder
+            .next_event()
+            .map_err(|e| anyhow!("Failed to poll H264 decoder: {:?}", e))?
+        {
+            surfaces.push(surface);
+        }
+        Ok(surfaces)
+    }
+
+    /// Signal end of stream and drain whatever pictures were still
+    /// in-flight (e.g. held back for B-frame reordering).
+    pub fn drain(&mut self) -> Result<Vec<PooledVaSurface<()>>> {
+        self.decoder
+            .flush()
+            .map_err(|e| anyhow!("Failed to flush H264 decoder: {:?}", e))?;
+        self.poll()
+    }
+}
+
+/// Borrow a decoded surface the same way the rest of this binary borrows
+/// pooled encode/scale surfaces.
+pub fn borrow_surface(surface: &PooledVaSurface<()>) -> &Surface<()> {
+    surface.borrow()
+}
@@ -0,0 +1,376 @@
+//! Small bit-level pass that patches VUI color-signaling fields into an
+//! H.264 SPS, for encoders (like the `cros-codecs` stateless one) that don't
+//! expose `colour_primaries`/`transfer_characteristics`/etc. directly.
+//!
+//! Only handles the common case produced by this crate's encoder: a
+//! Main/Baseline-profile SPS (no separate chroma/bit-depth syntax) with no
+//! VUI parameters present yet. If a SPS already carries VUI parameters, the
+//! bitstream is left untouched rather than risk corrupting it.
+
+use anyhow::{bail, Result};
+
+/// Color-signaling fields to stamp into the SPS VUI, per ITU-T H.264 Annex E.
+#[derive(Debug, Clone, Copy)]
+pub struct ColorConfig {
+    pub colour_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+    pub video_full_range_flag: bool,
+}
+
+impl ColorConfig {
+    /// sRGB desktop content: BT.709 primaries/matrix, IEC 61966-2-1 transfer.
+    pub const SRGB: Self = Self {
+        colour_primaries: 1,
+        transfer_characteristics: 13,
+        matrix_coefficients: 1,
+        video_full_range_flag: false,
+    };
+
+    /// Rec.709 video content.
+    pub const REC709: Self = Self {
+        colour_primaries: 1,
+        transfer_characteristics: 1,
+        matrix_coefficients: 1,
+        video_full_range_flag: false,
+    };
+
+    /// HDR10: BT.2020 primaries/matrix, SMPTE ST 2084 (PQ) transfer.
+    pub const HDR10: Self = Self {
+        colour_primaries: 9,
+        transfer_characteristics: 16,
+        matrix_coefficients: 9,
+        video_full_range_flag: false,
+    };
+}
+
+const NAL_TYPE_SPS: u8 = 7;
+
+/// Patch the VUI color-signaling fields into the first SPS NAL found in an
+/// Annex-B `bitstream`, in place. A no-op if no SPS is found or the SPS
+/// already carries VUI parameters.
+pub fn patch_sps_vui(bitstream: &mut Vec<u8>, color: ColorConfig) -> Result<()> {
+    let Some((nal_start, nal_end)) = find_sps_nal(bitstream) else {
+        return Ok(());
+    };
+
+    let header = bitstream[nal_start];
+    let rbsp = remove_emulation_prevention(&bitstream[nal_start + 1..nal_end]);
+
+    let Some(new_rbsp) = rewrite_sps_rbsp(&rbsp, color)? else {
+        // VUI already present (or parsing hit an unsupported profile); leave as-is.
+        return Ok(());
+    };
+
+    let mut new_nal = Vec::with_capacity(new_rbsp.len() + new_rbsp.len() / 2 + 1);
+    new_nal.push(header);
+    new_nal.extend(add_emulation_prevention(&new_rbsp));
+
+    bitstream.splice(nal_start..nal_end, new_nal);
+    Ok(())
+}
+
+fn find_sps_nal(bitstream: &[u8]) -> Option<(usize, usize)> {
+    let starts = find_start_codes(bitstream);
+    for (i, &(sc_start, payload_start)) in starts.iter().enumerate() {
+        if payload_start >= bitstream.len() {
+            continue;
+        }
+        let nal_unit_type = bitstream[payload_start] & 0x1f;
+        if nal_unit_type != NAL_TYPE_SPS {
+            continue;
+        }
+        let next_start = starts
+            .get(i + 1)
+            .map(|&(sc, _)| sc)
+            .unwrap_or(bitstream.len());
+        return Some((payload_start, next_start));
+    }
+    let _ = starts;
+    None
+}
+
+/// Returns `(start_code_offset, nal_payload_offset)` for every Annex-B start
+/// code (`00 00 01` or `00 00 00 01`) found in `data`. Also used by the
+/// `mp4` muxer to split an access unit into its component NAL units.
+pub(crate) fn find_start_codes(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 2 < data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push((i, i + 3));
+            i += 3;
+        } else if i + 3 < data.len() && data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 0 && data[i + 3] == 1 {
+            starts.push((i, i + 4));
+            i += 4;
+        } else {
+            i += 1;
+        }
+    }
+    starts
+}
+
+pub(crate) fn remove_emulation_prevention(nal: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal.len());
+    let mut zero_run = 0;
+    for &byte in nal {
+        if zero_run >= 2 && byte == 0x03 {
+            zero_run = 0;
+            continue;
+        }
+        rbsp.push(byte);
+        zero_run = if byte == 0 { zero_run + 1 } else { 0 };
+    }
+    rbsp
+}
+
+fn add_emulation_prevention(rbsp: &[u8]) -> Vec<u8> {
+    let mut nal = Vec::with_capacity(rbsp.len() + rbsp.len() / 2);
+    let mut zero_run = 0;
+    for &byte in rbsp {
+        if zero_run >= 2 && byte <= 0x03 {
+            nal.push(0x03);
+            zero_run = 0;
+        }
+        nal.push(byte);
+        zero_run = if byte == 0 { zero_run + 1 } else { 0 };
+    }
+    nal
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    bit_pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, bit_pos: 0 }
+    }
+
+    fn u(&mut self, n: u32) -> Result<u64> {
+        let mut value = 0u64;
+        for _ in 0..n {
+            let byte = self.bit_pos / 8;
+            if byte >= self.data.len() {
+                bail!("ran out of bits while parsing SPS");
+            }
+            let bit = 7 - (self.bit_pos % 8);
+            let b = (self.data[byte] >> bit) & 1;
+            value = (value << 1) | b as u64;
+            self.bit_pos += 1;
+        }
+        Ok(value)
+    }
+
+    fn ue(&mut self) -> Result<u64> {
+        let mut leading_zeros = 0;
+        while self.u(1)? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 32 {
+                bail!("malformed Exp-Golomb code in SPS");
+            }
+        }
+        if leading_zeros == 0 {
+            return Ok(0);
+        }
+        let suffix = self.u(leading_zeros)?;
+        Ok((1u64 << leading_zeros) - 1 + suffix)
+    }
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit_pos: usize,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: vec![0],
+            bit_pos: 0,
+        }
+    }
+
+    fn u(&mut self, value: u64, n: u32) {
+        for i in (0..n).rev() {
+            let bit = ((value >> i) & 1) as u8;
+            let byte = self.bit_pos / 8;
+            if byte == self.bytes.len() {
+                self.bytes.push(0);
+            }
+            let shift = 7 - (self.bit_pos % 8);
+            self.bytes[byte] |= bit << shift;
+            self.bit_pos += 1;
+        }
+    }
+
+    fn ue(&mut self, value: u64) {
+        let value = value + 1;
+        let bits = 64 - value.leading_zeros();
+        self.u(0, bits - 1);
+        self.u(value as u64, bits);
+    }
+
+    /// Append `rbsp_trailing_bits()`: a stop bit followed by zero padding to
+    /// the next byte boundary.
+    fn rbsp_trailing_bits(&mut self) {
+        self.u(1, 1);
+        let pad = (8 - (self.bit_pos % 8)) % 8;
+        if pad > 0 {
+            self.u(0, pad);
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+const PROFILES_WITH_CHROMA_INFO: &[u64] = &[
+    100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135,
+];
+
+/// Parse `seq_parameter_set_rbsp()` up to `vui_parameters_present_flag`,
+/// and (if VUI isn't already present) rewrite the RBSP with our color
+/// signaling VUI appended. Returns `Ok(None)` when the SPS already has VUI
+/// parameters or uses a profile this parser doesn't understand.
+fn rewrite_sps_rbsp(rbsp: &[u8], color: ColorConfig) -> Result<Option<Vec<u8>>> {
+    let mut r = BitReader::new(rbsp);
+
+    let profile_idc = r.u(8)?;
+    let flags_and_reserved = r.u(8)?; // constraint_set0..5_flag + reserved_zero_2bits
+    let level_idc = r.u(8)?;
+    let seq_parameter_set_id = r.ue()?;
+
+    if PROFILES_WITH_CHROMA_INFO.contains(&profile_idc) {
+        // Chroma/bit-depth syntax for high profiles isn't needed by this
+        // encoder (it only emits Main profile); bail out rather than risk
+        // misparsing and corrupting the SPS.
+        return Ok(None);
+    }
+
+    let log2_max_frame_num_minus4 = r.ue()?;
+    let pic_order_cnt_type = r.ue()?;
+    let mut log2_max_pic_order_cnt_lsb_minus4 = None;
+    let mut poc_cycle = Vec::new();
+    let mut delta_pic_order_always_zero_flag = 0;
+    let mut offset_for_non_ref_pic = 0;
+    let mut offset_for_top_to_bottom_field = 0;
+    match pic_order_cnt_type {
+        0 => log2_max_pic_order_cnt_lsb_minus4 = Some(r.ue()?),
+        1 => {
+            delta_pic_order_always_zero_flag = r.u(1)?;
+            offset_for_non_ref_pic = signed_from_ue(r.ue()?);
+            offset_for_top_to_bottom_field = signed_from_ue(r.ue()?);
+            let num_ref_frames_in_pic_order_cnt_cycle = r.ue()?;
+            for _ in 0..num_ref_frames_in_pic_order_cnt_cycle {
+                poc_cycle.push(signed_from_ue(r.ue()?));
+            }
+        }
+        _ => {}
+    }
+    let max_num_ref_frames = r.ue()?;
+    let gaps_in_frame_num_value_allowed_flag = r.u(1)?;
+    let pic_width_in_mbs_minus1 = r.ue()?;
+    let pic_height_in_map_units_minus1 = r.ue()?;
+    let frame_mbs_only_flag = r.u(1)?;
+    let mb_adaptive_frame_field_flag = if frame_mbs_only_flag == 0 {
+        Some(r.u(1)?)
+    } else {
+        None
+    };
+    let direct_8x8_inference_flag = r.u(1)?;
+    let frame_cropping_flag = r.u(1)?;
+    let crop = if frame_cropping_flag == 1 {
+        Some((r.ue()?, r.ue()?, r.ue()?, r.ue()?))
+    } else {
+        None
+    };
+    let vui_parameters_present_flag = r.u(1)?;
+    if vui_parameters_present_flag == 1 {
+        // Already has VUI; don't attempt to locate/patch fields inside it.
+        return Ok(None);
+    }
+
+    let mut w = BitWriter::new();
+    w.u(profile_idc, 8);
+    w.u(flags_and_reserved, 8);
+    w.u(level_idc, 8);
+    w.ue(seq_parameter_set_id);
+    w.ue(log2_max_frame_num_minus4);
+    w.ue(pic_order_cnt_type);
+    match pic_order_cnt_type {
+        0 => w.ue(log2_max_pic_order_cnt_lsb_minus4.unwrap()),
+        1 => {
+            w.u(delta_pic_order_always_zero_flag, 1);
+            w.ue(unsigned_to_ue(offset_for_non_ref_pic));
+            w.ue(unsigned_to_ue(offset_for_top_to_bottom_field));
+            w.ue(poc_cycle.len() as u64);
+            for offset in &poc_cycle {
+                w.ue(unsigned_to_ue(*offset));
+            }
+        }
+        _ => {}
+    }
+    w.ue(max_num_ref_frames);
+    w.u(gaps_in_frame_num_value_allowed_flag, 1);
+    w.ue(pic_width_in_mbs_minus1);
+    w.ue(pic_height_in_map_units_minus1);
+    w.u(frame_mbs_only_flag, 1);
+    if let Some(flag) = mb_adaptive_frame_field_flag {
+        w.u(flag, 1);
+    }
+    w.u(direct_8x8_inference_flag, 1);
+    w.u(frame_cropping_flag, 1);
+    if let Some((left, right, top, bottom)) = crop {
+        w.ue(left);
+        w.ue(right);
+        w.ue(top);
+        w.ue(bottom);
+    }
+
+    // vui_parameters_present_flag = 1, followed by our minimal vui_parameters().
+    w.u(1, 1);
+    write_vui_parameters(&mut w, color);
+
+    w.rbsp_trailing_bits();
+    Ok(Some(w.into_bytes()))
+}
+
+/// Writes a `vui_parameters()` with only `video_signal_type_present_flag`
+/// and `colour_description_present_flag` set, everything else left at its
+/// "not present" default.
+fn write_vui_parameters(w: &mut BitWriter, color: ColorConfig) {
+    w.u(0, 1); // aspect_ratio_info_present_flag
+    w.u(0, 1); // overscan_info_present_flag
+    w.u(1, 1); // video_signal_type_present_flag
+    w.u(5, 3); // video_format: 5 = Unspecified
+    w.u(color.video_full_range_flag as u64, 1); // video_full_range_flag
+    w.u(1, 1); // colour_description_present_flag
+    w.u(color.colour_primaries as u64, 8);
+    w.u(color.transfer_characteristics as u64, 8);
+    w.u(color.matrix_coefficients as u64, 8);
+    w.u(0, 1); // chroma_loc_info_present_flag
+    w.u(0, 1); // timing_info_present_flag
+    w.u(0, 1); // nal_hrd_parameters_present_flag
+    w.u(0, 1); // vcl_hrd_parameters_present_flag
+    w.u(0, 1); // pic_struct_present_flag
+    w.u(0, 1); // bitstream_restriction_flag
+}
+
+fn signed_from_ue(ue: u64) -> i64 {
+    if ue % 2 == 0 {
+        -((ue / 2) as i64)
+    } else {
+        (ue / 2 + 1) as i64
+    }
+}
+
+fn unsigned_to_ue(value: i64) -> u64 {
+    if value <= 0 {
+        (-value as u64) * 2
+    } else {
+        (value as u64) * 2 - 1
+    }
+}
@@ -1,6 +1,6 @@
 use std::{
     fs::File,
-    io::Write,
+    io::{Read, Write},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -11,17 +11,112 @@ use std::{
 use std::thread;
 use std::time::Duration;
 
+use clap::Parser;
+
+mod audio;
+mod audio_capture;
 mod capture;
 mod encode;
+mod encode_ffmpeg;
+mod frame_buffer;
+mod h264_vui;
+mod main_ffmpeg;
+mod mp4;
+mod mux_ffmpeg;
+mod pipeline_ffmpeg;
 
+use audio::{AudioEncoder, AudioEncoderSettings};
 use capture::Capturer;
-use encode::Encoder;
+use encode::{Encoder, EncoderSettings};
+use mp4::{AudioConfig, Mp4Muxer, OutputFormat};
 
 const FPS: u32 = 60;
+const OUTPUT_FORMAT: OutputFormat = OutputFormat::Fmp4;
+
+#[derive(Parser)]
+#[command(name = "gamescope-recorder")]
+#[command(about = "Capture and encode the gamescope compositor's output")]
+struct Args {
+    /// Raw interleaved S16LE PCM file to capture audio from, encoded to AAC
+    /// and interleaved with the video into the output container. Without
+    /// this the recording is video-only.
+    #[arg(long)]
+    audio_input: Option<String>,
+
+    /// Sample rate of `--audio-input`'s PCM.
+    #[arg(long, default_value_t = 48_000)]
+    audio_sample_rate: u32,
+
+    /// Channel count of `--audio-input`'s PCM.
+    #[arg(long, default_value_t = 2)]
+    audio_channels: u16,
+
+    /// How frame timestamps are derived: `cfr` stamps one tick per frame at
+    /// `--capture-timebase`, ignoring how long capture/encode actually took;
+    /// `real` stamps each frame with elapsed wall-clock time since capture
+    /// started, so uneven frame pacing survives into the output instead of
+    /// being flattened to a fixed rate.
+    #[arg(long, value_enum, default_value = "cfr")]
+    timestamps: TimestampMode,
+
+    /// Ticks per second for frame timestamps and the video track's mp4
+    /// timescale. Defaults to the capture framerate, which is what `cfr`
+    /// mode wants; `real` mode can use a finer timebase (e.g. 1_000_000 for
+    /// microsecond precision) to represent timing that doesn't land on
+    /// exact frame boundaries.
+    #[arg(long)]
+    capture_timebase: Option<u32>,
+
+    /// Capture and encode through the FFmpeg/VAAPI track
+    /// ([`main_ffmpeg`]/[`encode_ffmpeg`]/[`mux_ffmpeg`]/[`pipeline_ffmpeg`])
+    /// instead of the hand-rolled encoder and muxer above, writing
+    /// `output_ffmpeg.fmp4`. `--audio-input`/`--timestamps` don't apply to
+    /// this path; see `--audio-capture` for its audio track instead.
+    #[arg(long)]
+    ffmpeg_pipeline: bool,
+
+    /// With `--ffmpeg-pipeline`, also capture live PipeWire audio (rather
+    /// than from a file) and interleave it into the output as AAC.
+    #[arg(long)]
+    audio_capture: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq)]
+enum TimestampMode {
+    Cfr,
+    Real,
+}
 
 fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    if args.ffmpeg_pipeline {
+        return main_ffmpeg::run(&args);
+    }
+    let timebase = args.capture_timebase.unwrap_or(FPS);
+
     let mut encoder: Option<Encoder> = None;
-    let mut output_file = File::create("output.h264")?;
+    let mut output_file = match OUTPUT_FORMAT {
+        OutputFormat::AnnexB => Some(File::create("output.h264")?),
+        OutputFormat::Mp4 | OutputFormat::Fmp4 => None,
+    };
+    let mut muxer: Option<Mp4Muxer> = None;
+
+    let mut audio_input = args.audio_input.as_ref().map(File::open).transpose()?;
+    let mut audio_encoder = audio_input
+        .is_some()
+        .then(|| {
+            AudioEncoder::new(AudioEncoderSettings {
+                sample_rate: args.audio_sample_rate,
+                channels: args.audio_channels,
+                ..Default::default()
+            })
+        })
+        .transpose()?;
+    // One encoder-frame's worth of PCM per read, matching the fifo's own
+    // drain granularity so `read_exact_or_eof` never piles up more than one
+    // chunk of backlog in `AudioEncoder`'s internal fifo.
+    const AUDIO_CHUNK_FRAMES: usize = 1024;
+    let mut audio_chunk_buf = vec![0u8; AUDIO_CHUNK_FRAMES * args.audio_channels as usize * 2];
 
     let capturer = Capturer::new()?;
     let running = Arc::new(AtomicBool::new(true));
@@ -43,26 +138,76 @@ fn main() -> anyhow::Result<()> {
         // Get last frame from the capturer
         if let Some(frame) = capturer.read_frame() {
             if encoder.is_none() {
-                encoder = Some(Encoder::new(FPS, &frame).expect("Failed to create encoder"));
+                let new_encoder = Encoder::new(FPS, &frame, EncoderSettings::default())
+                    .expect("Failed to create encoder");
+                if matches!(OUTPUT_FORMAT, OutputFormat::Mp4 | OutputFormat::Fmp4) {
+                    let size = new_encoder.frame_layout.size;
+                    let path = match OUTPUT_FORMAT {
+                        OutputFormat::Mp4 => "output.mp4",
+                        OutputFormat::Fmp4 => "output.fmp4",
+                        OutputFormat::AnnexB => unreachable!(),
+                    };
+                    let audio_config = audio_encoder.as_ref().map(|enc| AudioConfig {
+                        sample_rate: args.audio_sample_rate,
+                        channels: args.audio_channels,
+                        asc: enc.audio_specific_config(),
+                    });
+                    muxer = Some(
+                        Mp4Muxer::new(path, OUTPUT_FORMAT, timebase, size.width, size.height, audio_config)
+                            .expect("Failed to create mp4 muxer"),
+                    );
+                }
+                encoder = Some(new_encoder);
             }
-            // Encode the frame
+            // Encode the frame, timestamped per `--timestamps`: `real` stamps
+            // elapsed wall-clock time (converted into `timebase` ticks) so
+            // capture jitter and stalls show up as actual variable sample
+            // durations in the container; `cfr` just takes the next tick.
             let encoder = encoder.as_mut().unwrap();
-            encoder.encode(frame)?;
+            let pts = match args.timestamps {
+                TimestampMode::Cfr => encoder.next_cfr_pts(),
+                TimestampMode::Real => {
+                    (start.elapsed().as_secs_f64() * timebase as f64).round() as u64
+                }
+            };
+            encoder.encode(frame, pts)?;
         } else {
             eprintln!("No frame captured");
         }
 
-        // Write the encoded frame to the output file
+        // Write the encoded frame to the output file/container
         if let Some(encoder) = &mut encoder {
-            while let Some(bitstream) = encoder.poll()? {
+            while let Some(frame) = encoder.poll()? {
                 frame_count += 1;
                 if frame_count % 60 == 0 {
                     print!(".");
                     std::io::stdout().flush().expect("Failed to flush stdout");
                 }
-                output_file
-                    .write_all(&bitstream.bitstream)
-                    .expect("Failed to write to output file");
+                if let Some(output_file) = &mut output_file {
+                    output_file
+                        .write_all(&frame.bitstream)
+                        .expect("Failed to write to output file");
+                } else if let Some(muxer) = &mut muxer {
+                    muxer
+                        .push_frame(&frame.bitstream, frame.pts)
+                        .expect("Failed to mux frame");
+                }
+            }
+        }
+
+        // Keep writing PCM into the audio fifo and draining whatever AAC
+        // access units that makes ready, same poll-driven shape as the
+        // video encoder above.
+        if let (Some(audio_input), Some(audio_encoder)) = (&mut audio_input, &mut audio_encoder) {
+            if read_exact_or_eof(audio_input, &mut audio_chunk_buf)? {
+                audio_encoder.push_samples(bytes_to_i16le(&audio_chunk_buf));
+            }
+            if let Some(muxer) = &mut muxer {
+                while let Some(packet) = audio_encoder.poll()? {
+                    muxer
+                        .push_audio_packet(&packet.data, packet.duration as u32)
+                        .expect("Failed to mux audio packet");
+                }
             }
         }
 
@@ -81,12 +226,52 @@ fn main() -> anyhow::Result<()> {
     println!("\nDraining encoder...");
     if let Some(mut encoder) = encoder {
         encoder.drain()?;
-        while let Some(bitstream) = encoder.poll()? {
-            output_file
-                .write_all(&bitstream.bitstream)
-                .expect("Failed to write to output file");
+        while let Some(frame) = encoder.poll()? {
+            if let Some(output_file) = &mut output_file {
+                output_file
+                    .write_all(&frame.bitstream)
+                    .expect("Failed to write to output file");
+            } else if let Some(muxer) = &mut muxer {
+                muxer
+                    .push_frame(&frame.bitstream, frame.pts)
+                    .expect("Failed to mux frame");
+            }
+        }
+    }
+    if let Some(mut audio_encoder) = audio_encoder {
+        audio_encoder.drain()?;
+        if let Some(muxer) = &mut muxer {
+            while let Some(packet) = audio_encoder.poll()? {
+                muxer
+                    .push_audio_packet(&packet.data, packet.duration as u32)
+                    .expect("Failed to mux audio packet");
+            }
         }
     }
+    if let Some(muxer) = muxer {
+        muxer.finish().expect("Failed to finalize mp4 output");
+    }
 
     Ok(())
 }
+
+/// Fill `buf` completely from `file`, or return `false` without consuming
+/// anything if fewer than `buf.len()` bytes remain (end of the PCM input).
+fn read_exact_or_eof(file: &mut File, buf: &mut [u8]) -> anyhow::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = file.read(&mut buf[filled..])?;
+        if n == 0 {
+            return Ok(false);
+        }
+        filled += n;
+    }
+    Ok(true)
+}
+
+/// Decode a little-endian S16 PCM byte buffer into samples.
+fn bytes_to_i16le(buf: &[u8]) -> Vec<i16> {
+    buf.chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect()
+}
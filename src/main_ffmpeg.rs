@@ -0,0 +1,95 @@
+//! Entry point for the ffmpeg-backed capture→encode→mux track
+//! ([`crate::encode_ffmpeg`]/[`crate::mux_ffmpeg`]/[`crate::pipeline_ffmpeg`]),
+//! selected via `--ffmpeg-pipeline` instead of the default hand-rolled
+//! encoder/muxer ([`crate::encode`]/[`crate::mp4`]) `main` normally runs.
+//! Same capture loop and Ctrl+C handling as the default path, but frames are
+//! handed to a [`Pipeline`] instead of encoded synchronously, and audio (if
+//! `--audio-capture` is set) comes from live PipeWire capture via
+//! [`crate::audio_capture::AudioCapturer`] instead of a PCM file.
+
+use std::{
+    fs::File,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::audio::AudioEncoderSettings;
+use crate::audio_capture::AudioCapturer;
+use crate::capture::Capturer;
+use crate::encode_ffmpeg::{EncoderConfig, PtsMode};
+use crate::pipeline_ffmpeg::{DropPolicy, Pipeline};
+use crate::Args;
+
+/// Frames the capture stage is allowed to get ahead of encode+mux by before
+/// [`DropPolicy::DropOldest`] starts evicting -- matches `capture.rs`'s own
+/// `FRAME_BUFFER_CAPACITY`.
+const PIPELINE_CAPACITY: usize = 4;
+
+/// How often the main loop drains [`AudioCapturer`]'s fifo into the
+/// pipeline -- fine-grained enough that `AudioEncoder`'s own fifo (one AAC
+/// frame, 1024 samples) never backs up by more than a chunk or two.
+const AUDIO_CAPTURE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+pub fn run(args: &Args) -> Result<()> {
+    let framerate = args.capture_timebase.unwrap_or(60) as i32;
+
+    let audio_capturer = if args.audio_capture {
+        Some(AudioCapturer::new(args.audio_sample_rate, args.audio_channels)?)
+    } else {
+        None
+    };
+    let audio_config = audio_capturer.as_ref().map(|_| AudioEncoderSettings {
+        sample_rate: args.audio_sample_rate,
+        channels: args.audio_channels,
+        ..Default::default()
+    });
+
+    let sink = File::create("output_ffmpeg.fmp4")?;
+    let mut pipeline = Pipeline::new(
+        PIPELINE_CAPACITY,
+        DropPolicy::DropOldest,
+        framerate,
+        EncoderConfig::default(),
+        audio_config,
+        Box::new(sink),
+    )?;
+
+    let capturer = Capturer::new()?;
+    let running = Arc::new(AtomicBool::new(true));
+    ctrlc::set_handler({
+        let running = running.clone();
+        move || {
+            println!("Received Ctrl+C!");
+            running.store(false, Ordering::SeqCst);
+        }
+    })
+    .expect("Error setting Ctrl+C handler");
+
+    let mut last_audio_poll = std::time::Instant::now();
+    while running.load(Ordering::SeqCst) {
+        if let Some(frame) = capturer.read_frame() {
+            pipeline.submit(frame, PtsMode::Real);
+        }
+
+        if let Some(audio_capturer) = &audio_capturer {
+            if last_audio_poll.elapsed() >= AUDIO_CAPTURE_POLL_INTERVAL {
+                let samples = audio_capturer.read_samples();
+                if !samples.is_empty() {
+                    pipeline.submit_audio_samples(samples);
+                }
+                last_audio_poll = std::time::Instant::now();
+            }
+        }
+
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    println!("\nDraining ffmpeg pipeline...");
+    pipeline.shutdown()
+}
@@ -0,0 +1,910 @@
+//! MP4/fMP4 muxer for the encoder's Annex-B H.264 output.
+//!
+//! Box writing follows the approach used by gst-plugins-rs's `fmp4mux`:
+//! [`write_box`] writes the body through a closure directly into the output
+//! buffer and back-patches the 32-bit `size` field once the body's length is
+//! known, and [`write_full_box`] layers the `version`/`flags` word that
+//! `FullBox`-derived boxes need on top of that.
+//!
+//! [`OutputFormat::Mp4`] buffers every sample in memory and writes a single
+//! `moov` with a full sample table once [`Mp4Muxer::finish`] is called.
+//! [`OutputFormat::Fmp4`] instead emits `ftyp`+`moov` (with an
+//! empty-duration `mvex`/`trex`) up front, then one `moof`+`mdat` pair per
+//! GOP as frames arrive, so the file is streamable/seekable without
+//! buffering the whole capture.
+//!
+//! An optional AAC audio track ([`AudioConfig`]) rides alongside the video
+//! one as a second `trak`/`traf`: its own timescale (the sample rate,
+//! instead of the video track's framerate), its own track ID, but otherwise
+//! the same whole-movie/fragmented split as the video samples.
+
+use anyhow::{bail, Result};
+use std::fs::File;
+use std::io::Write;
+
+use crate::h264_vui::find_start_codes;
+
+const NAL_TYPE_IDR: u8 = 5;
+const NAL_TYPE_SPS: u8 = 7;
+const NAL_TYPE_PPS: u8 = 8;
+
+/// Everything the muxer needs to describe the audio track: its sample rate,
+/// channel count and the raw `AudioSpecificConfig` bytes the AAC encoder
+/// produced (carried in `esds`, mirroring how SPS/PPS are carried in `avcC`).
+pub struct AudioConfig {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub asc: Vec<u8>,
+}
+
+/// Container format to mux the encoder's H.264 output into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Headerless Annex-B elementary stream: the encoder's NAL units
+    /// concatenated back-to-back, no container at all.
+    AnnexB,
+    /// A single, non-fragmented MP4 file.
+    Mp4,
+    /// Fragmented MP4 (CMAF-style), one `moof`+`mdat` pair per GOP.
+    Fmp4,
+}
+
+/// One AVCC sample (an access unit) pending in a fragment or in the
+/// whole-movie sample table, paired with its bytes in `sample_data`.
+struct PendingSample {
+    size: u32,
+    duration: u32,
+    is_sync: bool,
+}
+
+pub struct Mp4Muxer {
+    format: OutputFormat,
+    file: File,
+    framerate: u32,
+    width: u32,
+    height: u32,
+    track_id: u32,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    header_written: bool,
+    sequence_number: u32,
+    // Fmp4: samples/data for the fragment currently being accumulated.
+    fragment_samples: Vec<PendingSample>,
+    fragment_data: Vec<u8>,
+    fragment_base_dts: u64,
+    // Mp4: samples/data for the whole movie, flushed at `finish`.
+    all_samples: Vec<PendingSample>,
+    all_data: Vec<u8>,
+    // Real capture timestamp (`EncodedFrame::pts`) for each sample above, in
+    // the same order -- kept alongside rather than folded into
+    // `PendingSample` since a sample's duration isn't known until the next
+    // one arrives. `fill_durations_from_timestamps` turns these into `PendingSample::duration`
+    // just before a fragment/the whole movie is written out.
+    fragment_timestamps: Vec<u64>,
+    all_timestamps: Vec<u64>,
+
+    // Audio track, only present when `Mp4Muxer::new` was given an
+    // `AudioConfig`. Mirrors the video fields above one-for-one, just with
+    // its own timescale (the sample rate, vs. the video track's framerate)
+    // and its own track ID.
+    audio: Option<AudioConfig>,
+    audio_track_id: u32,
+    audio_samples_total: u64,
+    audio_fragment_samples: Vec<PendingSample>,
+    audio_fragment_data: Vec<u8>,
+    audio_fragment_base_dts: u64,
+    audio_all_samples: Vec<PendingSample>,
+    audio_all_data: Vec<u8>,
+}
+
+impl Mp4Muxer {
+    /// `framerate` is the video track's timescale: for a fixed-framerate
+    /// capture, the capture's fps; for real (`EncodedFrame::pts`-driven)
+    /// timestamps, whatever timebase those timestamps are expressed in, so
+    /// per-sample durations computed from them land in the same units.
+    pub fn new(
+        path: &str,
+        format: OutputFormat,
+        framerate: u32,
+        width: u32,
+        height: u32,
+        audio: Option<AudioConfig>,
+    ) -> Result<Self> {
+        if format == OutputFormat::AnnexB {
+            bail!("Mp4Muxer doesn't handle OutputFormat::AnnexB; write the Annex-B bitstream directly instead");
+        }
+        Ok(Self {
+            format,
+            file: File::create(path)?,
+            framerate,
+            width,
+            height,
+            track_id: 1,
+            sps: None,
+            pps: None,
+            header_written: false,
+            sequence_number: 0,
+            fragment_samples: Vec::new(),
+            fragment_data: Vec::new(),
+            fragment_base_dts: 0,
+            all_samples: Vec::new(),
+            all_data: Vec::new(),
+            fragment_timestamps: Vec::new(),
+            all_timestamps: Vec::new(),
+            audio_track_id: if audio.is_some() { 2 } else { 0 },
+            audio,
+            audio_samples_total: 0,
+            audio_fragment_samples: Vec::new(),
+            audio_fragment_data: Vec::new(),
+            audio_fragment_base_dts: 0,
+            audio_all_samples: Vec::new(),
+            audio_all_data: Vec::new(),
+        })
+    }
+
+    /// Push the next access unit out of the encoder, in decode order, along
+    /// with its display-order presentation timestamp (e.g.
+    /// [`EncodedFrame::pts`](crate::encode::EncodedFrame::pts) for callers
+    /// wired up to [`crate::encode::Encoder`]; cros_codecs'
+    /// `CodedBitstreamBuffer::metadata.timestamp` for callers polling a
+    /// `StatelessEncoder` directly).
+    pub fn push_frame(&mut self, bitstream: &[u8], pts: u64) -> Result<()> {
+        let (is_sync, data) = self.to_avcc_sample(bitstream)?;
+        let sample = PendingSample {
+            size: data.len() as u32,
+            duration: 0, // patched from `pts` deltas just before the fragment/whole movie is written
+            is_sync,
+        };
+
+        match self.format {
+            OutputFormat::AnnexB => unreachable!("rejected in Mp4Muxer::new"),
+            OutputFormat::Mp4 => {
+                self.all_data.extend_from_slice(&data);
+                self.all_samples.push(sample);
+                self.all_timestamps.push(pts);
+            }
+            OutputFormat::Fmp4 => {
+                if !self.header_written {
+                    self.write_header()?;
+                    self.fragment_base_dts = pts;
+                    self.audio_fragment_base_dts = self.audio_samples_total;
+                } else if is_sync && !self.fragment_samples.is_empty() {
+                    self.flush_fragment(Some(pts))?;
+                    self.fragment_base_dts = pts;
+                    self.audio_fragment_base_dts = self.audio_samples_total;
+                }
+                self.fragment_data.extend_from_slice(&data);
+                self.fragment_samples.push(sample);
+                self.fragment_timestamps.push(pts);
+            }
+        }
+        Ok(())
+    }
+
+    /// Push one AAC access unit (raw, no ADTS header), in decode order.
+    /// `duration_in_samples` is the packet's length in the audio track's own
+    /// timescale (the sample rate) -- e.g. 1024 for a typical AAC-LC frame.
+    pub fn push_audio_packet(&mut self, data: &[u8], duration_in_samples: u32) -> Result<()> {
+        if self.audio.is_none() {
+            bail!("Mp4Muxer::push_audio_packet called without an AudioConfig passed to Mp4Muxer::new");
+        }
+        let sample = PendingSample {
+            size: data.len() as u32,
+            duration: duration_in_samples,
+            is_sync: true, // every AAC access unit is independently decodable
+        };
+        match self.format {
+            OutputFormat::AnnexB => unreachable!("rejected in Mp4Muxer::new"),
+            OutputFormat::Mp4 => {
+                self.audio_all_data.extend_from_slice(data);
+                self.audio_all_samples.push(sample);
+            }
+            OutputFormat::Fmp4 => {
+                self.audio_fragment_data.extend_from_slice(data);
+                self.audio_fragment_samples.push(sample);
+            }
+        }
+        self.audio_samples_total += duration_in_samples as u64;
+        Ok(())
+    }
+
+    /// Flush whatever is buffered and close out the container.
+    pub fn finish(mut self) -> Result<()> {
+        match self.format {
+            OutputFormat::AnnexB => unreachable!("rejected in Mp4Muxer::new"),
+            OutputFormat::Mp4 => self.write_whole_movie()?,
+            OutputFormat::Fmp4 => {
+                if !self.header_written {
+                    // Never got a single frame; still leave a valid, empty movie behind.
+                    self.write_header()?;
+                }
+                if !self.fragment_samples.is_empty() || !self.audio_fragment_samples.is_empty() {
+                    self.flush_fragment(None)?;
+                }
+            }
+        }
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// Split `bitstream`'s NAL units into AVCC length-prefixed samples,
+    /// stashing SPS/PPS (needed for `avcC`) instead of writing them out as
+    /// samples. Returns whether this access unit starts with an IDR.
+    fn to_avcc_sample(&mut self, bitstream: &[u8]) -> Result<(bool, Vec<u8>)> {
+        let starts = find_start_codes(bitstream);
+        let mut data = Vec::with_capacity(bitstream.len());
+        let mut is_sync = false;
+        for (i, &(_, payload_start)) in starts.iter().enumerate() {
+            if payload_start >= bitstream.len() {
+                continue;
+            }
+            let next_start = starts
+                .get(i + 1)
+                .map(|&(sc, _)| sc)
+                .unwrap_or(bitstream.len());
+            let nal = &bitstream[payload_start..next_start];
+            let nal_type = nal[0] & 0x1f;
+            match nal_type {
+                NAL_TYPE_SPS => self.sps = Some(nal.to_vec()),
+                NAL_TYPE_PPS => self.pps = Some(nal.to_vec()),
+                _ => {
+                    if nal_type == NAL_TYPE_IDR {
+                        is_sync = true;
+                    }
+                    data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    data.extend_from_slice(nal);
+                }
+            }
+        }
+        Ok((is_sync, data))
+    }
+
+    fn avcc_config(&self) -> Result<Vec<u8>> {
+        let (Some(sps), Some(pps)) = (&self.sps, &self.pps) else {
+            bail!("mp4 muxer needs at least one SPS/PPS pair before writing the header");
+        };
+        Ok(write_avcc(sps, pps))
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let avcc = self.avcc_config()?;
+        let sps = self.sps.as_ref().expect("avcc_config already checked sps is present");
+        let mut out = Vec::new();
+        write_ftyp(&mut out, self.format, sps, self.width, self.height);
+        write_moov_fragmented(
+            &mut out,
+            self.framerate,
+            self.width,
+            self.height,
+            self.track_id,
+            &avcc,
+            self.audio.as_ref().map(|a| (self.audio_track_id, a)),
+        );
+        self.file.write_all(&out)?;
+        self.header_written = true;
+        Ok(())
+    }
+
+    /// Write out the currently-buffered fragment. `next_fragment_start` is
+    /// the real timestamp of the sample that triggered this flush (i.e. the
+    /// new fragment's first sample), needed to derive this fragment's last
+    /// sample's duration; `None` at end-of-stream, when there isn't one.
+    fn flush_fragment(&mut self, next_fragment_start: Option<u64>) -> Result<()> {
+        fill_durations_from_timestamps(&mut self.fragment_samples, &self.fragment_timestamps, next_fragment_start);
+
+        let mut out = Vec::new();
+        self.sequence_number += 1;
+
+        let mut tracks = Vec::with_capacity(2);
+        tracks.push(TrackFragment {
+            track_id: self.track_id,
+            base_decode_time: self.fragment_base_dts,
+            samples: &self.fragment_samples,
+            data: &self.fragment_data,
+        });
+        if self.audio.is_some() {
+            tracks.push(TrackFragment {
+                track_id: self.audio_track_id,
+                base_decode_time: self.audio_fragment_base_dts,
+                samples: &self.audio_fragment_samples,
+                data: &self.audio_fragment_data,
+            });
+        }
+        write_moof_and_mdat(&mut out, self.sequence_number, &tracks);
+
+        self.file.write_all(&out)?;
+        self.fragment_samples.clear();
+        self.fragment_data.clear();
+        self.fragment_timestamps.clear();
+        self.audio_fragment_samples.clear();
+        self.audio_fragment_data.clear();
+        Ok(())
+    }
+
+    fn write_whole_movie(&mut self) -> Result<()> {
+        fill_durations_from_timestamps(&mut self.all_samples, &self.all_timestamps, None);
+
+        let avcc = self.avcc_config()?;
+        let sps = self.sps.as_ref().expect("avcc_config already checked sps is present");
+        let mut out = Vec::new();
+        write_ftyp(&mut out, self.format, sps, self.width, self.height);
+
+        // mdat comes before moov so the whole file can be written in one
+        // pass; the moov's stco chunk offsets just point past ftyp+mdat's
+        // header, with the audio data (if any) laid out right after the
+        // video data inside the same mdat.
+        let mdat_start = out.len();
+        let video_chunk_offset = (mdat_start + 8) as u32;
+        let audio_chunk_offset = video_chunk_offset + self.all_data.len() as u32;
+        write_box(&mut out, b"mdat", |out| {
+            out.extend_from_slice(&self.all_data);
+            out.extend_from_slice(&self.audio_all_data);
+        });
+
+        write_moov_whole(
+            &mut out,
+            self.framerate,
+            self.width,
+            self.height,
+            self.track_id,
+            &avcc,
+            &self.all_samples,
+            video_chunk_offset,
+            self.audio
+                .as_ref()
+                .map(|a| (self.audio_track_id, a, &self.audio_all_samples, audio_chunk_offset)),
+        );
+        self.file.write_all(&out)?;
+        Ok(())
+    }
+}
+
+/// Fill in each sample's `duration` as the delta to the timestamp of the
+/// sample right after it, so variable capture timing survives into the
+/// container instead of being flattened to one tick per sample. `samples`
+/// and `timestamps` are parallel (same push order); `trailing_timestamp` is
+/// the timestamp of whatever comes after the last sample here -- the frame
+/// that triggered this flush, or `None` at end-of-stream, when the last
+/// sample's duration is instead extrapolated from the previous one.
+fn fill_durations_from_timestamps(samples: &mut [PendingSample], timestamps: &[u64], trailing_timestamp: Option<u64>) {
+    for i in 0..samples.len() {
+        let next_timestamp = timestamps.get(i + 1).copied().or(trailing_timestamp);
+        samples[i].duration = match next_timestamp {
+            Some(next) => next.saturating_sub(timestamps[i]).max(1) as u32,
+            None if i > 0 => samples[i - 1].duration,
+            None => 1,
+        };
+    }
+}
+
+/// Writes `fourcc`'s box body through `body`, then back-patches the 32-bit
+/// size field once the body's length is known.
+fn write_box(out: &mut Vec<u8>, fourcc: &[u8; 4], body: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0, 0, 0, 0]); // size placeholder
+    out.extend_from_slice(fourcc);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Like [`write_box`], but for a `FullBox`: writes the `version`/`flags`
+/// word ahead of `body`.
+fn write_full_box(out: &mut Vec<u8>, fourcc: &[u8; 4], version: u8, flags: u32, body: impl FnOnce(&mut Vec<u8>)) {
+    write_box(out, fourcc, |out| {
+        let version_and_flags = ((version as u32) << 24) | (flags & 0x00ff_ffff);
+        out.extend_from_slice(&version_and_flags.to_be_bytes());
+        body(out);
+    });
+}
+
+/// `profile_idc` (byte 1) and `level_idc` (byte 3) out of a raw SPS NAL
+/// (NAL header byte included, as stored in [`Mp4Muxer::sps`]), per H.264
+/// Annex A's SPS byte layout.
+fn sps_profile_level(sps: &[u8]) -> (u8, u8) {
+    (sps.get(1).copied().unwrap_or(0), sps.get(3).copied().unwrap_or(0))
+}
+
+/// `mp41`/`iso6`/`avc1` are always true regardless of what's inside; `cmfc`
+/// (CMAF) additionally promises the stricter Fmp4 constraints CMAF media
+/// profiles require, so it's only added for [`OutputFormat::Fmp4`], and only
+/// when the SPS is actually within the profile/level/resolution CMAF's AVC
+/// media profile allows (Baseline/Main/High, up to level 5.1, no more than
+/// 4096 pixels wide/tall).
+fn write_ftyp(out: &mut Vec<u8>, format: OutputFormat, sps: &[u8], width: u32, height: u32) {
+    let (profile_idc, level_idc) = sps_profile_level(sps);
+    let cmaf_compatible = matches!(profile_idc, 66 | 77 | 100)
+        && level_idc <= 51
+        && width <= 4096
+        && height <= 4096;
+
+    write_box(out, b"ftyp", |out| {
+        out.extend_from_slice(b"isom");
+        out.extend_from_slice(&0u32.to_be_bytes()); // minor_version
+        out.extend_from_slice(b"isom");
+        out.extend_from_slice(b"iso6");
+        out.extend_from_slice(b"avc1");
+        out.extend_from_slice(b"mp41");
+        if format == OutputFormat::Fmp4 && cmaf_compatible {
+            out.extend_from_slice(b"cmfc");
+        }
+    });
+}
+
+/// `avcC` configuration record: `AVCDecoderConfigurationRecord`, built from
+/// one SPS and one PPS NAL (NAL header byte included, no start code).
+fn write_avcc(sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    let mut avcc = Vec::with_capacity(11 + sps.len() + pps.len());
+    avcc.push(1); // configurationVersion
+    avcc.push(sps[1]); // AVCProfileIndication
+    avcc.push(sps[2]); // profile_compatibility
+    avcc.push(sps[3]); // AVCLevelIndication
+    avcc.push(0xfc | 0b11); // reserved(6) + lengthSizeMinusOne=3 (4-byte NAL lengths)
+    avcc.push(0xe0 | 1); // reserved(3) + numOfSequenceParameterSets=1
+    avcc.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    avcc.extend_from_slice(sps);
+    avcc.push(1); // numOfPictureParameterSets
+    avcc.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    avcc.extend_from_slice(pps);
+    avcc
+}
+
+fn write_stsd(out: &mut Vec<u8>, width: u32, height: u32, avcc: &[u8]) {
+    write_full_box(out, b"stsd", 0, 0, |out| {
+        out.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+        write_box(out, b"avc1", |out| {
+            out.extend_from_slice(&[0; 6]); // reserved
+            out.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+            out.extend_from_slice(&[0; 16]); // pre_defined/reserved
+            out.extend_from_slice(&(width as u16).to_be_bytes());
+            out.extend_from_slice(&(height as u16).to_be_bytes());
+            out.extend_from_slice(&0x0048_0000u32.to_be_bytes()); // horizresolution 72dpi
+            out.extend_from_slice(&0x0048_0000u32.to_be_bytes()); // vertresolution 72dpi
+            out.extend_from_slice(&0u32.to_be_bytes()); // reserved
+            out.extend_from_slice(&1u16.to_be_bytes()); // frame_count
+            out.extend_from_slice(&[0; 32]); // compressorname
+            out.extend_from_slice(&0x0018u16.to_be_bytes()); // depth = 24
+            out.extend_from_slice(&(-1i16).to_be_bytes()); // pre_defined
+            write_box(out, b"avcC", |out| out.extend_from_slice(avcc));
+        });
+    });
+}
+
+fn write_mvhd(out: &mut Vec<u8>, timescale: u32, duration: u32, next_track_id: u32) {
+    write_full_box(out, b"mvhd", 0, 0, |out| {
+        out.extend_from_slice(&0u32.to_be_bytes()); // creation_time
+        out.extend_from_slice(&0u32.to_be_bytes()); // modification_time
+        out.extend_from_slice(&timescale.to_be_bytes());
+        out.extend_from_slice(&duration.to_be_bytes());
+        out.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate 1.0
+        out.extend_from_slice(&0x0100u16.to_be_bytes()); // volume 1.0
+        out.extend_from_slice(&[0; 10]); // reserved
+        out.extend_from_slice(&unity_matrix());
+        out.extend_from_slice(&[0; 24]); // pre_defined
+        out.extend_from_slice(&next_track_id.to_be_bytes());
+    });
+}
+
+fn write_tkhd(out: &mut Vec<u8>, track_id: u32, duration: u32, width: u32, height: u32, volume: u16) {
+    // flags = track_enabled | track_in_movie
+    write_full_box(out, b"tkhd", 0, 0x000007, |out| {
+        out.extend_from_slice(&0u32.to_be_bytes()); // creation_time
+        out.extend_from_slice(&0u32.to_be_bytes()); // modification_time
+        out.extend_from_slice(&track_id.to_be_bytes());
+        out.extend_from_slice(&0u32.to_be_bytes()); // reserved
+        out.extend_from_slice(&duration.to_be_bytes());
+        out.extend_from_slice(&[0; 8]); // reserved
+        out.extend_from_slice(&0u16.to_be_bytes()); // layer
+        out.extend_from_slice(&0u16.to_be_bytes()); // alternate_group
+        out.extend_from_slice(&volume.to_be_bytes()); // volume: 0x0100 (1.0) for audio, 0 for video
+        out.extend_from_slice(&0u16.to_be_bytes()); // reserved
+        out.extend_from_slice(&unity_matrix());
+        out.extend_from_slice(&((width as u32) << 16).to_be_bytes()); // width, 16.16 fixed
+        out.extend_from_slice(&((height as u32) << 16).to_be_bytes()); // height, 16.16 fixed
+    });
+}
+
+fn write_mdhd(out: &mut Vec<u8>, timescale: u32, duration: u32) {
+    write_full_box(out, b"mdhd", 0, 0, |out| {
+        out.extend_from_slice(&0u32.to_be_bytes()); // creation_time
+        out.extend_from_slice(&0u32.to_be_bytes()); // modification_time
+        out.extend_from_slice(&timescale.to_be_bytes());
+        out.extend_from_slice(&duration.to_be_bytes());
+        out.extend_from_slice(&0x55c4u16.to_be_bytes()); // language = "und"
+        out.extend_from_slice(&0u16.to_be_bytes()); // pre_defined
+    });
+}
+
+fn write_hdlr(out: &mut Vec<u8>, handler_type: &[u8; 4], name: &[u8]) {
+    write_full_box(out, b"hdlr", 0, 0, |out| {
+        out.extend_from_slice(&0u32.to_be_bytes()); // pre_defined
+        out.extend_from_slice(handler_type);
+        out.extend_from_slice(&[0; 12]); // reserved
+        out.extend_from_slice(name);
+    });
+}
+
+fn write_dinf(out: &mut Vec<u8>) {
+    write_box(out, b"dinf", |out| {
+        write_full_box(out, b"dref", 0, 0, |out| {
+            out.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+            // flags = 1: media data is in the same file as the movie box.
+            write_full_box(out, b"url ", 0, 1, |_out| {});
+        });
+    });
+}
+
+fn write_vmhd(out: &mut Vec<u8>) {
+    write_full_box(out, b"vmhd", 0, 1, |out| {
+        out.extend_from_slice(&0u16.to_be_bytes()); // graphicsmode
+        out.extend_from_slice(&[0u8; 6]); // opcolor
+    });
+}
+
+fn write_smhd(out: &mut Vec<u8>) {
+    write_full_box(out, b"smhd", 0, 0, |out| {
+        out.extend_from_slice(&0i16.to_be_bytes()); // balance: centered
+        out.extend_from_slice(&0u16.to_be_bytes()); // reserved
+    });
+}
+
+fn write_mvex(out: &mut Vec<u8>, track_ids: &[u32]) {
+    write_box(out, b"mvex", |out| {
+        for &track_id in track_ids {
+            write_full_box(out, b"trex", 0, 0, |out| {
+                out.extend_from_slice(&track_id.to_be_bytes());
+                out.extend_from_slice(&1u32.to_be_bytes()); // default_sample_description_index
+                out.extend_from_slice(&0u32.to_be_bytes()); // default_sample_duration
+                out.extend_from_slice(&0u32.to_be_bytes()); // default_sample_size
+                // sample_depends_on = 1, is_non_sync_sample = 1: every trun
+                // below writes explicit per-sample flags
+                // (sample-flags-present), so this default is never actually
+                // consulted, but it should still read as "not a sync sample"
+                // rather than the reverse in case that ever changes.
+                out.extend_from_slice(&0x0101_0000u32.to_be_bytes()); // default_sample_flags
+            });
+        }
+    });
+}
+
+/// Empty `stts`/`stsc`/`stsz`/`stco`: everything lives in per-GOP fragments,
+/// so the up-front `moov` just needs placeholders satisfying `stbl`'s
+/// required-box list.
+fn write_empty_sample_tables(out: &mut Vec<u8>) {
+    write_full_box(out, b"stts", 0, 0, |out| out.extend_from_slice(&0u32.to_be_bytes()));
+    write_full_box(out, b"stsc", 0, 0, |out| out.extend_from_slice(&0u32.to_be_bytes()));
+    write_full_box(out, b"stsz", 0, 0, |out| {
+        out.extend_from_slice(&0u32.to_be_bytes()); // sample_size
+        out.extend_from_slice(&0u32.to_be_bytes()); // sample_count
+    });
+    write_full_box(out, b"stco", 0, 0, |out| out.extend_from_slice(&0u32.to_be_bytes()));
+}
+
+/// MPEG-4 `Descriptor`: like [`write_box`], but tagged and size-prefixed
+/// with the base-128 varint `esds` descriptors use instead of a 32-bit size.
+fn write_descriptor(out: &mut Vec<u8>, tag: u8, body: impl FnOnce(&mut Vec<u8>)) {
+    out.push(tag);
+    let mut buf = Vec::new();
+    body(&mut buf);
+    write_descriptor_size(out, buf.len());
+    out.extend_from_slice(&buf);
+}
+
+fn write_descriptor_size(out: &mut Vec<u8>, size: usize) {
+    let mut digits = Vec::new();
+    let mut size = size as u32;
+    loop {
+        digits.push((size & 0x7f) as u8);
+        size >>= 7;
+        if size == 0 {
+            break;
+        }
+    }
+    let last = digits.len() - 1;
+    for (i, &digit) in digits.iter().rev().enumerate() {
+        out.push(if i == last { digit } else { digit | 0x80 });
+    }
+}
+
+/// `esds`: wraps the AAC `AudioSpecificConfig` in the ES/DecoderConfig/SLConfig
+/// descriptor nesting `mp4a` needs, mirroring how `avcC` wraps SPS/PPS.
+fn write_esds(out: &mut Vec<u8>, asc: &[u8], avg_bitrate: u32) {
+    write_full_box(out, b"esds", 0, 0, |out| {
+        write_descriptor(out, 0x03, |out| {
+            // ES_Descriptor
+            out.extend_from_slice(&1u16.to_be_bytes()); // ES_ID
+            out.push(0); // streamDependenceFlag/URL_Flag/OCRstreamFlag/streamPriority, all 0
+            write_descriptor(out, 0x04, |out| {
+                // DecoderConfigDescriptor
+                out.push(0x40); // objectTypeIndication: MPEG-4 Audio (AAC)
+                out.push(0x15); // streamType=5 (AudioStream)<<2 | upStream=0<<1 | reserved=1
+                out.extend_from_slice(&[0, 0, 0]); // bufferSizeDB
+                out.extend_from_slice(&avg_bitrate.to_be_bytes()); // maxBitrate
+                out.extend_from_slice(&avg_bitrate.to_be_bytes()); // avgBitrate
+                write_descriptor(out, 0x05, |out| out.extend_from_slice(asc)); // DecoderSpecificInfo: raw ASC
+            });
+            write_descriptor(out, 0x06, |out| out.push(0x02)); // SLConfigDescriptor, predefined
+        });
+    });
+}
+
+fn write_stsd_audio(out: &mut Vec<u8>, audio: &AudioConfig) {
+    write_full_box(out, b"stsd", 0, 0, |out| {
+        out.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+        write_box(out, b"mp4a", |out| {
+            out.extend_from_slice(&[0; 6]); // reserved
+            out.extend_from_slice(&1u16.to_be_bytes()); // data_reference_index
+            out.extend_from_slice(&[0; 8]); // reserved (version/revision/vendor)
+            out.extend_from_slice(&audio.channels.to_be_bytes());
+            out.extend_from_slice(&16u16.to_be_bytes()); // samplesize
+            out.extend_from_slice(&0u16.to_be_bytes()); // pre_defined
+            out.extend_from_slice(&0u16.to_be_bytes()); // reserved
+            out.extend_from_slice(&(audio.sample_rate << 16).to_be_bytes()); // samplerate, 16.16 fixed
+            write_esds(out, &audio.asc, 128_000);
+        });
+    });
+}
+
+fn unity_matrix() -> [u8; 36] {
+    let mut m = [0u8; 36];
+    m[0..4].copy_from_slice(&0x0001_0000u32.to_be_bytes()); // a = 1.0
+    m[16..20].copy_from_slice(&0x0001_0000u32.to_be_bytes()); // d = 1.0
+    m[32..36].copy_from_slice(&0x4000_0000u32.to_be_bytes()); // w = 1.0
+    m
+}
+
+/// `moov` with an empty sample table (everything lives in per-GOP
+/// `moof`/`mdat` fragments) plus `mvex`/`trex`, written once up front.
+/// `audio`, if present, adds a second (sound) `trak` alongside the video one.
+fn write_moov_fragmented(
+    out: &mut Vec<u8>,
+    timescale: u32,
+    width: u32,
+    height: u32,
+    track_id: u32,
+    avcc: &[u8],
+    audio: Option<(u32, &AudioConfig)>,
+) {
+    write_box(out, b"moov", |out| {
+        let next_track_id = audio.map_or(track_id, |(id, _)| id) + 1;
+        write_mvhd(out, timescale, 0, next_track_id);
+        write_box(out, b"trak", |out| {
+            write_tkhd(out, track_id, 0, width, height, 0);
+            write_box(out, b"mdia", |out| {
+                write_mdhd(out, timescale, 0);
+                write_hdlr(out, b"vide", b"VideoHandler\0");
+                write_box(out, b"minf", |out| {
+                    write_vmhd(out);
+                    write_dinf(out);
+                    write_box(out, b"stbl", |out| {
+                        write_stsd(out, width, height, avcc);
+                        write_empty_sample_tables(out);
+                    });
+                });
+            });
+        });
+        if let Some((audio_track_id, audio)) = audio {
+            write_box(out, b"trak", |out| {
+                write_tkhd(out, audio_track_id, 0, 0, 0, 0x0100);
+                write_box(out, b"mdia", |out| {
+                    write_mdhd(out, audio.sample_rate, 0);
+                    write_hdlr(out, b"soun", b"SoundHandler\0");
+                    write_box(out, b"minf", |out| {
+                        write_smhd(out);
+                        write_dinf(out);
+                        write_box(out, b"stbl", |out| {
+                            write_stsd_audio(out, audio);
+                            write_empty_sample_tables(out);
+                        });
+                    });
+                });
+            });
+        }
+        let mut track_ids = vec![track_id];
+        if let Some((audio_track_id, _)) = audio {
+            track_ids.push(audio_track_id);
+        }
+        write_mvex(out, &track_ids);
+    });
+}
+
+/// `moov` for a single, non-fragmented file: a full sample table describing
+/// every sample in the one `mdat` chunk at `chunk_offset`. `audio`, if
+/// present, is `(track_id, config, samples, chunk_offset)` for a second
+/// (sound) `trak`.
+fn write_moov_whole(
+    out: &mut Vec<u8>,
+    timescale: u32,
+    width: u32,
+    height: u32,
+    track_id: u32,
+    avcc: &[u8],
+    samples: &[PendingSample],
+    chunk_offset: u32,
+    audio: Option<(u32, &AudioConfig, &[PendingSample], u32)>,
+) {
+    let duration: u32 = samples.iter().map(|s| s.duration).sum();
+    write_box(out, b"moov", |out| {
+        let next_track_id = audio.map_or(track_id, |(id, ..)| id) + 1;
+        write_mvhd(out, timescale, duration, next_track_id);
+        write_box(out, b"trak", |out| {
+            write_tkhd(out, track_id, duration, width, height, 0);
+            write_box(out, b"mdia", |out| {
+                write_mdhd(out, timescale, duration);
+                write_hdlr(out, b"vide", b"VideoHandler\0");
+                write_box(out, b"minf", |out| {
+                    write_vmhd(out);
+                    write_dinf(out);
+                    write_box(out, b"stbl", |out| {
+                        write_stsd(out, width, height, avcc);
+                        write_stts(out, samples);
+                        write_stss(out, samples);
+                        write_stsc_stsz_stco_single_chunk(out, samples, chunk_offset);
+                    });
+                });
+            });
+        });
+        if let Some((audio_track_id, audio, audio_samples, audio_chunk_offset)) = audio {
+            let audio_duration: u32 = audio_samples.iter().map(|s| s.duration).sum();
+            write_box(out, b"trak", |out| {
+                write_tkhd(out, audio_track_id, audio_duration, 0, 0, 0x0100);
+                write_box(out, b"mdia", |out| {
+                    write_mdhd(out, audio.sample_rate, audio_duration);
+                    write_hdlr(out, b"soun", b"SoundHandler\0");
+                    write_box(out, b"minf", |out| {
+                        write_smhd(out);
+                        write_dinf(out);
+                        write_box(out, b"stbl", |out| {
+                            write_stsd_audio(out, audio);
+                            write_stts(out, audio_samples);
+                            // No stss: every AAC access unit here is
+                            // independently decodable, and an absent
+                            // sync-sample table already means "all samples
+                            // are sync samples".
+                            write_stsc_stsz_stco_single_chunk(out, audio_samples, audio_chunk_offset);
+                        });
+                    });
+                });
+            });
+        }
+    });
+}
+
+/// `stsc`/`stsz`/`stco` for the common case in [`write_moov_whole`]: every
+/// sample for a track lives in the single `mdat` chunk at `chunk_offset`, so
+/// `stsc` collapses to one entry and `stco` to one offset.
+fn write_stsc_stsz_stco_single_chunk(out: &mut Vec<u8>, samples: &[PendingSample], chunk_offset: u32) {
+    write_full_box(out, b"stsc", 0, 0, |out| {
+        out.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+        out.extend_from_slice(&1u32.to_be_bytes()); // first_chunk
+        out.extend_from_slice(&(samples.len() as u32).to_be_bytes()); // samples_per_chunk
+        out.extend_from_slice(&1u32.to_be_bytes()); // sample_description_index
+    });
+    write_full_box(out, b"stsz", 0, 0, |out| {
+        out.extend_from_slice(&0u32.to_be_bytes()); // sample_size = 0 => use the table below
+        out.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+        for sample in samples {
+            out.extend_from_slice(&sample.size.to_be_bytes());
+        }
+    });
+    write_full_box(out, b"stco", 0, 0, |out| {
+        out.extend_from_slice(&1u32.to_be_bytes()); // entry_count
+        out.extend_from_slice(&chunk_offset.to_be_bytes());
+    });
+}
+
+/// `stts`: run-length-encoded sample durations, one entry per run of
+/// consecutive samples with the same duration.
+fn write_stts(out: &mut Vec<u8>, samples: &[PendingSample]) {
+    write_full_box(out, b"stts", 0, 0, |out| {
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        for sample in samples {
+            match runs.last_mut() {
+                Some((count, duration)) if *duration == sample.duration => *count += 1,
+                _ => runs.push((1, sample.duration)),
+            }
+        }
+        out.extend_from_slice(&(runs.len() as u32).to_be_bytes()); // entry_count
+        for (count, duration) in runs {
+            out.extend_from_slice(&count.to_be_bytes());
+            out.extend_from_slice(&duration.to_be_bytes());
+        }
+    });
+}
+
+/// `stss`: sync-sample table, listing the (1-based) sample numbers that are
+/// keyframes. Leaving the box out entirely would mean "every sample is a
+/// sync sample", so we always write it and enumerate them explicitly.
+fn write_stss(out: &mut Vec<u8>, samples: &[PendingSample]) {
+    write_full_box(out, b"stss", 0, 0, |out| {
+        let sync_samples: Vec<u32> = samples
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.is_sync)
+            .map(|(i, _)| (i + 1) as u32)
+            .collect();
+        out.extend_from_slice(&(sync_samples.len() as u32).to_be_bytes());
+        for number in sync_samples {
+            out.extend_from_slice(&number.to_be_bytes());
+        }
+    });
+}
+
+/// One track's pending samples for a single `moof`+`mdat` fragment: what
+/// [`write_moof_and_mdat`] needs to write that track's `traf` and lay its
+/// bytes into the fragment's shared `mdat`.
+struct TrackFragment<'a> {
+    track_id: u32,
+    base_decode_time: u64,
+    samples: &'a [PendingSample],
+    data: &'a [u8],
+}
+
+/// One fragment: `moof` (with `mfhd` + one `traf` per track) followed
+/// immediately by the `mdat` holding every track's sample bytes back to
+/// back, video first then audio.
+fn write_moof_and_mdat(out: &mut Vec<u8>, sequence_number: u32, tracks: &[TrackFragment]) {
+    let moof_start = out.len();
+    let mut data_offset_positions = Vec::with_capacity(tracks.len());
+
+    write_box(out, b"moof", |out| {
+        write_full_box(out, b"mfhd", 0, 0, |out| {
+            out.extend_from_slice(&sequence_number.to_be_bytes());
+        });
+        for track in tracks {
+            write_box(out, b"traf", |out| {
+                // flags = default-base-is-moof: offsets in trun are relative to this moof.
+                write_full_box(out, b"tfhd", 0, 0x02_0000, |out| {
+                    out.extend_from_slice(&track.track_id.to_be_bytes());
+                });
+                write_full_box(out, b"tfdt", 1, 0, |out| {
+                    out.extend_from_slice(&track.base_decode_time.to_be_bytes());
+                });
+
+                // flags = data-offset-present | sample-duration-present
+                //       | sample-size-present | sample-flags-present
+                let flags = 0x00_0001 | 0x00_0100 | 0x00_0200 | 0x00_0400;
+                write_full_box(out, b"trun", 0, flags, |out| {
+                    out.extend_from_slice(&(track.samples.len() as u32).to_be_bytes());
+                    data_offset_positions.push(out.len());
+                    out.extend_from_slice(&0i32.to_be_bytes()); // data_offset, patched below
+
+                    // Every sample gets its own explicit flags rather than
+                    // leaning on trex's default plus a first-sample override:
+                    // a video fragment's first sample is always its keyframe
+                    // (that's what starts a new fragment) and every other
+                    // video sample a P-frame, but an audio fragment can hold
+                    // several samples that are *all* sync (every AAC access
+                    // unit is independently decodable), which a single
+                    // first-sample-only override can't represent correctly.
+                    for sample in track.samples {
+                        out.extend_from_slice(&sample.duration.to_be_bytes());
+                        out.extend_from_slice(&sample.size.to_be_bytes());
+                        let sample_flags: u32 = if sample.is_sync {
+                            0x0200_0000 // sample_depends_on = 2 (I-frame), is_non_sync_sample = 0
+                        } else {
+                            0x0101_0000 // sample_depends_on = 1, is_non_sync_sample = 1
+                        };
+                        out.extend_from_slice(&sample_flags.to_be_bytes());
+                    }
+                });
+            });
+        }
+    });
+
+    // Each track's trun data_offset is relative to this moof's start
+    // (default-base-is-moof); the tracks' bytes are laid out one after
+    // another in the mdat that follows, in the same order as `tracks`.
+    let moof_size = out.len() - moof_start;
+    let mut data_offset = (moof_size + 8) as i32; // +8: this fragment's mdat box header
+    for (track, &pos) in tracks.iter().zip(&data_offset_positions) {
+        out[pos..pos + 4].copy_from_slice(&data_offset.to_be_bytes());
+        data_offset += track.data.len() as i32;
+    }
+
+    write_box(out, b"mdat", |out| {
+        for track in tracks {
+            out.extend_from_slice(track.data);
+        }
+    });
+}
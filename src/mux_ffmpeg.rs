@@ -0,0 +1,298 @@
+//! Fragmented-MP4 muxer (MP4 is the only container it writes; there is no
+//! MPEG-TS output) for [`crate::encode_ffmpeg::Encoder`]'s coded
+//! packets, optionally alongside [`crate::audio::AudioEncoder`]'s AAC ones,
+//! built on an `AVFormatContext` fed by a custom `AVIOContext` instead of
+//! one FFmpeg opens from a path -- so the sink can be any `Write + Seek` (a
+//! file, a socket wrapper, an in-memory buffer).
+//!
+//! Follows the usual custom-AVIO recipe: allocate the AVIO buffer with
+//! `av_malloc`, hand `avio_alloc_context` a pair of `write_packet`/`seek`
+//! trampolines that recover the sink through `opaque`, and free both the
+//! buffer and the context on drop. Fragmented MP4 is requested via the
+//! `movflags=frag_keyframe+empty_moov+default_base_moof` muxer option so the
+//! output is streamable/seekable without buffering the whole capture.
+
+use std::{
+    ffi::{c_void, CString},
+    io::{Seek, SeekFrom, Write},
+    os::raw::{c_int, c_longlong},
+    ptr,
+};
+
+use anyhow::{bail, Context, Result};
+use rsmpeg::{
+    avcodec::{AVCodecContext, AVPacket},
+    ffi,
+};
+
+const AVIO_BUFFER_SIZE: usize = 4096;
+
+/// A sink the muxer can write into: anything seekable (fragmented MP4 still
+/// back-patches a handful of offsets even in streaming mode) and writable.
+pub trait MuxSink: Write + Seek {}
+impl<T: Write + Seek> MuxSink for T {}
+
+/// State recovered from the `AVIOContext`'s `opaque` pointer by the
+/// `write_packet`/`seek` trampolines below.
+struct AvioUserData {
+    sink: Box<dyn MuxSink>,
+}
+
+unsafe extern "C" fn write_packet_trampoline(
+    opaque: *mut c_void,
+    buf: *const u8,
+    buf_size: c_int,
+) -> c_int {
+    let user_data = unsafe { &mut *(opaque as *mut AvioUserData) };
+    let data = unsafe { std::slice::from_raw_parts(buf, buf_size as usize) };
+    match user_data.sink.write_all(data) {
+        Ok(()) => buf_size,
+        Err(_) => ffi::AVERROR_EXTERNAL,
+    }
+}
+
+unsafe extern "C" fn seek_trampoline(opaque: *mut c_void, offset: c_longlong, whence: c_int) -> c_longlong {
+    let user_data = unsafe { &mut *(opaque as *mut AvioUserData) };
+    if whence & ffi::AVSEEK_SIZE as c_int != 0 {
+        // This sink (a streamed file or socket wrapper) has no fixed size
+        // to report; FFmpeg treats a negative return as "unknown".
+        return -1;
+    }
+    let from = match whence {
+        0 /* SEEK_SET */ => SeekFrom::Start(offset as u64),
+        1 /* SEEK_CUR */ => SeekFrom::Current(offset),
+        2 /* SEEK_END */ => SeekFrom::End(offset),
+        _ => return -1,
+    };
+    match user_data.sink.seek(from) {
+        Ok(pos) => pos as c_longlong,
+        Err(_) => -1,
+    }
+}
+
+/// Muxes one H.264 video track (sourced from [`crate::encode_ffmpeg::Encoder`])
+/// and, optionally, one AAC audio track into fragmented MP4, writing through
+/// a caller-supplied [`MuxSink`].
+pub struct Muxer {
+    fmt_ctx: *mut ffi::AVFormatContext,
+    avio_ctx: *mut ffi::AVIOContext,
+    avio_buffer: *mut u8,
+    // Leaked into `avio_ctx->opaque`; reclaimed in `Drop`.
+    user_data: *mut AvioUserData,
+    video_stream_index: c_int,
+    encoder_time_base: ffi::AVRational,
+    audio_stream_index: Option<c_int>,
+    audio_time_base: Option<ffi::AVRational>,
+    header_written: bool,
+}
+
+impl Muxer {
+    /// Open an fMP4 muxer writing into `sink`, with its video stream's codec
+    /// parameters copied from `video_avctx` (must already be opened, so
+    /// `extradata`/`extradata_size` -- the `avcC` box contents -- are set).
+    /// `audio_avctx`, if given, adds a second stream (e.g. AAC from
+    /// [`crate::audio::AudioEncoder`]) so [`Self::write_audio_packet`] can
+    /// interleave it alongside the video track.
+    pub fn new(
+        sink: Box<dyn MuxSink>,
+        video_avctx: &AVCodecContext,
+        audio_avctx: Option<&AVCodecContext>,
+    ) -> Result<Self> {
+        let avctx = video_avctx;
+        let user_data = Box::into_raw(Box::new(AvioUserData { sink }));
+
+        let avio_buffer = unsafe { ffi::av_malloc(AVIO_BUFFER_SIZE) as *mut u8 };
+        if avio_buffer.is_null() {
+            unsafe { drop(Box::from_raw(user_data)) };
+            bail!("Failed to allocate AVIO buffer");
+        }
+
+        let avio_ctx = unsafe {
+            ffi::avio_alloc_context(
+                avio_buffer,
+                AVIO_BUFFER_SIZE as c_int,
+                1, // write_flag
+                user_data as *mut c_void,
+                None,
+                Some(write_packet_trampoline),
+                Some(seek_trampoline),
+            )
+        };
+        if avio_ctx.is_null() {
+            unsafe {
+                ffi::av_free(avio_buffer as *mut c_void);
+                drop(Box::from_raw(user_data));
+            }
+            bail!("Failed to allocate AVIO context");
+        }
+
+        let format_name = CString::new("mp4").unwrap();
+        let mut fmt_ctx: *mut ffi::AVFormatContext = ptr::null_mut();
+        let ret = unsafe {
+            ffi::avformat_alloc_output_context2(
+                &mut fmt_ctx,
+                ptr::null_mut(),
+                format_name.as_ptr(),
+                ptr::null(),
+            )
+        };
+        if ret < 0 || fmt_ctx.is_null() {
+            unsafe {
+                ffi::avio_context_free(&mut (avio_ctx as *mut ffi::AVIOContext));
+                ffi::av_free(avio_buffer as *mut c_void);
+                drop(Box::from_raw(user_data));
+            }
+            bail!("Failed to allocate output format context: {ret}");
+        }
+
+        unsafe {
+            (*fmt_ctx).pb = avio_ctx;
+            (*fmt_ctx).flags |= ffi::AVFMT_FLAG_CUSTOM_IO as i32;
+        }
+
+        let stream = unsafe { ffi::avformat_new_stream(fmt_ctx, ptr::null()) };
+        if stream.is_null() {
+            unsafe { ffi::avformat_free_context(fmt_ctx) };
+            bail!("Failed to create output video stream");
+        }
+        let video_stream_index = unsafe { (*stream).index };
+
+        let ret =
+            unsafe { ffi::avcodec_parameters_from_context((*stream).codecpar, avctx.as_ptr()) };
+        if ret < 0 {
+            unsafe { ffi::avformat_free_context(fmt_ctx) };
+            bail!("Failed to copy codec parameters to output stream: {ret}");
+        }
+        unsafe {
+            (*stream).time_base = avctx.time_base;
+        }
+
+        let mut audio_stream_index = None;
+        let mut audio_time_base = None;
+        if let Some(audio_avctx) = audio_avctx {
+            let audio_stream = unsafe { ffi::avformat_new_stream(fmt_ctx, ptr::null()) };
+            if audio_stream.is_null() {
+                unsafe { ffi::avformat_free_context(fmt_ctx) };
+                bail!("Failed to create output audio stream");
+            }
+            let ret = unsafe {
+                ffi::avcodec_parameters_from_context(
+                    (*audio_stream).codecpar,
+                    audio_avctx.as_ptr(),
+                )
+            };
+            if ret < 0 {
+                unsafe { ffi::avformat_free_context(fmt_ctx) };
+                bail!("Failed to copy audio codec parameters to output stream: {ret}");
+            }
+            unsafe {
+                (*audio_stream).time_base = audio_avctx.time_base;
+            }
+            audio_stream_index = Some(unsafe { (*audio_stream).index });
+            audio_time_base = Some(audio_avctx.time_base);
+        }
+
+        Ok(Self {
+            fmt_ctx,
+            avio_ctx,
+            avio_buffer,
+            user_data,
+            video_stream_index,
+            encoder_time_base: avctx.time_base,
+            audio_stream_index,
+            audio_time_base,
+            header_written: false,
+        })
+    }
+
+    /// Write `avformat_write_header` with the fragmented-MP4 `movflags`,
+    /// called lazily before the first packet so stream setup above has
+    /// already run.
+    fn ensure_header_written(&mut self) -> Result<()> {
+        if self.header_written {
+            return Ok(());
+        }
+        let movflags_key = CString::new("movflags").unwrap();
+        let movflags_value = CString::new("frag_keyframe+empty_moov+default_base_moof").unwrap();
+        let mut opts: *mut ffi::AVDictionary = ptr::null_mut();
+        unsafe {
+            ffi::av_dict_set(&mut opts, movflags_key.as_ptr(), movflags_value.as_ptr(), 0);
+        }
+        let ret = unsafe { ffi::avformat_write_header(self.fmt_ctx, &mut opts) };
+        unsafe { ffi::av_dict_free(&mut opts) };
+        if ret < 0 {
+            bail!("Failed to write fMP4 header: {ret}");
+        }
+        self.header_written = true;
+        Ok(())
+    }
+
+    /// Rescale `packet`'s pts/dts from the encoder's time base to the output
+    /// stream's, tag it with the video stream index, and hand it to
+    /// `av_interleaved_write_frame`, which takes ownership of its buffer.
+    pub fn write_video_packet(&mut self, packet: &mut AVPacket) -> Result<()> {
+        self.ensure_header_written()?;
+        let stream_time_base =
+            unsafe { (*(*self.fmt_ctx).streams.offset(self.video_stream_index as isize)).time_base };
+        let raw_packet = unsafe { &mut *packet.as_mut_ptr() };
+        unsafe {
+            ffi::av_packet_rescale_ts(raw_packet, self.encoder_time_base, stream_time_base);
+        }
+        raw_packet.stream_index = self.video_stream_index;
+        let ret = unsafe { ffi::av_interleaved_write_frame(self.fmt_ctx, raw_packet) };
+        if ret < 0 {
+            bail!("Failed to mux video packet: {ret}");
+        }
+        Ok(())
+    }
+
+    /// Same as [`Self::write_video_packet`], but for a coded audio packet
+    /// (e.g. AAC from [`crate::audio::AudioEncoder`]), tagged with the audio
+    /// stream this muxer was opened with. Fails if `audio_avctx` wasn't
+    /// given to [`Self::new`].
+    pub fn write_audio_packet(&mut self, packet: &mut AVPacket) -> Result<()> {
+        self.ensure_header_written()?;
+        let Some(stream_index) = self.audio_stream_index else {
+            bail!("Muxer was opened without an audio stream");
+        };
+        let stream_time_base =
+            unsafe { (*(*self.fmt_ctx).streams.offset(stream_index as isize)).time_base };
+        let raw_packet = unsafe { &mut *packet.as_mut_ptr() };
+        unsafe {
+            ffi::av_packet_rescale_ts(raw_packet, self.audio_time_base.unwrap(), stream_time_base);
+        }
+        raw_packet.stream_index = stream_index;
+        let ret = unsafe { ffi::av_interleaved_write_frame(self.fmt_ctx, raw_packet) };
+        if ret < 0 {
+            bail!("Failed to mux audio packet: {ret}");
+        }
+        Ok(())
+    }
+
+    /// Flush any buffered fragments and write the trailer -- the moov/mfra
+    /// bookkeeping fMP4 still needs even though every sample was already
+    /// streamed out in its own fragment.
+    pub fn finish(&mut self) -> Result<()> {
+        self.ensure_header_written()
+            .context("Failed to write header before finishing with no packets muxed")?;
+        let ret = unsafe { ffi::av_write_trailer(self.fmt_ctx) };
+        if ret < 0 {
+            bail!("Failed to write fMP4 trailer: {ret}");
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Muxer {
+    fn drop(&mut self) {
+        unsafe {
+            // `avformat_free_context` doesn't know about the custom `pb`, so
+            // detach it first and free the AVIO side separately.
+            (*self.fmt_ctx).pb = ptr::null_mut();
+            ffi::avformat_free_context(self.fmt_ctx);
+            ffi::avio_context_free(&mut self.avio_ctx);
+            ffi::av_free(self.avio_buffer as *mut c_void);
+            drop(Box::from_raw(self.user_data));
+        }
+    }
+}
@@ -0,0 +1,375 @@
+//! Channel-driven capture → encode → mux pipeline for the ffmpeg encoder
+//! track, replacing a single-slot frame buffer and synchronous `Encoder`
+//! calls on the capture thread with stages connected by a bounded channel --
+//! similar to the producer/stage design in zap-stream-core's pipeline
+//! refactor. A slow encode no longer stalls capture; instead the configured
+//! [`DropPolicy`] decides what happens when the channel fills up.
+//!
+//! There are two stages here: capture (the caller, via [`Pipeline::submit`]
+//! and, if audio is enabled, [`Pipeline::submit_audio_samples`]) and a
+//! combined encode+mux stage on its own thread, since the encoder and muxer
+//! are tightly coupled (the muxer needs the encoder's opened codec
+//! parameters to open its own stream). Audio, unlike video, is never
+//! dropped (an unbounded channel, regardless of [`DropPolicy`]) since a
+//! gap in an audio track is far more noticeable than an occasional skipped
+//! video frame; the encode+mux thread polls it on a short timeout between
+//! video frames so it gets interleaved into the container promptly even
+//! when video is idle. [`Pipeline::shutdown`] closes the channels so that
+//! thread flushes the encoders and writes the muxer trailer before exiting,
+//! and joins it.
+
+use std::{
+    sync::{
+        mpsc::{self, sync_channel, Receiver, RecvTimeoutError, SyncSender},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use crate::audio::{AudioEncoder, AudioEncoderSettings};
+use crate::capture::CapturedSurface;
+use crate::encode_ffmpeg::{Encoder, EncoderConfig, PtsMode};
+use crate::frame_buffer::FrameBuffer;
+use crate::mux_ffmpeg::{MuxSink, Muxer};
+
+/// How often the encode+mux thread checks the audio channel when no video
+/// frame has arrived in the meantime; small enough that audio packets don't
+/// visibly lag the video they're interleaved with.
+const AUDIO_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// What the capture stage does when the encode stage is behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Block [`Pipeline::submit`] until the encode stage catches up. Never
+    /// loses a frame, but a slow encode stalls capture.
+    Block,
+    /// Evict the oldest not-yet-encoded frame to make room for the one
+    /// being submitted, so capture always makes forward progress and the
+    /// encode stage's backlog never grows unbounded, at the cost of
+    /// dropped frames under load. Built on [`FrameBuffer`]'s ring buffer
+    /// rather than an `mpsc` channel, since a channel can only block or
+    /// reject the newest item -- it has no way to evict one already queued.
+    DropOldest,
+}
+
+#[derive(Clone)]
+struct CaptureFrame {
+    surface: Arc<CapturedSurface>,
+    pts_mode: PtsMode,
+}
+
+/// Pairs a [`Condvar`] with the `closed` flag it guards, so the encode
+/// thread can block on `frame_buffer` going from empty to non-empty (or the
+/// pipeline being shut down) instead of spin-polling it.
+struct RingNotify {
+    closed: Mutex<bool>,
+    not_empty: Condvar,
+}
+
+impl RingNotify {
+    fn new() -> Self {
+        Self {
+            closed: Mutex::new(false),
+            not_empty: Condvar::new(),
+        }
+    }
+
+    fn notify(&self) {
+        let _guard = self.closed.lock().unwrap();
+        self.not_empty.notify_one();
+    }
+
+    fn close(&self) {
+        *self.closed.lock().unwrap() = true;
+        self.not_empty.notify_one();
+    }
+}
+
+/// The capture-stage side of the transport to the encode+mux thread, one
+/// variant per [`DropPolicy`].
+enum FrameSender {
+    Blocking(SyncSender<CaptureFrame>),
+    Ring {
+        buffer: Arc<FrameBuffer<CaptureFrame>>,
+        notify: Arc<RingNotify>,
+    },
+}
+
+/// The encode-stage side of the transport, paired with the [`FrameSender`]
+/// variant [`Pipeline::new`] built.
+enum FrameReceiver {
+    Blocking(Receiver<CaptureFrame>),
+    Ring {
+        buffer: Arc<FrameBuffer<CaptureFrame>>,
+        notify: Arc<RingNotify>,
+    },
+}
+
+/// What [`FrameReceiver::recv_timeout`] found.
+enum RecvOutcome {
+    Frame(CaptureFrame),
+    /// Nothing arrived within the timeout; the pipeline is still open.
+    Timeout,
+    /// The transport was closed and fully drained.
+    Closed,
+}
+
+impl FrameReceiver {
+    /// Wait up to `timeout` for a frame, so the encode+mux thread can come
+    /// back and check the audio channel even while video is idle, instead
+    /// of blocking on video indefinitely.
+    fn recv_timeout(&self, timeout: Duration) -> RecvOutcome {
+        match self {
+            FrameReceiver::Blocking(rx) => match rx.recv_timeout(timeout) {
+                Ok(frame) => RecvOutcome::Frame(frame),
+                Err(RecvTimeoutError::Timeout) => RecvOutcome::Timeout,
+                Err(RecvTimeoutError::Disconnected) => RecvOutcome::Closed,
+            },
+            FrameReceiver::Ring { buffer, notify } => {
+                let guard = notify.closed.lock().unwrap();
+                if let Some(frame) = buffer.read() {
+                    return RecvOutcome::Frame((*frame).clone());
+                }
+                if *guard {
+                    return RecvOutcome::Closed;
+                }
+                let (guard, _timed_out) = notify.not_empty.wait_timeout(guard, timeout).unwrap();
+                if let Some(frame) = buffer.read() {
+                    return RecvOutcome::Frame((*frame).clone());
+                }
+                if *guard {
+                    return RecvOutcome::Closed;
+                }
+                RecvOutcome::Timeout
+            }
+        }
+    }
+}
+
+/// Handle to a running capture→encode→mux pipeline.
+pub struct Pipeline {
+    frame_tx: Option<FrameSender>,
+    // `None` unless `Pipeline::new` was given an `audio_config`; an
+    // unbounded `mpsc` sender regardless of `DropPolicy`, since dropping
+    // audio to relieve backpressure is far more audible than dropping video.
+    audio_tx: Option<mpsc::Sender<Vec<i16>>>,
+    dropped_frames: u64,
+    encode_mux_thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl Pipeline {
+    /// Spawn the encode+mux stage. `capacity` bounds how many not-yet-encoded
+    /// frames capture can get ahead by; `framerate`/`encoder_config` build
+    /// the [`Encoder`] lazily from the encode thread once the first captured
+    /// frame's resolution is known, same as the synchronous main loop did.
+    /// `audio_config`, if given, also builds an [`AudioEncoder`] up front
+    /// (it doesn't need a first frame to know its format) and adds an audio
+    /// stream to the [`Muxer`] once it opens -- feed it PCM via
+    /// [`Self::submit_audio_samples`].
+    pub fn new(
+        capacity: usize,
+        drop_policy: DropPolicy,
+        framerate: i32,
+        encoder_config: EncoderConfig,
+        audio_config: Option<AudioEncoderSettings>,
+        sink: Box<dyn MuxSink + Send>,
+    ) -> Result<Self> {
+        let (frame_tx, frame_rx) = match drop_policy {
+            DropPolicy::Block => {
+                let (tx, rx) = sync_channel::<CaptureFrame>(capacity);
+                (FrameSender::Blocking(tx), FrameReceiver::Blocking(rx))
+            }
+            DropPolicy::DropOldest => {
+                let buffer = Arc::new(FrameBuffer::new(capacity));
+                let notify = Arc::new(RingNotify::new());
+                (
+                    FrameSender::Ring { buffer: buffer.clone(), notify: notify.clone() },
+                    FrameReceiver::Ring { buffer, notify },
+                )
+            }
+        };
+        let (audio_tx, audio_rx, audio_encoder) = match audio_config {
+            Some(settings) => {
+                let (tx, rx) = mpsc::channel::<Vec<i16>>();
+                let encoder = AudioEncoder::new(settings).context("Failed to create audio encoder")?;
+                (Some(tx), Some(rx), Some(encoder))
+            }
+            None => (None, None, None),
+        };
+        let encode_mux_thread = thread::spawn(move || {
+            run_encode_and_mux(frame_rx, framerate, encoder_config, audio_rx, audio_encoder, sink)
+        });
+        Ok(Self {
+            frame_tx: Some(frame_tx),
+            audio_tx,
+            dropped_frames: 0,
+            encode_mux_thread: Some(encode_mux_thread),
+        })
+    }
+
+    /// Push a freshly captured surface into the pipeline, applying the
+    /// configured [`DropPolicy`] if the encode stage is behind.
+    pub fn submit(&mut self, surface: Arc<CapturedSurface>, pts_mode: PtsMode) {
+        let Some(frame_tx) = &self.frame_tx else {
+            return;
+        };
+        let frame = CaptureFrame { surface, pts_mode };
+        match frame_tx {
+            FrameSender::Blocking(tx) => {
+                // An error here means the encode/mux thread has already
+                // exited (e.g. it hit an encoder error); nothing left to do
+                // but let `shutdown`/`Drop` surface that.
+                let _ = tx.send(frame);
+            }
+            FrameSender::Ring { buffer, notify } => {
+                if buffer.is_full() {
+                    self.dropped_frames += 1;
+                    eprintln!(
+                        "Pipeline: encode stage behind, dropped the oldest queued frame ({} total)",
+                        self.dropped_frames
+                    );
+                }
+                buffer.write(Arc::new(frame));
+                notify.notify();
+            }
+        }
+    }
+
+    /// Push interleaved S16 PCM captured since the last call, for muxing
+    /// into the audio stream `audio_config` added to the output. A no-op if
+    /// this pipeline was built without `audio_config`.
+    pub fn submit_audio_samples(&self, samples: Vec<i16>) {
+        if let Some(audio_tx) = &self.audio_tx {
+            // Same reasoning as `FrameSender::Blocking::send` above: an
+            // error just means the encode/mux thread already exited.
+            let _ = audio_tx.send(samples);
+        }
+    }
+
+    /// Close the transport so the encode+mux thread drains whatever's still
+    /// in flight, flushes the encoder, writes the muxer trailer, and exits;
+    /// then join it and propagate any error it hit.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.close_transport();
+        self.encode_mux_thread
+            .take()
+            .unwrap()
+            .join()
+            .map_err(|_| anyhow!("Encode/mux thread panicked"))?
+    }
+
+    fn close_transport(&mut self) {
+        match self.frame_tx.take() {
+            Some(FrameSender::Blocking(tx)) => drop(tx),
+            Some(FrameSender::Ring { notify, .. }) => notify.close(),
+            None => {}
+        }
+        self.audio_tx.take();
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        // Same sequence as `shutdown`, but without a result to report --
+        // this only runs if the caller dropped the pipeline without calling
+        // `shutdown` themselves.
+        self.close_transport();
+        if let Some(handle) = self.encode_mux_thread.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run_encode_and_mux(
+    frame_rx: FrameReceiver,
+    framerate: i32,
+    encoder_config: EncoderConfig,
+    audio_rx: Option<mpsc::Receiver<Vec<i16>>>,
+    mut audio_encoder: Option<AudioEncoder>,
+    sink: Box<dyn MuxSink + Send>,
+) -> Result<()> {
+    let mut encoder: Option<Encoder> = None;
+    // Taken (via `.take()`) into the `Muxer` the moment the encoder opens
+    // and its codec parameters are available; `Muxer::new` wants to consume
+    // its sink exactly once, not borrow it.
+    let mut sink = Some(sink);
+    let mut muxer: Option<Muxer> = None;
+
+    loop {
+        match frame_rx.recv_timeout(AUDIO_POLL_INTERVAL) {
+            RecvOutcome::Frame(frame) => {
+                if encoder.is_none() {
+                    encoder = Some(
+                        Encoder::new(framerate, &frame.surface, encoder_config.clone())
+                            .context("Failed to create encoder")?,
+                    );
+                }
+                let enc = encoder.as_mut().unwrap();
+                enc.encode(frame.surface, frame.pts_mode)?;
+
+                if muxer.is_none() {
+                    let sink = sink.take().expect("encoder opens exactly once");
+                    let audio_avctx = audio_encoder.as_ref().map(AudioEncoder::avctx);
+                    muxer = Some(Muxer::new(sink, enc.avctx(), audio_avctx)?);
+                }
+                drain_video_packets(enc, muxer.as_mut().unwrap())?;
+            }
+            RecvOutcome::Timeout => {}
+            RecvOutcome::Closed => break,
+        }
+
+        if let (Some(audio_rx), Some(audio_enc)) = (&audio_rx, audio_encoder.as_mut()) {
+            while let Ok(samples) = audio_rx.try_recv() {
+                audio_enc.push_samples(&samples);
+            }
+            if let Some(mux) = muxer.as_mut() {
+                drain_audio_packets(audio_enc, mux)?;
+            }
+        }
+    }
+
+    // End of stream: flush whatever the encoder/audio encoder were still
+    // holding back (e.g. B-frame reordering, a short final AAC frame) and
+    // close out the container.
+    if let Some(enc) = encoder.as_mut() {
+        enc.signal_eof()?;
+        if let Some(mux) = muxer.as_mut() {
+            drain_video_packets(enc, mux)?;
+        }
+    }
+    if let (Some(audio_rx), Some(audio_enc)) = (&audio_rx, audio_encoder.as_mut()) {
+        // Pick up anything still sitting in the channel from just before
+        // the pipeline was closed -- the main loop only drains it once per
+        // `AUDIO_POLL_INTERVAL` tick, which may not run again after `break`.
+        while let Ok(samples) = audio_rx.try_recv() {
+            audio_enc.push_samples(&samples);
+        }
+    }
+    if let Some(audio_enc) = audio_encoder.as_mut() {
+        audio_enc.drain()?;
+        if let Some(mux) = muxer.as_mut() {
+            drain_audio_packets(audio_enc, mux)?;
+        }
+    }
+    if let Some(mux) = muxer.as_mut() {
+        mux.finish()?;
+    }
+
+    Ok(())
+}
+
+fn drain_video_packets(encoder: &mut Encoder, muxer: &mut Muxer) -> Result<()> {
+    while let Some(mut packet) = encoder.poll_packet()? {
+        muxer.write_video_packet(&mut packet)?;
+    }
+    Ok(())
+}
+
+fn drain_audio_packets(encoder: &mut AudioEncoder, muxer: &mut Muxer) -> Result<()> {
+    while let Some(mut packet) = encoder.poll_packet()? {
+        muxer.write_audio_packet(&mut packet)?;
+    }
+    Ok(())
+}
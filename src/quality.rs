@@ -0,0 +1,77 @@
+use cros_codecs::{
+    codec::h264::parser::{Level, Profile},
+    encoder::{h264::EncoderConfig, PredictionStructure, RateControl, Tunings},
+    Resolution,
+};
+
+/// A single 0-100 quality knob, in the spirit of msvideo1enc's
+/// `map_quality_*` helpers: mapped onto a constant-bitrate [`Tunings`]
+/// instead of making a caller hand-pick `min_quality`/`max_quality` and a
+/// bitrate directly. `0` means "use the encoder's own defaults".
+#[derive(Debug, Clone, Copy)]
+pub struct Quality(pub u8);
+
+/// Builds an [`EncoderConfig`] from a single [`Quality`] knob instead of a
+/// hand-picked `RateControl`/QP band. Named to read like the constructor
+/// `EncoderConfig::from_quality` this was asked for, but it can't actually
+/// be an inherent impl on `EncoderConfig` -- that type lives in
+/// `cros_codecs`, and Rust's orphan rule only allows inherent impls in the
+/// crate that defines the type.
+pub fn from_quality(
+    resolution: Resolution,
+    profile: Profile,
+    level: Level,
+    pred_structure: PredictionStructure,
+    framerate: u32,
+    quality: Quality,
+) -> EncoderConfig {
+    EncoderConfig {
+        resolution,
+        profile,
+        level,
+        pred_structure,
+        initial_tunings: quality_tunings(resolution, framerate, quality),
+    }
+}
+
+/// `quality.0 == 0` is the escape hatch back to the encoder's own defaults
+/// (unbounded QP band, a conservative fixed bitrate); anything else is
+/// bucketed into `q = min(quality / 10, 10)` and mapped onto a QP band that
+/// narrows -- and a bitrate that grows -- as `q` rises.
+fn quality_tunings(resolution: Resolution, framerate: u32, quality: Quality) -> Tunings {
+    let baseline_bitrate = baseline_bitrate(resolution, framerate);
+
+    let Quality(raw) = quality;
+    if raw == 0 {
+        return Tunings {
+            rate_control: RateControl::ConstantBitrate(baseline_bitrate),
+            framerate,
+            min_quality: 0,
+            max_quality: u32::MAX,
+        };
+    }
+
+    let q = (raw as u32 / 10).min(10);
+    // Center QP tightens from 51 (q=0) to 11 (q=10); the band half-width
+    // shrinks from 12 down to 2 over the same range, so low quality settings
+    // get a wide, bitrate-driven QP range and high ones get pinned close to
+    // a low QP.
+    let center_qp = 51 - q * 4;
+    let half_width = 12 - q;
+    let bitrate = baseline_bitrate * (q as u64 + 1) / 11;
+
+    Tunings {
+        rate_control: RateControl::ConstantBitrate(bitrate),
+        framerate,
+        min_quality: center_qp.saturating_sub(half_width),
+        max_quality: (center_qp + half_width).min(51),
+    }
+}
+
+/// A resolution x framerate "base" bitrate `quality_tunings` scales around:
+/// roughly 0.1 bits per pixel per frame, a conservative ballpark for
+/// screen-capture-style content with lots of flat, static regions.
+fn baseline_bitrate(resolution: Resolution, framerate: u32) -> u64 {
+    let pixels_per_second = resolution.width as u64 * resolution.height as u64 * framerate as u64;
+    pixels_per_second / 10
+}
@@ -1,7 +1,10 @@
 use anyhow::{bail, Result};
 use clap::Parser;
 use cros_codecs::{
-    backend::vaapi::surface_pool::VaSurfacePool,
+    backend::vaapi::{
+        encoder::VaapiBackend,
+        surface_pool::{PooledVaSurface, VaSurfacePool},
+    },
     codec::h264::parser::{Level, Profile},
     decoder::FramePool,
     encoder::{
@@ -15,31 +18,46 @@ use cros_codecs::{
 use std::{
     borrow::Borrow,
     fs::File,
-    io::{Read, Write},
+    io::{BufReader, Read, Write},
     time::Instant,
 };
 
+mod cpu_scaler;
+mod h264_decode;
 mod vaapi_scaler;
+mod y4m;
 
 #[derive(Parser)]
 #[command(name = "scale-sample")]
 #[command(about = "Scale raw NV12 frames using VAAPI")]
 struct Args {
-    /// Input raw NV12 file
+    /// Input file: raw NV12, Y4M if `--input-format y4m` (or the path ends
+    /// in `.y4m`), or H264 Annex-B if `--input-format h264`
     #[arg(long)]
     input: String,
 
-    /// Output raw NV12 file
+    /// Output file: raw NV12, or Y4M if the path ends in `.y4m` (only
+    /// applies to `--format nv12`)
     #[arg(long)]
     output: String,
 
-    /// Input width
+    /// Input width, required for raw NV12 input (a Y4M stream's `W` tag is
+    /// used instead; H264 input doesn't need it)
     #[arg(long)]
-    input_width: u32,
+    input_width: Option<u32>,
 
-    /// Input height
+    /// Input height, required for raw NV12 input (a Y4M stream's `H` tag is
+    /// used instead; H264 input doesn't need it)
     #[arg(long)]
-    input_height: u32,
+    input_height: Option<u32>,
+
+    /// Input container format
+    #[arg(long, value_enum, default_value = "auto")]
+    input_format: InputFormat,
+
+    /// Encoder framerate, overridden by a Y4M input stream's `F` tag
+    #[arg(long, default_value_t = 60)]
+    framerate: u32,
 
     /// Output width
     #[arg(long)]
@@ -65,9 +83,51 @@ struct Args {
     #[arg(long, value_enum, default_value = "h264")]
     format: OutputFormat,
 
+    /// Scaler backend: `vaapi` requires a VPP-capable display and fails if
+    /// one isn't available; `cpu` is a portable bilinear fallback that needs
+    /// no GPU at all; `auto` tries `vaapi` first and falls back to `cpu`.
+    #[arg(long, value_enum, default_value = "auto")]
+    scaler: ScalerMode,
+
     /// Maximum number of frames to process (optional, processes all frames if not specified)
     #[arg(long)]
     frames: Option<usize>,
+
+    /// Encode an adaptive-bitrate ladder instead of a single output: repeat
+    /// for each rendition, e.g. `--variant 1920:1080:6M:8M --variant
+    /// 1280:720:3M:4M`. Every frame is uploaded once and scaled once per
+    /// rendition, so the upload/decode cost is shared across the ladder.
+    /// Overrides `--output-width`/`--output-height`/`--bitrate`/`--maxrate`;
+    /// each rendition is written to `<output>.<width>x<height>.h264`.
+    #[arg(long = "variant", value_parser = parse_variant)]
+    variants: Vec<Variant>,
+}
+
+#[derive(Clone)]
+struct Variant {
+    width: u32,
+    height: u32,
+    bitrate: u64,
+    maxrate: u64,
+}
+
+fn parse_variant(s: &str) -> Result<Variant, String> {
+    let parts: Vec<&str> = s.split(':').collect();
+    let [width, height, bitrate, maxrate] = parts[..] else {
+        return Err(format!(
+            "Invalid variant '{s}': expected W:H:bitrate:maxrate"
+        ));
+    };
+    Ok(Variant {
+        width: width
+            .parse()
+            .map_err(|_| format!("Invalid variant width: {width}"))?,
+        height: height
+            .parse()
+            .map_err(|_| format!("Invalid variant height: {height}"))?,
+        bitrate: parse_bitrate(bitrate)?,
+        maxrate: parse_bitrate(maxrate)?,
+    })
 }
 
 #[derive(clap::ValueEnum, Clone)]
@@ -82,6 +142,21 @@ enum RcMode {
     Vbr,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy)]
+enum ScalerMode {
+    Vaapi,
+    Cpu,
+    Auto,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq)]
+enum InputFormat {
+    Auto,
+    Nv12,
+    Y4m,
+    H264,
+}
+
 fn parse_bitrate(s: &str) -> Result<u64, String> {
     let s = s.trim();
     if s.is_empty() {
@@ -106,19 +181,56 @@ fn parse_bitrate(s: &str) -> Result<u64, String> {
 fn main() -> Result<()> {
     let args = Args::parse();
 
+    if !args.variants.is_empty() {
+        return run_ladder(&args);
+    }
+
+    let is_h264_input = match args.input_format {
+        InputFormat::H264 => true,
+        InputFormat::Nv12 | InputFormat::Y4m => false,
+        InputFormat::Auto => args.input.ends_with(".h264") || args.input.ends_with(".264"),
+    };
+    if is_h264_input {
+        return run_h264_transcode(&args);
+    }
+
     println!("Starting NV12 frame scaling + encoding using VAAPI...");
     println!("Input: {}", args.input);
     println!("Output: {}", args.output);
-    println!(
-        "Input resolution: {}x{}",
-        args.input_width, args.input_height
-    );
+
+    let is_y4m_input = match args.input_format {
+        InputFormat::Y4m => true,
+        InputFormat::Nv12 | InputFormat::H264 => false,
+        InputFormat::Auto => args.input.ends_with(".y4m"),
+    };
+
+    // Open the input file, parsing the Y4M header up front when present so
+    // resolution and framerate travel with the pixels instead of having to
+    // be passed in on the command line.
+    let mut input_file = BufReader::new(File::open(&args.input)?);
+    let (input_width, input_height, framerate) = if is_y4m_input {
+        let header = y4m::read_header(&mut input_file)?;
+        println!(
+            "Detected Y4M input: {}x{} @ {}:{} fps",
+            header.width, header.height, header.fps_num, header.fps_den
+        );
+        (header.width, header.height, header.framerate())
+    } else {
+        let width = args
+            .input_width
+            .ok_or_else(|| anyhow::anyhow!("--input-width is required for raw NV12 input"))?;
+        let height = args
+            .input_height
+            .ok_or_else(|| anyhow::anyhow!("--input-height is required for raw NV12 input"))?;
+        (width, height, args.framerate)
+    };
+    println!("Input resolution: {}x{}", input_width, input_height);
     println!(
         "Output resolution: {}x{}",
         args.output_width, args.output_height
     );
 
-    let input_frame_size = (args.input_width * args.input_height * 3 / 2) as usize;
+    let input_frame_size = (input_width * input_height * 3 / 2) as usize;
 
     // Parse bitrates (required for H264 format)
     let (bitrate, maxrate) = if matches!(args.format, OutputFormat::H264) {
@@ -141,33 +253,65 @@ fn main() -> Result<()> {
         (0, 0) // Dummy values for NV12 mode
     };
 
-    // Open the raw NV12 file
-    let mut input_file = File::open(&args.input)?;
-
-    // Get file size and calculate total frames
-    let file_size = input_file.metadata()?.len() as usize;
-    let available_frames = file_size / input_frame_size;
-    let total_frames = args
-        .frames
-        .unwrap_or(available_frames)
-        .min(available_frames);
-    println!(
-        "Input file size: {} bytes, estimated frames: {}, processing: {}",
-        file_size, available_frames, total_frames
-    );
+    // Y4M is a stream format with no fixed frame size to divide the file
+    // size by, so its frame count is only known once we hit EOF; raw NV12
+    // can still estimate it up front.
+    let total_frames = if is_y4m_input {
+        args.frames.unwrap_or(usize::MAX)
+    } else {
+        let file_size = input_file.get_ref().metadata()?.len() as usize;
+        let available_frames = file_size / input_frame_size;
+        let total_frames = args
+            .frames
+            .unwrap_or(available_frames)
+            .min(available_frames);
+        println!(
+            "Input file size: {} bytes, estimated frames: {}, processing: {}",
+            file_size, available_frames, total_frames
+        );
+        total_frames
+    };
 
-    // Initialize VAAPI display
-    let Some(display) = cros_codecs::libva::Display::open() else {
-        bail!("Failed to open VAAPI display");
+    let src_resolution = Resolution {
+        width: input_width,
+        height: input_height,
+    };
+    let dst_resolution = Resolution {
+        width: args.output_width,
+        height: args.output_height,
     };
 
-    // Create reusable scaler
-    let scaler = vaapi_scaler::VaapiScaler::new(display.clone())?;
+    // Pick the scaler backend: a real display is only required for
+    // ScalerMode::Vaapi (or ScalerMode::Auto when one is actually available),
+    // so ScalerMode::Cpu works on headless/software-rendered machines with
+    // no VAAPI VPP entrypoint at all.
+    let mut scaler_backend: Box<dyn vaapi_scaler::ScalerBackend> = match args.scaler {
+        ScalerMode::Vaapi => {
+            Box::new(vaapi_scaler::VaapiScalerBackend::new(src_resolution, dst_resolution)?)
+        }
+        ScalerMode::Cpu => Box::new(cpu_scaler::CpuScalerBackend::default()),
+        ScalerMode::Auto => {
+            match vaapi_scaler::VaapiScalerBackend::new(src_resolution, dst_resolution) {
+                Ok(backend) => {
+                    println!("Using VAAPI scaler");
+                    Box::new(backend)
+                }
+                Err(e) => {
+                    println!("VAAPI scaler unavailable ({e}), falling back to CPU scaler");
+                    Box::new(cpu_scaler::CpuScalerBackend::default())
+                }
+            }
+        }
+    };
 
+    // Encoding still needs its own VAAPI display/surface pool/encoder
+    // regardless of which scaler produced the pixels.
     let fourcc = Fourcc::from(b"NV12");
+    let mut encode_state = if matches!(args.format, OutputFormat::H264) {
+        let Some(display) = cros_codecs::libva::Display::open() else {
+            bail!("Failed to open VAAPI display (required to encode H264, even with --scaler cpu)");
+        };
 
-    // Create encoder only if output format is H264
-    let mut encoder = if matches!(args.format, OutputFormat::H264) {
         let rate_control = match args.rc_mode {
             RcMode::Cbr => RateControl::ConstantBitrate(bitrate),
             RcMode::Vbr => RateControl::VariableBitrate {
@@ -175,48 +319,39 @@ fn main() -> Result<()> {
                 max_bitrate: maxrate,
             },
         };
-
         let encoder_config = EncoderConfig {
-            resolution: Resolution {
-                width: args.output_width,
-                height: args.output_height,
-            },
+            resolution: dst_resolution,
             profile: Profile::High,
             level: Level::L4_1,
             pred_structure: PredictionStructure::LowDelay { limit: 30 },
             initial_tunings: Tunings {
                 rate_control,
-                framerate: 60,
+                framerate,
                 min_quality: 0,
                 max_quality: u32::MAX,
             },
         };
-
-        Some(
-            StatelessEncoder::<H264, _, _>::new_native_vaapi(
-                display.clone(),
-                encoder_config,
-                fourcc,
-                Resolution {
-                    width: args.output_width,
-                    height: args.output_height,
-                },
-                false, // low_power
-                BlockingMode::NonBlocking,
-            )
-            .map_err(|e| anyhow::anyhow!("Failed to create encoder: {:?}", e))?,
+        let encoder = StatelessEncoder::<H264, _, _>::new_native_vaapi(
+            display.clone(),
+            encoder_config,
+            fourcc,
+            dst_resolution,
+            false, // low_power
+            BlockingMode::NonBlocking,
         )
-    } else {
-        None
-    };
+        .map_err(|e| anyhow::anyhow!("Failed to create encoder: {:?}", e))?;
+
+        let mut dst_pool = VaSurfacePool::<()>::new(
+            display.clone(),
+            VA_RT_FORMAT_YUV420,
+            Some(UsageHint::USAGE_HINT_ENCODER),
+            dst_resolution,
+        );
+        dst_pool.add_frames(vec![(); 16])?;
 
-    let frame_layout = if matches!(args.format, OutputFormat::H264) {
-        Some(FrameLayout {
+        let frame_layout = FrameLayout {
             format: (fourcc, 0),
-            size: Resolution {
-                width: args.output_width,
-                height: args.output_height,
-            },
+            size: dst_resolution,
             planes: vec![
                 PlaneLayout {
                     buffer_index: 0,
@@ -229,161 +364,158 @@ fn main() -> Result<()> {
                     stride: args.output_width as usize,
                 },
             ],
-        })
+        };
+
+        Some((display, encoder, dst_pool, frame_layout))
     } else {
         None
     };
 
-    // Create surface pool for input resolution with VPP read hint
-    let mut src_pool = VaSurfacePool::<()>::new(
-        display.clone(),
-        VA_RT_FORMAT_YUV420,
-        Some(UsageHint::USAGE_HINT_VPP_READ),
-        Resolution {
-            width: args.input_width,
-            height: args.input_height,
-        },
-    );
-    src_pool.add_frames(vec![(); 1])?; // Only need 1 surface for input
-
-    // Create surface pool for output resolution
-    let usage_hint = match args.format {
-        OutputFormat::H264 => Some(UsageHint::USAGE_HINT_ENCODER | UsageHint::USAGE_HINT_VPP_WRITE),
-        OutputFormat::Nv12 => Some(UsageHint::USAGE_HINT_VPP_WRITE),
-    };
-
-    let mut dst_pool = VaSurfacePool::<()>::new(
-        display.clone(),
-        VA_RT_FORMAT_YUV420,
-        usage_hint,
-        Resolution {
-            width: args.output_width,
-            height: args.output_height,
-        },
-    );
-    dst_pool.add_frames(vec![(); 16])?;
-
-    // Create output file
+    // Create output file. A `.y4m` output path only makes sense alongside
+    // `--format nv12`, since Y4M carries raw planar frames, not a coded
+    // bitstream.
+    let is_y4m_output = matches!(args.format, OutputFormat::Nv12) && args.output.ends_with(".y4m");
     let mut output_file = File::create(&args.output)?;
+    if is_y4m_output {
+        y4m::write_header(&mut output_file, args.output_width, args.output_height, framerate)?;
+    }
 
     let action = match args.format {
         OutputFormat::H264 => "Encoding",
         OutputFormat::Nv12 => "Scaling",
     };
-    println!("{} {} frames...", action, total_frames);
+    if total_frames == usize::MAX {
+        println!("{} frames...", action);
+    } else {
+        println!("{} {} frames...", action, total_frames);
+    }
 
-    // Frame buffer for reading one frame at a time
+    // Frame buffer for reading one frame at a time. For Y4M input this
+    // holds the planar I420 payload, which `y4m::i420_to_nv12` then
+    // converts into `frame_buffer` below; for raw NV12 input the two are
+    // the same thing and `y4m_plane_buffer` goes unused.
+    let mut y4m_plane_buffer = vec![0u8; input_frame_size];
     let mut frame_buffer = vec![0u8; input_frame_size];
-
-    // Output buffer for NV12 mode
     let output_frame_size = (args.output_width * args.output_height * 3 / 2) as usize;
-    let mut output_buffer = if matches!(args.format, OutputFormat::Nv12) {
-        Some(vec![0u8; output_frame_size])
+    let mut output_buffer = vec![0u8; output_frame_size];
+    let mut y4m_output_plane_buffer = if is_y4m_output {
+        vec![0u8; output_frame_size]
     } else {
-        None
+        Vec::new()
     };
 
     // Timing variables
-    let mut total_upload_time = std::time::Duration::ZERO;
     let mut total_scale_time = std::time::Duration::ZERO;
-    let mut total_download_time = std::time::Duration::ZERO;
+    let mut total_encode_upload_time = std::time::Duration::ZERO;
+    let mut frames_done = 0usize;
 
     // Process each frame
     for frame_idx in 0..total_frames {
         // Read one frame from file
-        match input_file.read_exact(&mut frame_buffer) {
-            Ok(_) => {}
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                println!("Reached end of file at frame {}", frame_idx);
+        if is_y4m_input {
+            if !y4m::read_frame_marker(&mut input_file)? {
+                println!("Reached end of Y4M stream at frame {}", frame_idx);
                 break;
             }
-            Err(e) => return Err(e.into()),
+            match input_file.read_exact(&mut y4m_plane_buffer) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    println!("Reached end of Y4M stream at frame {}", frame_idx);
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+            y4m::i420_to_nv12(
+                &y4m_plane_buffer,
+                input_width as usize,
+                input_height as usize,
+                &mut frame_buffer,
+            );
+        } else {
+            match input_file.read_exact(&mut frame_buffer) {
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    println!("Reached end of file at frame {}", frame_idx);
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
         }
+        frames_done += 1;
 
-        println!("Processing frame {}/{}", frame_idx + 1, total_frames);
-
-        // Get a surface from the input pool
-        let src_pooled_surface = src_pool
-            .get_surface()
-            .ok_or_else(|| anyhow::anyhow!("Failed to get source surface from pool"))?;
+        if total_frames == usize::MAX {
+            println!("Processing frame {}", frame_idx + 1);
+        } else {
+            println!("Processing frame {}/{}", frame_idx + 1, total_frames);
+        }
 
-        // Upload frame data to source surface
-        let src_surface: &Surface<()> = src_pooled_surface.borrow();
-        let upload_start = Instant::now();
-        upload_nv12_frame(
-            &display,
-            src_surface,
+        // Scale via whichever backend was selected above.
+        let scale_start = Instant::now();
+        scaler_backend.scale_nv12(
             &frame_buffer,
-            args.input_width,
-            args.input_height,
+            input_width,
+            input_height,
+            &mut output_buffer,
+            args.output_width,
+            args.output_height,
         )?;
-        let upload_time = upload_start.elapsed();
-        total_upload_time += upload_time;
+        total_scale_time += scale_start.elapsed();
 
-        // Get a surface from the output pool for the scaled output
-        let dst_pooled_surface = dst_pool
-            .get_surface()
-            .ok_or_else(|| anyhow::anyhow!("Failed to get destination surface from pool"))?;
+        match (&mut encode_state, args.format) {
+            (Some((display, encoder, dst_pool, frame_layout)), OutputFormat::H264) => {
+                let dst_pooled_surface = dst_pool
+                    .get_surface()
+                    .ok_or_else(|| anyhow::anyhow!("Failed to get destination surface from pool"))?;
+                let dst_surface: &Surface<()> = dst_pooled_surface.borrow();
 
-        // Scale the frame from input resolution to output resolution
-        let dst_surface: &Surface<()> = dst_pooled_surface.borrow();
-        let scale_start = Instant::now();
-        match args.format {
-            OutputFormat::H264 => scaler.scale(src_surface, dst_surface)?,
-            OutputFormat::Nv12 => scaler.scale_sync(src_surface, dst_surface)?,
-        }
-        let scale_time = scale_start.elapsed();
-        total_scale_time += scale_time;
+                let upload_start = Instant::now();
+                upload_nv12_frame(display, dst_surface, &output_buffer, args.output_width, args.output_height)?;
+                total_encode_upload_time += upload_start.elapsed();
 
-        match args.format {
-            OutputFormat::H264 => {
-                // Create frame metadata
                 let meta = FrameMetadata {
                     timestamp: frame_idx as u64,
-                    layout: frame_layout.as_ref().unwrap().clone(),
+                    layout: frame_layout.clone(),
                     force_keyframe: frame_idx == 0, // Force keyframe for first frame
                 };
-
-                // Encode the scaled frame
-                if let Some(ref mut enc) = encoder {
-                    enc.encode(meta, dst_pooled_surface)
-                        .map_err(|e| anyhow::anyhow!("Failed to encode frame: {:?}", e))?;
-
-                    // Poll for encoded data
-                    while let Some(coded_buffer) = enc
-                        .poll()
-                        .map_err(|e| anyhow::anyhow!("Failed to poll encoder: {:?}", e))?
-                    {
-                        output_file.write_all(&coded_buffer.bitstream)?;
-                    }
+                encoder
+                    .encode(meta, dst_pooled_surface)
+                    .map_err(|e| anyhow::anyhow!("Failed to encode frame: {:?}", e))?;
+
+                while let Some(coded_buffer) = encoder
+                    .poll()
+                    .map_err(|e| anyhow::anyhow!("Failed to poll encoder: {:?}", e))?
+                {
+                    output_file.write_all(&coded_buffer.bitstream)?;
                 }
             }
-            OutputFormat::Nv12 => {
-                // Download the scaled frame as raw NV12
-                if let Some(ref mut buf) = output_buffer {
-                    buf.fill(0); // Clear buffer
-                    let download_start = Instant::now();
-                    download_nv12_frame(
-                        &display,
-                        dst_surface,
-                        buf,
-                        args.output_width,
-                        args.output_height,
-                    )?;
-                    let download_time = download_start.elapsed();
-                    total_download_time += download_time;
-                    output_file.write_all(buf)?;
+            (None, OutputFormat::Nv12) => {
+                if is_y4m_output {
+                    y4m::write_frame_marker(&mut output_file)?;
+                    y4m::nv12_to_i420(
+                        &output_buffer,
+                        args.output_width as usize,
+                        args.output_height as usize,
+                        &mut y4m_output_plane_buffer,
+                    );
+                    output_file.write_all(&y4m_output_plane_buffer)?;
+                } else {
+                    output_file.write_all(&output_buffer)?;
                 }
             }
+            _ => unreachable!("encode_state is Some(..) iff args.format is H264"),
         }
 
         if frame_idx % 30 == 0 {
-            println!("Processed frame {}/{}", frame_idx + 1, total_frames);
+            if total_frames == usize::MAX {
+                println!("Processed frame {}", frame_idx + 1);
+            } else {
+                println!("Processed frame {}/{}", frame_idx + 1, total_frames);
+            }
         }
     }
 
     // Drain encoder (only for H264 mode)
-    if let Some(ref mut enc) = encoder {
+    if let Some((_, ref mut enc, ..)) = encode_state {
         enc.drain()
             .map_err(|e| anyhow::anyhow!("Failed to drain encoder: {:?}", e))?;
 
@@ -402,27 +534,419 @@ fn main() -> Result<()> {
     }
 
     // Print timing summary
-    let frames_processed = total_frames;
+    let frames_processed = frames_done;
     println!("\n=== Timing Summary ===");
     println!("Total frames processed: {}", frames_processed);
     println!(
-        "Upload time:   {:.2}ms total, {:.3}ms avg per frame",
-        total_upload_time.as_secs_f64() * 1000.0,
-        total_upload_time.as_secs_f64() * 1000.0 / frames_processed as f64
-    );
-    println!(
-        "Scale time:    {:.2}ms total, {:.3}ms avg per frame",
+        "Scale time:          {:.2}ms total, {:.3}ms avg per frame",
         total_scale_time.as_secs_f64() * 1000.0,
         total_scale_time.as_secs_f64() * 1000.0 / frames_processed as f64
     );
-    if matches!(args.format, OutputFormat::Nv12) {
+    if matches!(args.format, OutputFormat::H264) {
+        println!(
+            "Encode re-upload time: {:.2}ms total, {:.3}ms avg per frame",
+            total_encode_upload_time.as_secs_f64() * 1000.0,
+            total_encode_upload_time.as_secs_f64() * 1000.0 / frames_processed as f64
+        );
+    }
+
+    Ok(())
+}
+
+/// A single rung of the ladder: its own destination surface pool, encoder
+/// and output sink, all sized/tuned for `width`x`height`.
+struct Rendition {
+    width: u32,
+    height: u32,
+    dst_pool: VaSurfacePool<()>,
+    encoder: StatelessEncoder<H264, PooledVaSurface<()>, VaapiBackend<(), PooledVaSurface<()>>>,
+    frame_layout: FrameLayout,
+    output_file: File,
+}
+
+/// Fan one decoded/uploaded source frame out to every `--variant` rendition:
+/// upload once into `src_pool`, then scale once per rendition into its own
+/// `dst_pool` before encoding, so the upload cost is shared across the whole
+/// ladder instead of paid once per rendition like running the tool N times
+/// would. Mirrors the variant-stream handling in zap-stream-core.
+fn run_ladder(args: &Args) -> Result<()> {
+    // Y4M input isn't supported in ladder mode yet, so both dimensions must
+    // come from the command line here.
+    let input_width = args
+        .input_width
+        .ok_or_else(|| anyhow::anyhow!("--input-width is required for --variant ladder mode"))?;
+    let input_height = args
+        .input_height
+        .ok_or_else(|| anyhow::anyhow!("--input-height is required for --variant ladder mode"))?;
+
+    println!("Starting NV12 frame scaling + ladder encoding using VAAPI...");
+    println!("Input: {}", args.input);
+    println!("Input resolution: {}x{}", input_width, input_height);
+    for variant in &args.variants {
+        println!(
+            "Variant: {}x{} @ {} bps (max {} bps)",
+            variant.width, variant.height, variant.bitrate, variant.maxrate
+        );
+    }
+
+    let input_frame_size = (input_width * input_height * 3 / 2) as usize;
+    let mut input_file = File::open(&args.input)?;
+    let file_size = input_file.metadata()?.len() as usize;
+    let available_frames = file_size / input_frame_size;
+    let total_frames = args
+        .frames
+        .unwrap_or(available_frames)
+        .min(available_frames);
+
+    let Some(display) = cros_codecs::libva::Display::open() else {
+        bail!("Failed to open VAAPI display");
+    };
+    let scaler = vaapi_scaler::VaapiScaler::new(display.clone())?;
+    let fourcc = Fourcc::from(b"NV12");
+
+    let mut src_pool = VaSurfacePool::<()>::new(
+        display.clone(),
+        VA_RT_FORMAT_YUV420,
+        Some(UsageHint::USAGE_HINT_VPP_READ),
+        Resolution {
+            width: input_width,
+            height: input_height,
+        },
+    );
+    src_pool.add_frames(vec![(); 1])?;
+
+    let mut renditions = Vec::with_capacity(args.variants.len());
+    for variant in &args.variants {
+        let resolution = Resolution {
+            width: variant.width,
+            height: variant.height,
+        };
+        let encoder_config = EncoderConfig {
+            resolution,
+            profile: Profile::High,
+            level: Level::L4_1,
+            pred_structure: PredictionStructure::LowDelay { limit: 30 },
+            initial_tunings: Tunings {
+                rate_control: RateControl::VariableBitrate {
+                    target_bitrate: variant.bitrate,
+                    max_bitrate: variant.maxrate,
+                },
+                framerate: args.framerate,
+                min_quality: 0,
+                max_quality: u32::MAX,
+            },
+        };
+        let encoder = StatelessEncoder::<H264, _, _>::new_native_vaapi(
+            display.clone(),
+            encoder_config,
+            fourcc,
+            resolution,
+            false, // low_power
+            BlockingMode::NonBlocking,
+        )
+        .map_err(|e| anyhow::anyhow!("Failed to create encoder: {:?}", e))?;
+
+        let mut dst_pool = VaSurfacePool::<()>::new(
+            display.clone(),
+            VA_RT_FORMAT_YUV420,
+            Some(UsageHint::USAGE_HINT_ENCODER | UsageHint::USAGE_HINT_VPP_WRITE),
+            resolution,
+        );
+        dst_pool.add_frames(vec![(); 16])?;
+
+        let frame_layout = FrameLayout {
+            format: (fourcc, 0),
+            size: resolution,
+            planes: vec![
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 0,
+                    stride: variant.width as usize,
+                },
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: (variant.width * variant.height) as usize,
+                    stride: variant.width as usize,
+                },
+            ],
+        };
+
+        let output_file = File::create(format!(
+            "{}.{}x{}.h264",
+            args.output, variant.width, variant.height
+        ))?;
+
+        renditions.push(Rendition {
+            width: variant.width,
+            height: variant.height,
+            dst_pool,
+            encoder,
+            frame_layout,
+            output_file,
+        });
+    }
+
+    let mut frame_buffer = vec![0u8; input_frame_size];
+    for frame_idx in 0..total_frames {
+        match input_file.read_exact(&mut frame_buffer) {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                println!("Reached end of file at frame {}", frame_idx);
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let src_pooled_surface = src_pool
+            .get_surface()
+            .ok_or_else(|| anyhow::anyhow!("Failed to get source surface from pool"))?;
+        let src_surface: &Surface<()> = src_pooled_surface.borrow();
+        upload_nv12_frame(
+            &display,
+            src_surface,
+            &frame_buffer,
+            input_width,
+            input_height,
+        )?;
+
+        for rendition in &mut renditions {
+            let dst_pooled_surface = rendition
+                .dst_pool
+                .get_surface()
+                .ok_or_else(|| anyhow::anyhow!("Failed to get destination surface from pool"))?;
+            let dst_surface: &Surface<()> = dst_pooled_surface.borrow();
+            scaler.scale(src_surface, dst_surface)?;
+
+            let meta = FrameMetadata {
+                timestamp: frame_idx as u64,
+                layout: rendition.frame_layout.clone(),
+                force_keyframe: frame_idx == 0,
+            };
+            rendition
+                .encoder
+                .encode(meta, dst_pooled_surface)
+                .map_err(|e| anyhow::anyhow!("Failed to encode frame: {:?}", e))?;
+
+            while let Some(coded_buffer) = rendition
+                .encoder
+                .poll()
+                .map_err(|e| anyhow::anyhow!("Failed to poll encoder: {:?}", e))?
+            {
+                rendition.output_file.write_all(&coded_buffer.bitstream)?;
+            }
+        }
+
+        if frame_idx % 30 == 0 {
+            println!("Processed frame {}/{}", frame_idx + 1, total_frames);
+        }
+    }
+
+    for rendition in &mut renditions {
+        rendition
+            .encoder
+            .drain()
+            .map_err(|e| anyhow::anyhow!("Failed to drain encoder: {:?}", e))?;
+        while let Some(coded_buffer) = rendition
+            .encoder
+            .poll()
+            .map_err(|e| anyhow::anyhow!("Failed to poll encoder: {:?}", e))?
+        {
+            rendition.output_file.write_all(&coded_buffer.bitstream)?;
+        }
         println!(
-            "Download time: {:.2}ms total, {:.3}ms avg per frame",
-            total_download_time.as_secs_f64() * 1000.0,
-            total_download_time.as_secs_f64() * 1000.0 / frames_processed as f64
+            "Rendition {}x{} complete",
+            rendition.width, rendition.height
         );
     }
 
+    println!("Ladder encoding completed successfully!");
+    Ok(())
+}
+
+/// Decode an H264 Annex-B file straight to VA surfaces, scale each decoded
+/// picture via VAAPI VPP, and re-encode -- a real GPU transcode path with no
+/// decode-to-CPU / re-upload round trip between decode and scale, unlike the
+/// raw-NV12 path above which always goes through a plain byte buffer.
+fn run_h264_transcode(args: &Args) -> Result<()> {
+    println!("Starting H264 decode + scale + encode using VAAPI...");
+    println!("Input: {}", args.input);
+    println!("Output: {}", args.output);
+    println!(
+        "Output resolution: {}x{}",
+        args.output_width, args.output_height
+    );
+
+    let bitrate_str = args
+        .bitrate
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("--bitrate is required for H264 transcode"))?;
+    let maxrate_str = args
+        .maxrate
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("--maxrate is required for H264 transcode"))?;
+    let bitrate =
+        parse_bitrate(bitrate_str).map_err(|e| anyhow::anyhow!("Invalid bitrate: {}", e))?;
+    let maxrate =
+        parse_bitrate(maxrate_str).map_err(|e| anyhow::anyhow!("Invalid maxrate: {}", e))?;
+
+    let dst_resolution = Resolution {
+        width: args.output_width,
+        height: args.output_height,
+    };
+
+    let Some(display) = cros_codecs::libva::Display::open() else {
+        bail!("Failed to open VAAPI display (required for both decode and encode)");
+    };
+
+    let mut decoder = h264_decode::H264Decoder::new(display.clone())?;
+    let scaler = vaapi_scaler::VaapiScaler::new(display.clone())?;
+
+    let rate_control = match args.rc_mode {
+        RcMode::Cbr => RateControl::ConstantBitrate(bitrate),
+        RcMode::Vbr => RateControl::VariableBitrate {
+            target_bitrate: bitrate,
+            max_bitrate: maxrate,
+        },
+    };
+    let fourcc = Fourcc::from(b"NV12");
+    let encoder_config = EncoderConfig {
+        resolution: dst_resolution,
+        profile: Profile::High,
+        level: Level::L4_1,
+        pred_structure: PredictionStructure::LowDelay { limit: 30 },
+        initial_tunings: Tunings {
+            rate_control,
+            framerate: args.framerate,
+            min_quality: 0,
+            max_quality: u32::MAX,
+        },
+    };
+    let mut encoder = StatelessEncoder::<H264, _, _>::new_native_vaapi(
+        display.clone(),
+        encoder_config,
+        fourcc,
+        dst_resolution,
+        false, // low_power
+        BlockingMode::NonBlocking,
+    )
+    .map_err(|e| anyhow::anyhow!("Failed to create encoder: {:?}", e))?;
+
+    let mut dst_pool = VaSurfacePool::<()>::new(
+        display.clone(),
+        VA_RT_FORMAT_YUV420,
+        Some(UsageHint::USAGE_HINT_ENCODER | UsageHint::USAGE_HINT_VPP_WRITE),
+        dst_resolution,
+    );
+    dst_pool.add_frames(vec![(); 16])?;
+
+    let frame_layout = FrameLayout {
+        format: (fourcc, 0),
+        size: dst_resolution,
+        planes: vec![
+            PlaneLayout {
+                buffer_index: 0,
+                offset: 0,
+                stride: args.output_width as usize,
+            },
+            PlaneLayout {
+                buffer_index: 0,
+                offset: (args.output_width * args.output_height) as usize,
+                stride: args.output_width as usize,
+            },
+        ],
+    };
+
+    let mut output_file = File::create(&args.output)?;
+    let input_data = std::fs::read(&args.input)?;
+    let nalus = h264_decode::split_annex_b_nalus(&input_data);
+
+    let mut frame_idx = 0usize;
+    'decode: for (nalu_idx, nalu) in nalus.iter().enumerate() {
+        decoder.decode_nalu(nalu_idx as u64, nalu)?;
+        for decoded_surface in decoder.poll()? {
+            scale_and_encode(
+                &scaler,
+                &mut dst_pool,
+                &mut encoder,
+                &mut output_file,
+                &frame_layout,
+                h264_decode::borrow_surface(&decoded_surface),
+                frame_idx,
+            )?;
+            frame_idx += 1;
+            if frame_idx % 30 == 0 {
+                println!("Processed frame {}", frame_idx);
+            }
+            if args.frames.is_some_and(|max| frame_idx >= max) {
+                break 'decode;
+            }
+        }
+    }
+    for decoded_surface in decoder.drain()? {
+        scale_and_encode(
+            &scaler,
+            &mut dst_pool,
+            &mut encoder,
+            &mut output_file,
+            &frame_layout,
+            h264_decode::borrow_surface(&decoded_surface),
+            frame_idx,
+        )?;
+        frame_idx += 1;
+    }
+
+    encoder
+        .drain()
+        .map_err(|e| anyhow::anyhow!("Failed to drain encoder: {:?}", e))?;
+    while let Some(coded_buffer) = encoder
+        .poll()
+        .map_err(|e| anyhow::anyhow!("Failed to poll encoder: {:?}", e))?
+    {
+        output_file.write_all(&coded_buffer.bitstream)?;
+    }
+
+    println!(
+        "H264 decode + scale + encode completed successfully! ({} frames)",
+        frame_idx
+    );
+    Ok(())
+}
+
+/// Scale one decoded picture into a pooled destination surface and push it
+/// through the encoder, writing out whatever coded access units that makes
+/// ready. Shared between `run_h264_transcode`'s steady-state loop and its
+/// end-of-stream drain so the two don't drift apart.
+#[allow(clippy::too_many_arguments)]
+fn scale_and_encode(
+    scaler: &vaapi_scaler::VaapiScaler,
+    dst_pool: &mut VaSurfacePool<()>,
+    encoder: &mut StatelessEncoder<H264, PooledVaSurface<()>, VaapiBackend<(), PooledVaSurface<()>>>,
+    output_file: &mut File,
+    frame_layout: &FrameLayout,
+    src_surface: &Surface<()>,
+    frame_idx: usize,
+) -> Result<()> {
+    let dst_pooled_surface = dst_pool
+        .get_surface()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get destination surface from pool"))?;
+    let dst_surface: &Surface<()> = dst_pooled_surface.borrow();
+    scaler.scale(src_surface, dst_surface)?;
+
+    let meta = FrameMetadata {
+        timestamp: frame_idx as u64,
+        layout: frame_layout.clone(),
+        force_keyframe: frame_idx == 0,
+    };
+    encoder
+        .encode(meta, dst_pooled_surface)
+        .map_err(|e| anyhow::anyhow!("Failed to encode frame: {:?}", e))?;
+
+    while let Some(coded_buffer) = encoder
+        .poll()
+        .map_err(|e| anyhow::anyhow!("Failed to poll encoder: {:?}", e))?
+    {
+        output_file.write_all(&coded_buffer.bitstream)?;
+    }
     Ok(())
 }
 
@@ -485,49 +1009,3 @@ fn upload_nv12_frame(
     Ok(())
 }
 
-fn download_nv12_frame(
-    display: &cros_codecs::libva::Display,
-    surface: &Surface<()>,
-    frame_data: &mut [u8],
-    width: u32,
-    height: u32,
-) -> Result<()> {
-    let image = map_surface_nv12(display, surface);
-    let va_image = *image.image();
-    let src = image.as_ref();
-    let width = width as usize;
-    let height = height as usize;
-
-    // Copy Y plane - use stride-aware copying
-    let y_plane_size = width * height;
-    let y_dst = &mut frame_data[0..y_plane_size];
-
-    for row in 0..height {
-        let src_row_start = va_image.offsets[0] as usize + row * va_image.pitches[0] as usize;
-        let dst_row_start = row * width;
-
-        if src_row_start + width <= src.len() && dst_row_start + width <= y_dst.len() {
-            let src_row = &src[src_row_start..src_row_start + width];
-            let dst_row = &mut y_dst[dst_row_start..dst_row_start + width];
-            dst_row.copy_from_slice(src_row);
-        }
-    }
-
-    // Copy UV plane - use stride-aware copying
-    let uv_plane_size = width * height / 2;
-    let uv_dst = &mut frame_data[y_plane_size..y_plane_size + uv_plane_size];
-
-    for row in 0..height / 2 {
-        let src_row_start = va_image.offsets[1] as usize + row * va_image.pitches[1] as usize;
-        let dst_row_start = row * width;
-
-        if src_row_start + width <= src.len() && dst_row_start + width <= uv_dst.len() {
-            let src_row = &src[src_row_start..src_row_start + width];
-            let dst_row = &mut uv_dst[dst_row_start..dst_row_start + width];
-            dst_row.copy_from_slice(src_row);
-        }
-    }
-
-    Ok(())
-}
-
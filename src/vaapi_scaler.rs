@@ -1,13 +1,190 @@
 use anyhow::{bail, Result};
+use cros_codecs::backend::vaapi::surface_pool::VaSurfacePool;
 use cros_codecs::libva::{
-    Display, Surface, VARectangle, VABufferID, VABufferType, VAConfigID, VAContextID, 
-    VAEntrypoint, VAProfile, VAProcPipelineParameterBuffer, VASurfaceID, VA_PROGRESSIVE, 
-    VA_STATUS_SUCCESS, vaBeginPicture, vaCreateBuffer, vaCreateConfig, vaCreateContext, 
-    vaDestroyBuffer, vaDestroyConfig, vaDestroyContext, vaEndPicture, vaRenderPicture, 
-    vaSyncSurface
+    Display, Surface, VARectangle, VABufferID, VABufferType, VAConfigID, VAContextID,
+    VAEntrypoint, VAProcDeinterlacingType, VAProcFilterParameterBuffer,
+    VAProcFilterParameterBufferDeinterlacing, VAProcFilterType, VAProfile,
+    VAProcPipelineParameterBuffer, VASurfaceID, UsageHint, VA_PROGRESSIVE,
+    VA_RT_FORMAT_YUV420, VA_STATUS_SUCCESS, vaBeginPicture, vaCreateBuffer, vaCreateConfig,
+    vaCreateContext, vaDestroyBuffer, vaDestroyConfig, vaDestroyContext, vaEndPicture,
+    vaQueryVideoProcFilters, vaRenderPicture, vaSyncSurface
 };
+use cros_codecs::Resolution;
+use std::borrow::Borrow;
 use std::rc::Rc;
 
+/// Scales one NV12 frame at a time, operating on plain byte buffers rather
+/// than GPU surfaces so callers can swap in a software fallback (see the
+/// `cpu_scaler` module) without the rest of the pipeline knowing the
+/// difference. `src`/`dst` are tightly packed NV12 (no extra stride).
+pub trait ScalerBackend {
+    fn scale_nv12(
+        &mut self,
+        src: &[u8],
+        src_width: u32,
+        src_height: u32,
+        dst: &mut [u8],
+        dst_width: u32,
+        dst_height: u32,
+    ) -> Result<()>;
+}
+
+/// [`ScalerBackend`] backed by [`VaapiScaler`]: uploads `src` into a pooled
+/// input surface, runs the existing VPP scale, and downloads the result
+/// back into `dst`.
+pub struct VaapiScalerBackend {
+    display: Rc<Display>,
+    scaler: VaapiScaler,
+    src_pool: VaSurfacePool<()>,
+    dst_pool: VaSurfacePool<()>,
+}
+
+impl VaapiScalerBackend {
+    /// Opens a VAAPI display and probes VPP support, so construction fails
+    /// cleanly (for callers to fall back to [`crate::cpu_scaler::CpuScalerBackend`])
+    /// instead of the process aborting partway through a frame.
+    pub fn new(src_resolution: Resolution, dst_resolution: Resolution) -> Result<Self> {
+        let Some(display) = Display::open() else {
+            bail!("Failed to open VAAPI display");
+        };
+        let scaler = VaapiScaler::new(display.clone())?;
+
+        let mut src_pool = VaSurfacePool::<()>::new(
+            display.clone(),
+            VA_RT_FORMAT_YUV420,
+            Some(UsageHint::USAGE_HINT_VPP_READ),
+            src_resolution,
+        );
+        src_pool.add_frames(vec![(); 1])?;
+
+        let mut dst_pool = VaSurfacePool::<()>::new(
+            display.clone(),
+            VA_RT_FORMAT_YUV420,
+            Some(UsageHint::USAGE_HINT_VPP_WRITE),
+            dst_resolution,
+        );
+        dst_pool.add_frames(vec![(); 1])?;
+
+        Ok(Self {
+            display,
+            scaler,
+            src_pool,
+            dst_pool,
+        })
+    }
+}
+
+impl ScalerBackend for VaapiScalerBackend {
+    fn scale_nv12(
+        &mut self,
+        src: &[u8],
+        src_width: u32,
+        src_height: u32,
+        dst: &mut [u8],
+        dst_width: u32,
+        dst_height: u32,
+    ) -> Result<()> {
+        let src_pooled = self
+            .src_pool
+            .get_surface()
+            .ok_or_else(|| anyhow::anyhow!("Failed to get source surface from pool"))?;
+        let src_surface: &Surface<()> = src_pooled.borrow();
+        upload_nv12(&self.display, src_surface, src, src_width, src_height)?;
+
+        let dst_pooled = self
+            .dst_pool
+            .get_surface()
+            .ok_or_else(|| anyhow::anyhow!("Failed to get destination surface from pool"))?;
+        let dst_surface: &Surface<()> = dst_pooled.borrow();
+        self.scaler.scale_sync(src_surface, dst_surface)?;
+        download_nv12(&self.display, dst_surface, dst, dst_width, dst_height)
+    }
+}
+
+fn map_surface_nv12<'a>(display: &Display, surface: &'a Surface<()>) -> cros_codecs::libva::Image<'a> {
+    let image_fmts = display.query_image_formats().unwrap();
+    let image_fmt = image_fmts
+        .into_iter()
+        .find(|f| f.fourcc == cros_codecs::libva::VA_FOURCC_NV12)
+        .unwrap();
+    cros_codecs::libva::Image::create_from(surface, image_fmt, surface.size(), surface.size())
+        .unwrap()
+}
+
+fn upload_nv12(display: &Display, surface: &Surface<()>, frame_data: &[u8], width: u32, height: u32) -> Result<()> {
+    let mut image = map_surface_nv12(display, surface);
+    let va_image = *image.image();
+    let dest = image.as_mut();
+    let (width, height) = (width as usize, height as usize);
+
+    let y_plane_size = width * height;
+    let y_src = &frame_data[0..y_plane_size];
+    let y_dst = &mut dest[va_image.offsets[0] as usize..va_image.offsets[0] as usize + y_plane_size];
+    for row in 0..height {
+        let src = &y_src[row * width..row * width + width];
+        let dst = &mut y_dst[row * va_image.pitches[0] as usize..row * va_image.pitches[0] as usize + width];
+        dst.copy_from_slice(src);
+    }
+
+    let uv_plane_size = width * height / 2;
+    let uv_src = &frame_data[y_plane_size..y_plane_size + uv_plane_size];
+    let uv_dst = &mut dest[va_image.offsets[1] as usize..va_image.offsets[1] as usize + uv_plane_size];
+    for row in 0..height / 2 {
+        let src = &uv_src[row * width..row * width + width];
+        let dst = &mut uv_dst[row * va_image.pitches[1] as usize..row * va_image.pitches[1] as usize + width];
+        dst.copy_from_slice(src);
+    }
+
+    Ok(())
+}
+
+fn download_nv12(display: &Display, surface: &Surface<()>, frame_data: &mut [u8], width: u32, height: u32) -> Result<()> {
+    let image = map_surface_nv12(display, surface);
+    let va_image = *image.image();
+    let src = image.as_ref();
+    let (width, height) = (width as usize, height as usize);
+
+    let y_plane_size = width * height;
+    let y_dst = &mut frame_data[0..y_plane_size];
+    for row in 0..height {
+        let src_row = &src[va_image.offsets[0] as usize + row * va_image.pitches[0] as usize..][..width];
+        y_dst[row * width..row * width + width].copy_from_slice(src_row);
+    }
+
+    let uv_plane_size = width * height / 2;
+    let uv_dst = &mut frame_data[y_plane_size..y_plane_size + uv_plane_size];
+    for row in 0..height / 2 {
+        let src_row = &src[va_image.offsets[1] as usize + row * va_image.pitches[1] as usize..][..width];
+        uv_dst[row * width..row * width + width].copy_from_slice(src_row);
+    }
+
+    Ok(())
+}
+
+/// Deinterlacing algorithm to request from the driver's VPP filter.
+#[derive(Debug, Clone, Copy)]
+pub enum DeinterlaceMode {
+    Bob,
+    MotionAdaptive,
+}
+
+/// Optional post-processing filters chained onto the scale in a single
+/// `VAProcPipelineParameterBuffer` submission.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FilterChain {
+    pub deinterlace: Option<DeinterlaceMode>,
+    /// Noise-reduction intensity, 0.0-1.0.
+    pub denoise: Option<f32>,
+    /// Sharpening intensity, 0.0-1.0.
+    pub sharpen: Option<f32>,
+}
+
+impl FilterChain {
+    fn is_empty(&self) -> bool {
+        self.deinterlace.is_none() && self.denoise.is_none() && self.sharpen.is_none()
+    }
+}
+
 /// RAII wrapper for VAConfigID
 struct VppConfig {
     display: Rc<Display>,
@@ -147,6 +324,23 @@ impl VaapiScaler {
 
     /// Scale a frame from source surface to destination surface
     pub fn scale(&self, src_surface: &Surface<()>, dst_surface: &Surface<()>) -> Result<()> {
+        self.process(src_surface, dst_surface, &FilterChain::default(), &[], &[])
+    }
+
+    /// Scale and/or apply a post-processing filter chain (deinterlace,
+    /// denoise, sharpen) in a single VPP pipeline submission.
+    ///
+    /// `forward_references`/`backward_references` are only consulted when
+    /// `filters.deinterlace` is set; motion-adaptive/bob deinterlacing reads
+    /// neighboring fields from them.
+    pub fn process(
+        &self,
+        src_surface: &Surface<()>,
+        dst_surface: &Surface<()>,
+        filters: &FilterChain,
+        forward_references: &[VASurfaceID],
+        backward_references: &[VASurfaceID],
+    ) -> Result<()> {
         // Create context for this specific scaling operation
         let render_targets = [dst_surface.id()];
         let context = VppContext::new(
@@ -172,11 +366,24 @@ impl VaapiScaler {
             height: dst_surface.size().1 as u16,
         };
 
+        let filter_buffers = if filters.is_empty() {
+            Vec::new()
+        } else {
+            build_filter_buffers(&self.display, context.id(), filters)?
+        };
+        let mut filter_ids: Vec<VABufferID> = filter_buffers.iter().map(|b| b.id()).collect();
+
         // Create pipeline parameter buffer
         let pipeline_param = VAProcPipelineParameterBuffer {
             surface: src_surface.id(),
             surface_region: &src_rect,
             output_region: &dst_rect,
+            filters: filter_ids.as_mut_ptr(),
+            num_filters: filter_ids.len() as u32,
+            forward_references: forward_references.as_ptr() as *mut _,
+            num_forward_references: forward_references.len() as u32,
+            backward_references: backward_references.as_ptr() as *mut _,
+            num_backward_references: backward_references.len() as u32,
             ..Default::default()
         };
         let mut params = [pipeline_param];
@@ -189,7 +396,7 @@ impl VaapiScaler {
             (&mut params).as_mut_ptr() as *mut _,
         )?;
 
-        // Perform the scaling operation
+        // Perform the scaling/filtering operation
         unsafe {
             vaBeginPicture(self.display.handle(), context.id(), dst_surface.id());
             vaRenderPicture(self.display.handle(), context.id(), &mut pipeline_buf.id(), 1);
@@ -207,4 +414,87 @@ impl VaapiScaler {
         }
         Ok(())
     }
+}
+
+/// Query the filters the driver supports for this VPP context, and allocate
+/// one `VppBuffer` per filter that is both enabled in `filters` and
+/// supported by the driver; enabled filters the driver lacks are skipped.
+fn build_filter_buffers(
+    display: &Rc<Display>,
+    context: VAContextID,
+    filters: &FilterChain,
+) -> Result<Vec<VppBuffer>> {
+    let mut supported = [VAProcFilterType::VAProcFilterNone; 16];
+    let mut num_supported = supported.len() as u32;
+    let ret = unsafe {
+        vaQueryVideoProcFilters(
+            display.handle(),
+            context,
+            supported.as_mut_ptr(),
+            &mut num_supported,
+        )
+    };
+    if ret != VA_STATUS_SUCCESS as i32 {
+        bail!("Error querying VPP filters: {ret:?}");
+    }
+    let supported = &supported[..num_supported as usize];
+
+    let mut buffers = Vec::new();
+
+    if let Some(mode) = filters.deinterlace {
+        if supported.contains(&VAProcFilterType::VAProcFilterDeinterlacing) {
+            let algorithm = match mode {
+                DeinterlaceMode::Bob => VAProcDeinterlacingType::VAProcDeinterlacingBob,
+                DeinterlaceMode::MotionAdaptive => {
+                    VAProcDeinterlacingType::VAProcDeinterlacingMotionAdaptive
+                }
+            };
+            let mut param = VAProcFilterParameterBufferDeinterlacing {
+                type_: VAProcFilterType::VAProcFilterDeinterlacing,
+                algorithm,
+                flags: 0,
+            };
+            buffers.push(VppBuffer::new(
+                display.clone(),
+                context,
+                VABufferType::VAProcFilterParameterBufferType,
+                std::mem::size_of::<VAProcFilterParameterBufferDeinterlacing>() as u32,
+                &mut param as *mut _ as *mut std::ffi::c_void,
+            )?);
+        }
+    }
+
+    if let Some(intensity) = filters.denoise {
+        if supported.contains(&VAProcFilterType::VAProcFilterNoiseReduction) {
+            let mut param = VAProcFilterParameterBuffer {
+                type_: VAProcFilterType::VAProcFilterNoiseReduction,
+                value: intensity,
+            };
+            buffers.push(VppBuffer::new(
+                display.clone(),
+                context,
+                VABufferType::VAProcFilterParameterBufferType,
+                std::mem::size_of::<VAProcFilterParameterBuffer>() as u32,
+                &mut param as *mut _ as *mut std::ffi::c_void,
+            )?);
+        }
+    }
+
+    if let Some(intensity) = filters.sharpen {
+        if supported.contains(&VAProcFilterType::VAProcFilterSharpening) {
+            let mut param = VAProcFilterParameterBuffer {
+                type_: VAProcFilterType::VAProcFilterSharpening,
+                value: intensity,
+            };
+            buffers.push(VppBuffer::new(
+                display.clone(),
+                context,
+                VABufferType::VAProcFilterParameterBufferType,
+                std::mem::size_of::<VAProcFilterParameterBuffer>() as u32,
+                &mut param as *mut _ as *mut std::ffi::c_void,
+            )?);
+        }
+    }
+
+    Ok(buffers)
 }
\ No newline at end of file
@@ -0,0 +1,140 @@
+//! Minimal [YUV4MPEG2](https://wiki.multimedia.cx/index.php/YUV4MPEG2) (Y4M)
+//! reader/writer: parses the stream header and per-frame `FRAME` markers so
+//! callers get real dimensions and framerate instead of having to pass them
+//! in out of band and assume a fixed frame size. Frame payloads are planar
+//! 4:2:0 (I420); conversion to/from the NV12 buffers the rest of the
+//! pipeline works with lives here too, since it's inherent to the container.
+
+use anyhow::{bail, Context, Result};
+use std::io::{BufRead, Write};
+
+/// Parsed `YUV4MPEG2` stream header.
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    pub fps_num: u32,
+    pub fps_den: u32,
+}
+
+impl Header {
+    /// Rounds the rational `fps_num:fps_den` to the nearest whole framerate,
+    /// since the rest of the pipeline only carries an integer framerate.
+    pub fn framerate(&self) -> u32 {
+        (self.fps_num as f64 / self.fps_den as f64).round() as u32
+    }
+}
+
+/// Reads and parses the `YUV4MPEG2 ...\n` stream header. Tags other than
+/// `W`/`H`/`F` (`A` aspect, `C` colorspace, `X` comments, interlacing...) are
+/// accepted and ignored, since nothing downstream needs them beyond 4:2:0.
+pub fn read_header<R: BufRead>(reader: &mut R) -> Result<Header> {
+    let line = read_line(reader)?.context("Unexpected EOF reading Y4M header")?;
+    let mut tags = line.split_ascii_whitespace();
+
+    let magic = tags.next().context("Empty Y4M header")?;
+    if magic != "YUV4MPEG2" {
+        bail!("Not a Y4M stream (expected YUV4MPEG2, got {magic:?})");
+    }
+
+    let mut width = None;
+    let mut height = None;
+    let mut fps_num = None;
+    let mut fps_den = None;
+
+    for tag in tags {
+        let (kind, value) = tag.split_at(1);
+        match kind {
+            "W" => width = Some(value.parse()?),
+            "H" => height = Some(value.parse()?),
+            "F" => {
+                let (num, den) = value.split_once(':').context("Invalid Y4M F tag")?;
+                fps_num = Some(num.parse()?);
+                fps_den = Some(den.parse()?);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Header {
+        width: width.context("Y4M header missing W tag")?,
+        height: height.context("Y4M header missing H tag")?,
+        fps_num: fps_num.unwrap_or(25),
+        fps_den: fps_den.unwrap_or(1),
+    })
+}
+
+/// Reads the `FRAME` marker (optional parameters before the newline are
+/// ignored) preceding each frame's pixel data. Returns `Ok(false)` at EOF.
+pub fn read_frame_marker<R: BufRead>(reader: &mut R) -> Result<bool> {
+    match read_line(reader)? {
+        None => Ok(false),
+        Some(line) => {
+            if !line.starts_with("FRAME") {
+                bail!("Expected Y4M FRAME marker, got {line:?}");
+            }
+            Ok(true)
+        }
+    }
+}
+
+/// Writes the `YUV4MPEG2 ...\n` stream header for a progressive 4:2:0 stream.
+pub fn write_header<W: Write>(writer: &mut W, width: u32, height: u32, framerate: u32) -> Result<()> {
+    writeln!(
+        writer,
+        "YUV4MPEG2 W{width} H{height} F{framerate}:1 Ip A1:1 C420mpeg2"
+    )?;
+    Ok(())
+}
+
+/// Writes the `FRAME` marker preceding one frame's pixel data.
+pub fn write_frame_marker<W: Write>(writer: &mut W) -> Result<()> {
+    writeln!(writer, "FRAME")?;
+    Ok(())
+}
+
+/// Converts one planar I420 frame (Y4M's native 4:2:0 layout) into tightly
+/// packed NV12 (interleaved `CbCr` plane), as used by the rest of the
+/// pipeline.
+pub fn i420_to_nv12(src: &[u8], width: usize, height: usize, dst: &mut [u8]) {
+    let y_size = width * height;
+    dst[..y_size].copy_from_slice(&src[..y_size]);
+
+    let chroma_size = (width / 2) * (height / 2);
+    let u = &src[y_size..y_size + chroma_size];
+    let v = &src[y_size + chroma_size..y_size + 2 * chroma_size];
+    let uv_dst = &mut dst[y_size..y_size + 2 * chroma_size];
+    for i in 0..chroma_size {
+        uv_dst[2 * i] = u[i];
+        uv_dst[2 * i + 1] = v[i];
+    }
+}
+
+/// Converts one tightly packed NV12 frame into planar I420, the inverse of
+/// [`i420_to_nv12`].
+pub fn nv12_to_i420(src: &[u8], width: usize, height: usize, dst: &mut [u8]) {
+    let y_size = width * height;
+    dst[..y_size].copy_from_slice(&src[..y_size]);
+
+    let chroma_size = (width / 2) * (height / 2);
+    let uv_src = &src[y_size..y_size + 2 * chroma_size];
+    let (u_dst, v_dst) = dst[y_size..y_size + 2 * chroma_size].split_at_mut(chroma_size);
+    for i in 0..chroma_size {
+        u_dst[i] = uv_src[2 * i];
+        v_dst[i] = uv_src[2 * i + 1];
+    }
+}
+
+/// Reads one `\n`-terminated line (the trailing newline is stripped).
+/// Returns `Ok(None)` if the reader is already at EOF.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+    let mut buf = Vec::new();
+    let n = reader.read_until(b'\n', &mut buf)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+    }
+    Ok(Some(String::from_utf8(buf)?))
+}